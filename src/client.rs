@@ -1,52 +1,604 @@
-use std::{env, time::Duration};
+use std::{
+    collections::HashSet,
+    env,
+    future::Future,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process,
+    time::Duration,
+};
 
-use clap::Subcommand;
+use clap::{Subcommand, ValueEnum};
+use serde_yaml::{Mapping, Value};
 use tokio::{
-    fs,
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::UnixStream,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpStream, UnixStream},
     time::timeout,
 };
 
 use crate::{
+    efficiency::{least_efficient, Efficiency, WASTEFUL},
     jobs_management::JobConfiguration,
-    unix::{ClientRequest, DispatcherResponse},
+    queue_management::{AmountLimit, QueueLimits, QueueOverview},
+    resources_management::ResourcesRequirement,
+    unix::{encode, AuthRequest, ClientRequest, Credentials, DispatcherResponse},
+    vertex::VertexJobStatus,
 };
 
 #[derive(Subcommand, Debug)]
 pub enum ClientCommands {
-    Submit { queue: String, filepath: String },
-    Delete { id: String },
-    Status,
+    Submit {
+        filepath: String,
+        /// Queue to submit to, `auto` picks the first accepting queue.
+        /// Defaults to the dispatcher's default queue.
+        #[arg(short, long)]
+        queue: Option<String>,
+        /// Format of the spec, detected from the file extension by default.
+        #[arg(short, long)]
+        format: Option<SpecFormat>,
+        /// Submitting again with the same key returns the first job.
+        #[arg(long)]
+        idempotency_key: Option<String>,
+    },
+    /// Delete a queued job, or with `--label key=value` each of your queued
+    /// jobs carrying that label.
+    Delete {
+        #[arg(required_unless_present = "label")]
+        id: Option<String>,
+        #[arg(long, value_parser = parse_label, conflicts_with = "id")]
+        label: Option<(String, String)>,
+    },
+    /// Replace the requirement of a queued job with the one in `filepath`.
+    Modify {
+        id: String,
+        filepath: String,
+        #[arg(short, long)]
+        format: Option<SpecFormat>,
+    },
+    /// Move a queued job to another queue.
+    Requeue { id: String, queue: String },
+    Status {
+        /// Only the jobs carrying the label `key=value`.
+        #[arg(long, value_parser = parse_label)]
+        label: Option<(String, String)>,
+    },
+    /// Job counts and limits of every queue.
+    Queues,
+    Permissions,
+    /// Tell why a queued job isn't running yet.
+    Why { id: String },
+    /// Print the configuration of the running dispatcher, root only.
+    DumpConfig,
+    /// Stop dispatching the jobs of a queue, root only.
+    Pause { queue: String },
+    /// Dispatch the jobs of a paused queue again, root only.
+    Resume { queue: String },
+    /// Freeze a running job without killing it, root only.
+    SuspendJob { id: String },
+    /// Thaw a job frozen by `suspend-job`, root only.
+    ResumeJob { id: String },
+    /// Make vertexes forget jobs which ended before the unix timestamp
+    /// `before`, root only. Running jobs are kept.
+    Prune { before: u64 },
+    /// Submit a job and wait for it, printing its stdout as it runs. Exits
+    /// with the exit code of the job.
+    Run {
+        filepath: String,
+        #[arg(short, long)]
+        queue: Option<String>,
+        #[arg(short, long)]
+        format: Option<SpecFormat>,
+        /// Submitting again with the same key returns the first job.
+        #[arg(long)]
+        idempotency_key: Option<String>,
+        /// Print the task id and return, `attach` follows the job later.
+        #[arg(long)]
+        detach: bool,
+    },
+    /// Follow a job like `run` does, resuming its output where the last
+    /// attach stopped.
+    Attach { id: String },
+    /// Used over requested cpu time and memory of the finished jobs, least
+    /// efficient first.
+    Efficiency {
+        /// Only the jobs finished after this unix timestamp.
+        #[arg(long, default_value_t = 0)]
+        since: u64,
+    },
+}
+
+/// Seconds to wait for an answer, from `--timeout`, then the
+/// `JOB_DISPATCHER_TIMEOUT` environment variable, then 5.
+fn answer_timeout(timeout: Option<u64>) -> Duration {
+    let secs = timeout
+        .or(env::var("JOB_DISPATCHER_TIMEOUT")
+            .ok()
+            .and_then(|secs| secs.parse().ok()))
+        .unwrap_or(5);
+    Duration::from_secs(secs)
 }
 
-pub async fn client(command: ClientCommands) {
-    let mut server = UnixStream::connect(
-        env::var("JOB_DISPATCHER_SOCKET").unwrap_or("/tmp/job_dispatcher.socket".to_string()),
-    )
-    .await
-    .unwrap();
+pub async fn client(command: ClientCommands, timeout: Option<u64>) {
+    let time_limit = answer_timeout(timeout);
     let request = match command {
-        ClientCommands::Submit { queue, filepath } => {
-            let content = fs::read_to_string(filepath).await.unwrap();
-            let job: JobConfiguration = serde_yaml::from_str(&content).unwrap();
-            ClientRequest::SubmitJob(queue, job)
+        ClientCommands::Submit { queue, filepath, format, idempotency_key } => {
+            submit_request(queue, load_spec(Path::new(&filepath), format), idempotency_key)
+        }
+        ClientCommands::Delete { id: Some(id), .. } => ClientRequest::DeleteJob(id),
+        ClientCommands::Delete { label, .. } => {
+            let (key, value) = label.unwrap();
+            ClientRequest::DeleteByLabel(key, value)
+        }
+        ClientCommands::Modify { id, filepath, format } => {
+            let requirement: ResourcesRequirement = load_spec(Path::new(&filepath), format);
+            ClientRequest::Modify(id, requirement)
+        }
+        ClientCommands::Requeue { id, queue } => ClientRequest::RequeueJob(id, queue),
+        ClientCommands::Status { label: None } => ClientRequest::Status,
+        ClientCommands::Status { label: Some((key, value)) } => {
+            ClientRequest::StatusByLabel(key, value)
+        }
+        ClientCommands::Queues => ClientRequest::Queues,
+        ClientCommands::Permissions => ClientRequest::Permissions,
+        ClientCommands::Why { id } => ClientRequest::Why(id),
+        ClientCommands::DumpConfig => ClientRequest::DumpConfig,
+        ClientCommands::Pause { queue } => ClientRequest::PauseQueue(queue),
+        ClientCommands::Resume { queue } => ClientRequest::ResumeQueue(queue),
+        ClientCommands::SuspendJob { id } => ClientRequest::Suspend(id),
+        ClientCommands::ResumeJob { id } => ClientRequest::Resume(id),
+        ClientCommands::Prune { before } => ClientRequest::PruneHistory(before),
+        ClientCommands::Run { filepath, queue, format, idempotency_key, detach } => {
+            let job: JobConfiguration = load_spec(Path::new(&filepath), format);
+            let request = submit_request(queue, job, idempotency_key);
+            return run(request, detach, time_limit).await;
+        }
+        ClientCommands::Attach { id } => return attach(&id, time_limit).await,
+        ClientCommands::Efficiency { since } => {
+            return match send(&ClientRequest::Status, time_limit).await {
+                DispatcherResponse::Status { vertex_jobs, .. } => {
+                    print_efficiency(&least_efficient(&vertex_jobs, since))
+                }
+                response => println!("{:#?}", response),
+            };
         }
-        ClientCommands::Delete { id } => ClientRequest::DeleteJob(id),
-        ClientCommands::Status => ClientRequest::Status,
     };
-    let data = serde_json::to_string(&request).unwrap();
-    let data = data.as_bytes();
+    match send(&request, time_limit).await {
+        DispatcherResponse::Permissions { uid, gid, queues } => {
+            print_permissions(uid, gid, &queues)
+        }
+        DispatcherResponse::Queues(queues) => print_queues(&queues),
+        DispatcherResponse::Config(config) => {
+            println!("{}", serde_json::to_string_pretty(&config).unwrap())
+        }
+        response => println!("{:#?}", response),
+    }
+}
+
+fn parse_label(label: &str) -> Result<(String, String), String> {
+    label
+        .split_once('=')
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .ok_or(format!("{} is not key=value", label))
+}
+
+fn submit_request(
+    queue: Option<String>,
+    job: JobConfiguration,
+    idempotency_key: Option<String>,
+) -> ClientRequest {
+    if queue.as_deref() == Some("auto") {
+        ClientRequest::SubmitAuto(Box::new(job), idempotency_key)
+    } else {
+        ClientRequest::SubmitJob(queue, Box::new(job), idempotency_key)
+    }
+}
+
+/// Connect to the dispatcher at `target`, retrying a few times in case its
+/// socket isn't ready yet.
+async fn connect<S, F, Fut>(target: &str, connect: F) -> S
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = io::Result<S>>,
+{
+    let mut delay = Duration::from_millis(200);
+    for _ in 0..3 {
+        if let Ok(server) = connect().await {
+            return server;
+        }
+        tokio::time::sleep(delay).await;
+        delay *= 2;
+    }
+    connect().await.unwrap_or_else(|err| {
+        eprintln!(
+            "Can't connect to the dispatcher at {}: {}. Is it running? Set JOB_DISPATCHER_SOCKET or JOB_DISPATCHER_TCP if it listens elsewhere.",
+            target, err
+        );
+        process::exit(1)
+    })
+}
+
+/// Send `request` over TCP if `JOB_DISPATCHER_TCP` is set, or else over the
+/// unix socket. `JOB_DISPATCHER_TOKEN`, or over TCP `JOB_DISPATCHER_USER` and
+/// `JOB_DISPATCHER_PASSWORD`, give the credentials.
+async fn send(request: &ClientRequest, time_limit: Duration) -> DispatcherResponse {
+    let token = env::var("JOB_DISPATCHER_TOKEN").ok().map(Credentials::Token);
+    if let Ok(addr) = env::var("JOB_DISPATCHER_TCP") {
+        let server = connect(&addr, || TcpStream::connect(addr.as_str())).await;
+        let credentials = token.unwrap_or_else(|| Credentials::Basic {
+            username: env::var("JOB_DISPATCHER_USER").unwrap_or_default(),
+            password: env::var("JOB_DISPATCHER_PASSWORD").unwrap_or_default(),
+        });
+        let request = AuthRequest {
+            credentials,
+            request: request.clone(),
+        };
+        exchange(server, &encode(&request), time_limit).await
+    } else {
+        let socket =
+            env::var("JOB_DISPATCHER_SOCKET").unwrap_or("/tmp/job_dispatcher.socket".to_string());
+        let server = connect(&socket, || UnixStream::connect(&socket)).await;
+        let data = if let Some(credentials) = token {
+            encode(&AuthRequest {
+                credentials,
+                request: request.clone(),
+            })
+        } else {
+            encode(request)
+        };
+        exchange(server, &data, time_limit).await
+    }
+}
+
+async fn exchange<S: AsyncRead + AsyncWrite + Unpin>(
+    mut server: S,
+    data: &[u8],
+    time_limit: Duration,
+) -> DispatcherResponse {
     server.write_all(data).await.unwrap();
     server.shutdown().await.unwrap();
     let mut response = String::new();
-    let time_limit = timeout(Duration::from_secs(5), server.read_to_string(&mut response)).await;
-    if let Ok(Ok(_)) = time_limit {
-        let response: DispatcherResponse = serde_json::from_str(&response).unwrap();
-        println!("{:#?}", response);
-    } else if let Ok(Err(err)) = time_limit {
-        panic!("{:#?}", err)
-    } else {
-        panic!("Timeout! Is server running correctly?")
+    match timeout(time_limit, server.read_to_string(&mut response)).await {
+        Ok(Ok(_)) => serde_json::from_str(&response).unwrap(),
+        Ok(Err(err)) => {
+            eprintln!("Failed to read the answer of the dispatcher: {}", err);
+            process::exit(1)
+        }
+        Err(_) => {
+            eprintln!(
+                "No answer from the dispatcher within {}s. Raise it with --timeout or JOB_DISPATCHER_TIMEOUT.",
+                time_limit.as_secs()
+            );
+            process::exit(1)
+        }
+    }
+}
+
+/// Submit `request`, then attach to the job unless `detach` is set, in
+/// which case only its task id is printed.
+async fn run(request: ClientRequest, detach: bool, time_limit: Duration) {
+    let task_id = match send(&request, time_limit).await {
+        DispatcherResponse::SubmitSuccess(task_id) => task_id,
+        DispatcherResponse::SubmitAutoSuccess { task_id, .. } => task_id,
+        response => {
+            eprintln!("{:#?}", response);
+            process::exit(1)
+        }
+    };
+    if detach {
+        println!("{}", task_id);
+        return;
+    }
+    eprintln!("Submitted {}", task_id);
+    attach(&task_id, time_limit).await
+}
+
+/// Poll the status of a job until it ends, printing its stdout from where
+/// the last attach to it stopped. Exits instead of returning.
+async fn attach(task_id: &str, time_limit: Duration) {
+    let cursor = cursor_path(task_id);
+    let mut printed = cursor
+        .as_ref()
+        .and_then(|cursor| std::fs::read_to_string(cursor).ok())
+        .and_then(|offset| offset.trim().parse().ok())
+        .unwrap_or(0);
+    let mut interval = Duration::from_millis(500);
+    loop {
+        tokio::time::sleep(interval).await;
+        interval = (interval * 3 / 2).min(Duration::from_secs(5));
+        let (jobs, vertex_jobs) = match send(&ClientRequest::Status, time_limit).await {
+            DispatcherResponse::Status { jobs, vertex_jobs, .. } => (jobs, vertex_jobs),
+            response => {
+                eprintln!("{:#?}", response);
+                continue;
+            }
+        };
+        let status = vertex_jobs
+            .values()
+            .find_map(|statuses| statuses.get(task_id));
+        if status.is_some() {
+            printed = print_new_output(task_id, printed, time_limit).await;
+            if let Some(cursor) = &cursor {
+                save_cursor(cursor, printed);
+            }
+        }
+        let cancelled = jobs
+            .iter()
+            .find(|summary| summary.task_id == task_id)
+            .and_then(|summary| summary.cancelled.clone());
+        let exit_code = match status {
+            _ if cancelled.is_some() => {
+                eprintln!("Job cancelled: {}", cancelled.unwrap());
+                1
+            }
+            Some(VertexJobStatus::Finished { exit_code, .. }) => *exit_code,
+            Some(VertexJobStatus::Error { status_code, error_message, .. }) => {
+                eprintln!("Job failed: {}", error_message);
+                *status_code
+            }
+            _ if jobs.iter().any(|summary| summary.task_id == task_id) => continue,
+            Some(VertexJobStatus::Running { .. }) => continue,
+            None => {
+                eprintln!("Job {} is gone", task_id);
+                1
+            }
+        };
+        if let Some(cursor) = &cursor {
+            let _ = std::fs::remove_file(cursor);
+        }
+        process::exit(exit_code)
+    }
+}
+
+/// Print the stdout of a job after its first `printed` bytes, and return
+/// how many bytes are printed now.
+async fn print_new_output(task_id: &str, mut printed: u64, time_limit: Duration) -> u64 {
+    let mut stdout = std::io::stdout();
+    loop {
+        match send(&ClientRequest::Log(task_id.to_string(), printed), time_limit).await {
+            DispatcherResponse::Log(data) if data.is_empty() => break,
+            DispatcherResponse::Log(data) => {
+                let _ = stdout.write_all(&data);
+                printed += data.len() as u64;
+            }
+            response => {
+                eprintln!("{:#?}", response);
+                break;
+            }
+        }
+    }
+    let _ = stdout.flush();
+    printed
+}
+
+/// Where the printed length of a job's stdout is kept between attaches.
+fn cursor_path(task_id: &str) -> Option<PathBuf> {
+    let home = env::var("HOME").ok()?;
+    Some(Path::new(&home).join(".job_dispatcher/cursors").join(task_id))
+}
+
+fn save_cursor(cursor: &Path, printed: u64) {
+    if let Some(parent) = cursor.parent() {
+        let _ = std::fs::create_dir_all(parent);
+    }
+    let _ = std::fs::write(cursor, printed.to_string());
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum SpecFormat {
+    Yaml,
+    Json,
+    Toml,
+}
+
+impl SpecFormat {
+    fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "yml" | "yaml" => Some(Self::Yaml),
+            "json" => Some(Self::Json),
+            "toml" => Some(Self::Toml),
+            _ => None,
+        }
+    }
+
+    fn parse(&self, content: &str) -> Result<Value, String> {
+        let value = match self {
+            Self::Yaml => return serde_yaml::from_str(content).map_err(|e| e.to_string()),
+            Self::Json => serde_json::from_str::<serde_json::Value>(content).map_err(|e| e.to_string())?,
+            Self::Toml => toml::from_str::<serde_json::Value>(content).map_err(|e| e.to_string())?,
+        };
+        serde_yaml::to_value(value).map_err(|e| e.to_string())
+    }
+}
+
+/// Load a spec, exiting with a readable error if it can't be used. The
+/// format of `path` is `format` or guessed from the extension, the format of
+/// the bases it extends is guessed the same way.
+fn load_spec<T: serde::de::DeserializeOwned>(path: &Path, format: Option<SpecFormat>) -> T {
+    resolve_extends(path, format)
+        .and_then(|spec| {
+            serde_json::from_value(yaml_to_json(spec))
+                .map_err(|err| format!("Invalid spec {}: {}", path.display(), err))
+        })
+        .unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            process::exit(1)
+        })
+}
+
+/// YAML tags become single-key mappings, the way JSON spells enum variants.
+fn yaml_to_json(value: Value) -> serde_json::Value {
+    match value {
+        Value::Null => serde_json::Value::Null,
+        Value::Bool(value) => serde_json::Value::Bool(value),
+        Value::Number(number) => serde_json::to_value(number).unwrap(),
+        Value::String(value) => serde_json::Value::String(value),
+        Value::Sequence(values) => values.into_iter().map(yaml_to_json).collect(),
+        Value::Mapping(mapping) => mapping
+            .into_iter()
+            .map(|(key, value)| {
+                let key = match key {
+                    Value::String(key) => key,
+                    key => serde_yaml::to_string(&key).unwrap().trim().to_string(),
+                };
+                (key, yaml_to_json(value))
+            })
+            .collect(),
+        Value::Tagged(tagged) => {
+            let tag = tagged.tag.to_string();
+            let mut variant = serde_json::Map::new();
+            variant.insert(tag.trim_start_matches('!').to_string(), yaml_to_json(tagged.value));
+            serde_json::Value::Object(variant)
+        }
+    }
+}
+
+/// Load a spec and resolve its `extends` chain. Paths are relative to the
+/// file declaring them, and mappings of a spec override its base.
+fn resolve_extends(path: &Path, format: Option<SpecFormat>) -> Result<Value, String> {
+    let mut layers = Vec::new();
+    let mut visited = HashSet::new();
+    let mut next = Some(path.to_path_buf());
+    let mut format = format.or(SpecFormat::from_path(path)).unwrap_or(SpecFormat::Yaml);
+    while let Some(path) = next.take() {
+        let canonical = path
+            .canonicalize()
+            .map_err(|err| format!("Failed to open job spec {}: {}", path.display(), err))?;
+        if !visited.insert(canonical.clone()) {
+            return Err(format!("Cyclic extends detected at {}", path.display()));
+        }
+        let content = std::fs::read_to_string(&canonical)
+            .map_err(|err| format!("Failed to read job spec {}: {}", path.display(), err))?;
+        let mut layer: Value = format
+            .parse(&content)
+            .map_err(|err| format!("Failed to parse job spec {}: {}", path.display(), err))?;
+        if let Some(base) = layer.as_mapping_mut().and_then(|layer| layer.remove("extends")) {
+            let base = base
+                .as_str()
+                .ok_or(format!("extends in {} must be a path", path.display()))?;
+            let parent = canonical.parent().map(Path::to_path_buf).unwrap_or_default();
+            let base = parent.join(PathBuf::from(base));
+            format = SpecFormat::from_path(&base).unwrap_or(format);
+            next = Some(base);
+        }
+        layers.push(layer);
+    }
+    Ok(layers
+        .into_iter()
+        .rev()
+        .reduce(merge_yaml)
+        .unwrap_or(Value::Mapping(Mapping::new())))
+}
+
+/// A single-key mapping keyed by a PascalCase name, which is how JSON and
+/// TOML specs spell an enum variant.
+fn is_variant(mapping: &Mapping) -> bool {
+    mapping.len() == 1
+        && mapping
+            .keys()
+            .next()
+            .and_then(Value::as_str)
+            .map(|key| {
+                key.starts_with(|c: char| c.is_ascii_uppercase())
+                    && key.contains(|c: char| c.is_ascii_lowercase())
+            })
+            .unwrap_or(false)
+}
+
+fn merge_yaml(base: Value, overrides: Value) -> Value {
+    match (base, overrides) {
+        (Value::Mapping(base), Value::Mapping(overrides))
+            if is_variant(&overrides) && base.keys().ne(overrides.keys()) =>
+        {
+            Value::Mapping(overrides)
+        }
+        (Value::Mapping(mut base), Value::Mapping(overrides)) => {
+            for (key, value) in overrides {
+                let merged = match base.remove(&key) {
+                    Some(base_value) => merge_yaml(base_value, value),
+                    None => value,
+                };
+                base.insert(key, merged);
+            }
+            Value::Mapping(base)
+        }
+        (_, overrides) => overrides,
+    }
+}
+
+fn limit(limit: &Option<AmountLimit>) -> String {
+    limit
+        .as_ref()
+        .map(|limit| format!("{}/{}", limit.max_running, limit.max_queue))
+        .unwrap_or("-".to_string())
+}
+
+fn print_queues(queues: &[QueueOverview]) {
+    println!(
+        "{:<20} {:>8} {:>8} {:>8} {:>12} {:>12} {:>12}",
+        "QUEUE", "QUEUED", "RUNNING", "HELD", "GLOBAL", "USER", "GROUP"
+    );
+    for queue in queues {
+        let name = if queue.paused {
+            format!("{} (paused)", queue.limits.name)
+        } else {
+            queue.limits.name.clone()
+        };
+        println!(
+            "{:<20} {:>8} {:>8} {:>8} {:>12} {:>12} {:>12}",
+            name,
+            queue.queued,
+            queue.running,
+            queue.held,
+            limit(&queue.limits.global_limit),
+            limit(&queue.limits.user_limit),
+            limit(&queue.limits.group_limit)
+        );
+    }
+    println!("Limits are shown as max_running/max_queue.");
+}
+
+fn print_efficiency(jobs: &[Efficiency]) {
+    let ratio = |ratio: Option<f64>| {
+        ratio
+            .map(|ratio| format!("{:.0}%", ratio * 100.))
+            .unwrap_or("-".to_string())
+    };
+    println!(
+        "{:<38} {:<20} {:<12} {:>8} {:>8} {:>8}",
+        "TASK", "NAME", "VERTEX", "UID", "CPU", "MEMORY"
+    );
+    for job in jobs {
+        println!(
+            "{:<38} {:<20} {:<12} {:>8} {:>8} {:>8}{}",
+            job.task_id,
+            job.name,
+            job.vertex,
+            job.uid,
+            ratio(job.cpu),
+            ratio(job.memory),
+            if job.wasteful() { "  *" } else { "" }
+        );
+    }
+    println!(
+        "Jobs marked * used less than {:.0}% of what they requested.",
+        WASTEFUL * 100.
+    );
+}
+
+fn print_permissions(uid: u32, gid: u32, queues: &[QueueLimits]) {
+    println!("uid={} gid={}", uid, gid);
+    println!(
+        "{:<20} {:>12} {:>12} {:>12}",
+        "QUEUE", "GLOBAL", "USER", "GROUP"
+    );
+    for queue in queues {
+        println!(
+            "{:<20} {:>12} {:>12} {:>12}",
+            queue.name,
+            limit(&queue.global_limit),
+            limit(&queue.user_limit),
+            limit(&queue.group_limit)
+        );
     }
+    println!("Limits are shown as max_running/max_queue.");
 }
@@ -1,102 +1,507 @@
 use std::{
     collections::{HashMap, HashSet},
+    ffi::CString,
     fs,
-    io::Result,
+    io::{Error, ErrorKind, Result},
+    net::SocketAddr,
+    os::unix::{fs::PermissionsExt, io::AsRawFd},
     sync::{Arc, RwLock},
     time::Duration,
 };
 
 use crate::{
-    queue_management::{Queue, QueueConfiguration, QueueGroup},
-    utils::now_to_micros,
-    vertex_client::{VertexClient, VertexConnect}, unix::{DispatcherResponse, ClientRequest, DispatcherFailReasons},
+    http::TlsConfig,
+    jobs_management::JobConfiguration,
+    metrics::Metrics,
+    notify::{FinishNotification, NotifyConfig, StartNotification},
+    queue_management::{
+        shared_id_prefix, Queue, QueueConfiguration, QueueGroup, Scheduler, SchedulerKind,
+    },
+    queue_store::{QueueStore, QueueStoreKind},
+    resources_management::{NodeSet, NodesRequirement, PackingStrategy, ResourcesProvider},
+    utils::{home_dir, now_to_micros, now_to_secs, primary_gid, RwLockExt},
+    vertex::VertexJobStatus,
+    vertex_client::{Redacted, Registration, VertexClient, VertexConnect},
+    unix::{
+        decode, AuthRequest, ClientRequest, Credentials, DispatcherFailReasons,
+        DispatcherResponse, UnixRequest,
+    },
 };
 
-use serde::{Deserialize, Serialize};
+use axum::{
+    extract::State,
+    http::StatusCode,
+    routing::{get, post},
+    Json, Router,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::{unix::UCred, UnixListener, UnixStream},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpSocket, UnixListener},
+    sync::Semaphore,
     time::timeout,
 };
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct DispatcherConfig {
     listen: String,
+    #[serde(serialize_with = "serialize_redacted")]
     vertexes: HashMap<String, VertexConnect>,
     max_timeout: u64,
     loop_interval: u64,
+    #[serde(default)]
+    dead_after: Option<u64>,
     queues: HashMap<String, QueueConfiguration>,
+    #[serde(default)]
+    default_queue: Option<String>,
+    /// Path the queues are saved to, in the `persistence` format.
     persistent: String,
+    #[serde(default)]
+    persistence: QueueStoreKind,
+    #[serde(default)]
+    metrics_listen: Option<SocketAddr>,
+    #[serde(default)]
+    reservations: Vec<Reservation>,
+    #[serde(default)]
+    on_finish: Option<NotifyConfig>,
+    #[serde(default)]
+    submit_rate: Option<SubmitRate>,
+    /// Upper bound of jobs dispatched by one loop pass.
+    #[serde(default)]
+    max_dispatch_per_pass: Option<usize>,
+    /// Times a failed submission is retried on the same vertex before the
+    /// job is tried on the next one.
+    #[serde(default)]
+    submit_retries: u32,
+    /// Milliseconds before the first retry of a submission, doubled for
+    /// each next one.
+    #[serde(default = "default_submit_retry_delay_ms")]
+    submit_retry_delay_ms: u64,
+    #[serde(default)]
+    discovery: Option<DiscoveryConfig>,
+    #[serde(default)]
+    registration: Option<RegistrationConfig>,
+    /// Programs jobs may start, compared with the first element of `Run`
+    /// phases as written. `Sh` phases count as `sh`. Any if unset.
+    #[serde(default)]
+    allowed_commands: Option<Vec<String>>,
+    /// Permission bits of the `listen` socket, like `0o660`.
+    #[serde(default)]
+    socket_mode: Option<u32>,
+    /// Group owning the `listen` socket.
+    #[serde(default)]
+    socket_group: Option<String>,
+    /// Path of the logs of jobs not naming their own, without the `.out` or
+    /// `.err` extension. `~`, `{name}`, `{uid}` and `{task_id}` are expanded.
+    #[serde(default = "default_log_dir_template")]
+    log_dir_template: String,
+    /// Seconds an idempotency key of a submission is remembered.
+    #[serde(default = "default_idempotency_ttl")]
+    idempotency_ttl: u64,
+    /// Largest request read from a client, before and after decompression.
+    #[serde(default = "default_max_request_bytes")]
+    max_request_bytes: u64,
+    /// Seconds a client has to send its whole request, after which the
+    /// connection is dropped.
+    #[serde(default = "default_read_timeout_secs")]
+    read_timeout_secs: u64,
+    /// Clients served at once over both listeners. Once reached, no more
+    /// connections are accepted and new ones wait in the listen backlog.
+    #[serde(default = "default_max_connections")]
+    max_connections: usize,
+    #[serde(default = "default_listen_backlog")]
+    listen_backlog: u32,
+    /// Also accept clients over TCP, authenticated as one of `tcp_users` or
+    /// with one of the `tokens`.
+    #[serde(default)]
+    tcp_listen: Option<SocketAddr>,
+    #[serde(default, serialize_with = "serialize_tcp_users")]
+    tcp_users: HashMap<String, TcpUser>,
+    /// Tokens authenticating a client as the uid, only the uids are dumped.
+    #[serde(default, serialize_with = "serialize_tokens")]
+    tokens: HashMap<String, u32>,
+    /// How the next job to dispatch is picked.
+    #[serde(default)]
+    scheduler: SchedulerKind,
+    /// How a vertex is picked among those able to run a job.
+    #[serde(default)]
+    packing: PackingStrategy,
+}
+
+/// Credentials of a TCP client and the user it acts as.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct TcpUser {
+    password: String,
+    uid: u32,
+    gid: u32,
+}
+
+fn serialize_tokens<S: serde::Serializer>(
+    tokens: &HashMap<String, u32>,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    let mut uids = tokens.values().collect::<Vec<_>>();
+    uids.sort();
+    serializer.collect_seq(uids)
+}
+
+fn serialize_tcp_users<S: serde::Serializer>(
+    users: &HashMap<String, TcpUser>,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    serializer.collect_map(users.iter().map(|(name, user)| {
+        let user = TcpUser {
+            password: "<redacted>".to_string(),
+            ..user.clone()
+        };
+        (name, user)
+    }))
+}
+
+fn default_log_dir_template() -> String {
+    "~/jobs/{name}-{task_id}".to_string()
+}
+
+fn default_idempotency_ttl() -> u64 {
+    3600
+}
+
+fn default_max_request_bytes() -> u64 {
+    4 * 1024 * 1024
+}
+
+fn default_read_timeout_secs() -> u64 {
+    30
+}
+
+fn default_submit_retry_delay_ms() -> u64 {
+    200
 }
 
+fn default_max_connections() -> usize {
+    256
+}
+
+fn default_listen_backlog() -> u32 {
+    1024
+}
+
+/// Vertexes found in `directory`, one `<name>.yml` file holding the
+/// connection of each, read again every `interval` seconds. They come in
+/// addition to the static `vertexes`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct DiscoveryConfig {
+    directory: String,
+    interval: u64,
+}
+
+/// Vertexes announcing themselves over HTTP at `listen`, with `secret`.
+/// One not announced again within `ttl` seconds is dropped and its jobs
+/// queued again.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct RegistrationConfig {
+    listen: SocketAddr,
+    #[serde(serialize_with = "serialize_secret")]
+    secret: String,
+    /// Serve HTTPS, as announcements carry the secret and the credentials
+    /// of the vertex.
+    #[serde(default)]
+    tls: Option<TlsConfig>,
+    #[serde(default = "default_registration_ttl")]
+    ttl: u64,
+}
+
+fn default_registration_ttl() -> u64 {
+    90
+}
+
+fn serialize_secret<S: serde::Serializer>(
+    _secret: &str,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    serializer.serialize_str("<redacted>")
+}
+
+/// Token bucket for submissions of a user: `burst` submissions at once,
+/// refilled by `per_secs` submissions each second.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct SubmitRate {
+    per_secs: f64,
+    burst: f64,
+}
+
+impl SubmitRate {
+    /// Take a token from `bucket` (tokens, last refill in micros), or tell
+    /// how many seconds until one is available.
+    fn take(&self, bucket: &mut (f64, u128), now: u128) -> std::result::Result<(), u64> {
+        let (tokens, last) = bucket;
+        let elapsed = now.saturating_sub(*last) as f64 / 1e6;
+        *tokens = (*tokens + elapsed * self.per_secs).min(self.burst);
+        *last = now;
+        if *tokens >= 1. {
+            *tokens -= 1.;
+            Ok(())
+        } else if self.per_secs > 0. {
+            Err(((1. - *tokens) / self.per_secs).ceil() as u64)
+        } else {
+            Err(u64::MAX)
+        }
+    }
+}
+
+fn serialize_redacted<S: serde::Serializer>(
+    vertexes: &HashMap<String, VertexConnect>,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    serializer.collect_map(vertexes.iter().map(|(name, vertex)| (name, Redacted(vertex))))
+}
+
+/// Cpus kept for some users between `start` and `end` (unix seconds). A
+/// reservation without `vertex` applies to every vertex.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Reservation {
+    start: u64,
+    end: u64,
+    #[serde(default)]
+    vertex: Option<String>,
+    uids: HashSet<u32>,
+    cpus: NodeSet,
+}
+
+impl Reservation {
+    fn blocks(&self, vertex: &str, uid: u32, now: u64) -> bool {
+        self.start <= now
+            && now < self.end
+            && self.vertex.as_ref().map(|v| v == vertex).unwrap_or(true)
+            && !self.uids.contains(&uid)
+    }
+}
+
+impl DispatcherConfig {
+    /// The uid and gid `credentials` authenticate as, a token acts with the
+    /// primary group of its uid.
+    fn authenticate(&self, credentials: &Credentials) -> Option<(u32, u32)> {
+        match credentials {
+            Credentials::Basic { username, password } => self
+                .tcp_users
+                .get(username)
+                .filter(|user| &user.password == password)
+                .map(|user| (user.uid, user.gid)),
+            Credentials::Token(token) => {
+                let uid = *self.tokens.get(token)?;
+                Some((uid, primary_gid(uid)?))
+            }
+        }
+    }
+
+    /// With reservations configured, resolve the cpus of `job` from
+    /// `available` so the vertex can't pick reserved cpus itself. Jobs
+    /// notified on start are resolved too, to tell their cpuset.
+    fn pin_cpus(&self, job: &JobConfiguration, available: &ResourcesProvider) -> JobConfiguration {
+        let mut job = job.clone();
+        if !self.reservations.is_empty() || job.notify_start.is_some() {
+            job.requirement.cpus = match &job.requirement.cpus {
+                NodesRequirement::Use(size) => NodesRequirement::Select(
+                    available.cpus.iter().take(*size).cloned().collect(),
+                ),
+                NodesRequirement::Auto => NodesRequirement::Select(available.cpus.clone()),
+                selected => selected.clone(),
+            };
+        }
+        job
+    }
+
+    /// Resources of `free` which `uid` may use on `vertex` at `now`.
+    fn available_for(
+        &self,
+        free: &ResourcesProvider,
+        vertex: &str,
+        uid: u32,
+        now: u64,
+    ) -> ResourcesProvider {
+        let mut available = free.clone();
+        for reservation in &self.reservations {
+            if reservation.blocks(vertex, uid, now) {
+                available.cpus = available
+                    .cpus
+                    .difference(&reservation.cpus)
+                    .cloned()
+                    .collect();
+            }
+        }
+        available
+    }
+
+    /// The log template expanded for `job`, but `{task_id}` which is only
+    /// known once queued. `None` if the job has no log files of its own and
+    /// the home of the owner can't be found.
+    fn log_template(&self, job: &JobConfiguration) -> Option<String> {
+        if !job.stdout_file.is_empty() && !job.stderr_file.is_empty() {
+            return Some(String::new());
+        }
+        let mut template = self
+            .log_dir_template
+            .replace("{name}", &job.name)
+            .replace("{uid}", &job.uid.to_string());
+        if template.starts_with('~') {
+            template = template.replacen('~', &home_dir(job.uid)?, 1);
+        }
+        Some(template)
+    }
+
+    /// A vertex contacted within this many microseconds is considered alive.
+    fn alive_window(&self) -> u64 {
+        self.dead_after
+            .unwrap_or(self.loop_interval + 2 * self.max_timeout)
+    }
+}
+
+/// Queue and task id of recent submissions by uid and idempotency key, with
+/// the time they were made.
+type Submissions = HashMap<(u32, String), (String, String, u64)>;
+
+/// Last announcement of each registered vertex, in seconds, and the
+/// connection it announced, unknown for a vertex only seen in the saved
+/// queues.
+type Registered = HashMap<String, (u64, Option<VertexConnect>)>;
+
 #[derive(Clone)]
 struct DispatcherCachedState {
     configuration: DispatcherConfig,
     vertex_status: Arc<RwLock<HashMap<String, (VertexClient, u128)>>>,
     queues: Arc<RwLock<QueueGroup>>,
+    vertex_jobs_cache: Arc<RwLock<HashMap<String, HashMap<String, VertexJobStatus>>>>,
+    /// Last free resources reported by each vertex.
+    vertex_free_cache: Arc<RwLock<HashMap<String, ResourcesProvider>>>,
+    submit_buckets: Arc<RwLock<HashMap<u32, (f64, u128)>>>,
+    submissions: Arc<RwLock<Submissions>>,
+    registered: Arc<RwLock<Registered>>,
+    metrics: Arc<Metrics>,
+    scheduler: Arc<dyn Scheduler>,
+    store: Arc<dyn QueueStore>,
 }
 
 pub async fn dispatcher(config_path: &str) {
     let configuration: DispatcherConfig =
         serde_yaml::from_str(&fs::read_to_string(config_path).unwrap()).unwrap();
+    if let Some(default_queue) = &configuration.default_queue {
+        if !configuration.queues.contains_key(default_queue) {
+            panic!("Default queue {} is not configured", default_queue)
+        }
+    }
+    if let Some(shared) = shared_id_prefix(&configuration.queues) {
+        panic!("{}", shared)
+    }
     let mut queue_in_conf = configuration
         .queues
         .iter()
         .map(|(task_id, configuration)| (task_id.to_string(), Queue::new(configuration)))
         .collect::<HashMap<_, _>>();
-    let persistent: HashMap<String, Queue> = serde_json::from_str(
-        &fs::read_to_string(&configuration.persistent).unwrap_or("".to_string()),
-    )
-    .unwrap_or(HashMap::new());
+    let store = configuration
+        .persistence
+        .create(&configuration.persistent)
+        .unwrap();
+    let persistent = store.load().unwrap_or_else(|err| {
+        println!("Failed to load saved queues: {}", err);
+        HashMap::new()
+    });
     queue_in_conf.extend(persistent);
-    let vertex_status = configuration
-        .vertexes
-        .iter()
-        .map(|(name, config)| (name.to_string(), (config.create(), now_to_micros())))
-        .collect::<HashMap<_, _>>();
-    let cached_state = DispatcherCachedState {
-        configuration,
-        vertex_status: Arc::new(RwLock::new(vertex_status)),
-        queues: Arc::new(RwLock::new(QueueGroup::new(queue_in_conf))),
-    };
+    let cached_state = DispatcherCachedState::new(configuration, queue_in_conf, store);
+
+    if let Some(metrics_listen) = cached_state.configuration.metrics_listen {
+        let app = Router::new()
+            .route("/metrics", get(get_metrics))
+            .with_state(cached_state.clone());
+        tokio::spawn(async move {
+            axum::Server::bind(&metrics_listen)
+                .serve(app.into_make_service())
+                .await
+                .unwrap();
+        });
+    }
+
+    if let Some(discovery) = cached_state.configuration.discovery.clone() {
+        let state = cached_state.clone();
+        tokio::spawn(async move {
+            let mut known = HashMap::new();
+            loop {
+                discover_vertexes(&state, &discovery, &mut known);
+                tokio::time::sleep(Duration::from_secs(discovery.interval)).await;
+            }
+        });
+    }
+
+    if let Some(registration) = cached_state.configuration.registration.clone() {
+        let app = Router::new()
+            .route("/register", post(register_vertex))
+            .with_state(cached_state.clone());
+        let listen = registration.listen;
+        let tls = registration.tls.clone();
+        tokio::spawn(async move {
+            if let Some(tls) = tls {
+                axum_server::bind_rustls(listen, tls.rustls_config().unwrap())
+                    .serve(app.into_make_service())
+                    .await
+                    .unwrap();
+            } else {
+                axum::Server::bind(&listen)
+                    .serve(app.into_make_service())
+                    .await
+                    .unwrap();
+            }
+        });
+        let state = cached_state.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(Duration::from_secs(registration.ttl)).await;
+                expire_registrations(&state, registration.ttl);
+            }
+        });
+    }
 
+    let connections = Arc::new(Semaphore::new(cached_state.configuration.max_connections));
     let server_state = cached_state.clone();
+    let unix_connections = connections.clone();
     tokio::spawn(async move {
         let socket = UnixListener::bind(&server_state.configuration.listen).unwrap();
+        // listening again only changes the backlog tokio gave the socket
+        let backlog = server_state.configuration.listen_backlog as i32;
+        if unsafe { libc::listen(socket.as_raw_fd(), backlog) } != 0 {
+            println!("Warning: failed to set the listen backlog to {}", backlog);
+        }
+        secure_socket(&server_state.configuration);
         loop {
+            // waiting for a permit first leaves new clients in the backlog
+            let permit = unix_connections.clone().acquire_owned().await.unwrap();
             let request = socket.accept().await;
             let server_state = server_state.clone();
             tokio::spawn(async move {
+                let _permit = permit;
                 match request {
                     Ok((mut stream, _)) => {
-                        if let Ok(request) = get_request(&mut stream).await {
-                            if let Ok(ucred) = stream.peer_cred() {
-                                let mut status = server_state.clone();
-                                let response = request.handle(&mut status, &ucred).await;
-                                let _ = stream
-                                    .write_all(serde_json::to_string(&response).unwrap().as_bytes())
-                                    .await;
-                                let _ = stream.shutdown().await;
-                            } else {
-                                let _ = stream
-                                    .write_all(
-                                        serde_json::to_string(&DispatcherResponse::InvalidRequest)
-                                            .unwrap()
-                                            .as_bytes(),
-                                    )
-                                    .await;
-                                let _ = stream.shutdown().await;
-                            }
-                        } else {
-                            let _ = stream
-                                .write_all(
-                                    serde_json::to_string(&DispatcherResponse::InvalidRequest)
-                                        .unwrap()
-                                        .as_bytes(),
-                                )
+                        let request =
+                            get_request::<_, UnixRequest>(&mut stream, &server_state.configuration)
                                 .await;
-                            let _ = stream.shutdown().await;
-                        }
+                        let response = match (request, stream.peer_cred()) {
+                            (Ok(UnixRequest::Plain(request)), Ok(ucred)) => {
+                                handle_isolated(request, &server_state, ucred.uid(), ucred.gid()).await
+                            }
+                            (Ok(UnixRequest::Authenticated(request)), Ok(ucred)) => {
+                                let AuthRequest { credentials, request } = request;
+                                match server_state.configuration.authenticate(&credentials) {
+                                    Some((uid, gid)) if ucred.uid() == 0 => {
+                                        handle_isolated(request, &server_state, uid, gid).await
+                                    }
+                                    Some(_) => {
+                                        handle_isolated(request, &server_state, ucred.uid(), ucred.gid())
+                                            .await
+                                    }
+                                    None => DispatcherResponse::Unauthorized,
+                                }
+                            }
+                            _ => DispatcherResponse::InvalidRequest,
+                        };
+                        respond(&mut stream, &response).await;
                     }
                     Err(err) => {
                         println!("Error: {:#?}", err);
@@ -106,89 +511,1205 @@ pub async fn dispatcher(config_path: &str) {
         }
     });
 
+    if let Some(tcp_listen) = cached_state.configuration.tcp_listen {
+        let server_state = cached_state.clone();
+        tokio::spawn(async move {
+            let socket = if tcp_listen.is_ipv4() {
+                TcpSocket::new_v4()
+            } else {
+                TcpSocket::new_v6()
+            }
+            .unwrap();
+            socket.set_reuseaddr(true).unwrap();
+            socket.bind(tcp_listen).unwrap();
+            let socket = socket
+                .listen(server_state.configuration.listen_backlog)
+                .unwrap();
+            loop {
+                let permit = connections.clone().acquire_owned().await.unwrap();
+                let request = socket.accept().await;
+                let server_state = server_state.clone();
+                tokio::spawn(async move {
+                    let _permit = permit;
+                    match request {
+                        Ok((mut stream, _)) => {
+                            let request =
+                                get_request::<_, AuthRequest>(&mut stream, &server_state.configuration);
+                            let response = match request.await {
+                                Ok(AuthRequest { credentials, request }) => {
+                                    match server_state.configuration.authenticate(&credentials) {
+                                        Some((uid, gid)) => {
+                                            handle_isolated(request, &server_state, uid, gid).await
+                                        }
+                                        None => DispatcherResponse::Unauthorized,
+                                    }
+                                }
+                                Err(_) => DispatcherResponse::InvalidRequest,
+                            };
+                            respond(&mut stream, &response).await;
+                        }
+                        Err(err) => {
+                            println!("Error: {:#?}", err);
+                        }
+                    }
+                });
+            }
+        });
+    }
+
+    reconcile(&cached_state).await;
     loop {
-        for (_, (client, last_connected)) in cached_state.vertex_status.write().unwrap().iter_mut()
-        {
-            let request_free = client.free();
-            let request_free = timeout(
-                Duration::from_micros(cached_state.configuration.max_timeout),
-                request_free,
-            );
-            if let Ok(Ok(request_free)) = request_free.await {
+        let reaped = cached_state.queues.lock_write().reap_stale(now_to_secs());
+        if !reaped.is_empty() {
+            println!("Cancelled {} jobs after waiting too long: {:?}", reaped.len(), reaped);
+        }
+        let missed = cached_state.queues.lock_write().miss_deadlines(now_to_secs());
+        if !missed.is_empty() {
+            println!("Cancelled {} jobs past their deadline: {:?}", missed.len(), missed);
+        }
+        let frees = poll_vertexes(&cached_state).await;
+        dispatch_jobs(&cached_state, &frees).await;
+        refresh_vertex_jobs(&cached_state, &frees, false).await;
+        kill_overruns(&cached_state).await;
+        save_queues(&cached_state);
+        tokio::time::sleep(Duration::from_micros(
+            cached_state.configuration.loop_interval,
+        ))
+        .await;
+    }
+}
+
+/// Have the vertex running `task_id` freeze or thaw it.
+async fn set_frozen(
+    state: &DispatcherCachedState,
+    task_id: &str,
+    frozen: bool,
+) -> std::result::Result<(), DispatcherFailReasons> {
+    let vertex = state
+        .vertex_jobs_cache
+        .lock_read()
+        .iter()
+        .find(|(_, jobs)| jobs.contains_key(task_id))
+        .map(|(vertex, _)| vertex.clone())
+        .ok_or(DispatcherFailReasons::NotFound)?;
+    let client = state
+        .vertex_status
+        .lock_read()
+        .get(&vertex)
+        .map(|(client, _)| client.clone())
+        .ok_or(DispatcherFailReasons::NotFound)?;
+    client
+        .set_frozen(task_id, frozen)
+        .await
+        .map_err(DispatcherFailReasons::Rejected)
+}
+
+fn set_paused(state: &DispatcherCachedState, queue: &str, paused: bool) -> DispatcherResponse {
+    if state.queues.lock_write().set_paused(queue, paused).is_some() {
+        DispatcherResponse::PauseSuccess
+    } else {
+        DispatcherResponse::PauseFailed(DispatcherFailReasons::NotFound)
+    }
+}
+
+/// Start notifications run by the dispatcher itself, so only root may ask
+/// for a command.
+fn notify_rejection(job: &JobConfiguration, uid: u32) -> Option<String> {
+    match job.notify_start {
+        Some(NotifyConfig::Command(_)) if uid != 0 => {
+            Some("notify_start commands are root only".to_string())
+        }
+        _ => None,
+    }
+}
+
+/// Fill the empty log files of a queued job from `template`.
+fn fill_log_files(queues: &mut QueueGroup, task_id: &str, template: &str) {
+    let path = template.replace("{task_id}", task_id);
+    queues.update_queued(task_id, |job| {
+        if job.stdout_file.is_empty() {
+            job.stdout_file = format!("{}.out", path);
+        }
+        if job.stderr_file.is_empty() {
+            job.stderr_file = format!("{}.err", path);
+        }
+    });
+}
+
+fn secure_socket(configuration: &DispatcherConfig) {
+    if let Some(group) = &configuration.socket_group {
+        let name = CString::new(group.as_str()).unwrap();
+        let group_entry = unsafe { libc::getgrnam(name.as_ptr()) };
+        if group_entry.is_null() {
+            panic!("Socket group {} doesn't exist", group)
+        }
+        let gid = unsafe { (*group_entry).gr_gid };
+        std::os::unix::fs::chown(&configuration.listen, None, Some(gid)).unwrap();
+    }
+    if let Some(mode) = configuration.socket_mode {
+        fs::set_permissions(&configuration.listen, fs::Permissions::from_mode(mode)).unwrap();
+    }
+}
+
+/// Sync `vertex_status` with the endpoint files of `discovery`. `known`
+/// holds the content of the files seen last time, by vertex name. Static
+/// vertexes are never replaced nor removed.
+fn discover_vertexes(
+    state: &DispatcherCachedState,
+    discovery: &DiscoveryConfig,
+    known: &mut HashMap<String, String>,
+) {
+    let entries = match fs::read_dir(&discovery.directory) {
+        Ok(entries) => entries,
+        Err(err) => {
+            println!("Failed to read {}: {}", discovery.directory, err);
+            return;
+        }
+    };
+    let mut found = HashMap::new();
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let name = match (path.file_stem(), path.extension()) {
+            (Some(name), Some(extension)) if extension == "yml" || extension == "yaml" => {
+                name.to_string_lossy().to_string()
+            }
+            _ => continue,
+        };
+        if state.configuration.vertexes.contains_key(&name) {
+            continue;
+        }
+        if let Ok(content) = fs::read_to_string(&path) {
+            found.insert(name, content);
+        }
+    }
+    for (name, content) in &found {
+        if known.get(name) == Some(content) {
+            continue;
+        }
+        match serde_yaml::from_str::<VertexConnect>(content) {
+            Ok(connect) => {
+                println!("Discovered vertex {}", name);
+                state
+                    .vertex_status
+                    .lock_write()
+                    .insert(name.clone(), (connect.create(), now_to_micros()));
+            }
+            Err(err) => println!("Invalid vertex file for {}: {}", name, err),
+        }
+    }
+    for name in known.keys().filter(|name| !found.contains_key(*name)) {
+        state.vertex_status.lock_write().remove(name);
+        state.vertex_free_cache.lock_write().remove(name);
+        state.vertex_jobs_cache.lock_write().remove(name);
+        let requeued = state.queues.lock_write().requeue_vertex(name);
+        println!(
+            "Vertex {} is gone from discovery, requeued {} jobs: {:?}",
+            name,
+            requeued.len(),
+            requeued
+        );
+    }
+    *known = found;
+}
+
+/// Add or refresh a vertex announcing itself. Names of static vertexes
+/// can't be taken.
+async fn register_vertex(
+    State(state): State<DispatcherCachedState>,
+    Json(registration): Json<Registration>,
+) -> StatusCode {
+    let secret = state
+        .configuration
+        .registration
+        .as_ref()
+        .map(|registration| registration.secret.as_str());
+    if secret != Some(registration.secret.as_str()) {
+        return StatusCode::UNAUTHORIZED;
+    }
+    let Registration {
+        name,
+        connect,
+        resources,
+        ..
+    } = registration;
+    if state.configuration.vertexes.contains_key(&name) {
+        return StatusCode::CONFLICT;
+    }
+    let previous = state
+        .registered
+        .lock_write()
+        .insert(name.clone(), (now_to_secs(), Some(connect.clone())));
+    let moved = previous.and_then(|(_, previous)| previous).as_ref() != Some(&connect);
+    let mut vertex_status = state.vertex_status.lock_write();
+    if let Some((client, _)) = vertex_status.get_mut(&name) {
+        if moved {
+            println!("Vertex {} registered a new connection", name);
+            *client = connect.create();
+        }
+    } else {
+        println!(
+            "Vertex {} registered with {} cpus and {:?}",
+            name,
+            resources.cpus.len(),
+            resources.countables.get_all()
+        );
+        vertex_status.insert(name, (connect.create(), now_to_micros()));
+    }
+    StatusCode::OK
+}
+
+/// Drop the registered vertexes silent for more than `ttl` seconds and
+/// queue their jobs again.
+fn expire_registrations(state: &DispatcherCachedState, ttl: u64) {
+    let now = now_to_secs();
+    let expired = state
+        .registered
+        .lock_read()
+        .iter()
+        .filter(|(_, (last, _))| now.saturating_sub(*last) > ttl)
+        .map(|(name, _)| name.clone())
+        .collect::<Vec<_>>();
+    for name in expired {
+        state.registered.lock_write().remove(&name);
+        state.vertex_status.lock_write().remove(&name);
+        state.vertex_free_cache.lock_write().remove(&name);
+        state.vertex_jobs_cache.lock_write().remove(&name);
+        let requeued = state.queues.lock_write().requeue_vertex(&name);
+        println!(
+            "Vertex {} stopped announcing itself, requeued {} jobs: {:?}",
+            name,
+            requeued.len(),
+            requeued
+        );
+    }
+}
+
+/// Check the running jobs of the persisted queues against the vertexes
+/// before scheduling anything. Jobs of vertexes no longer configured are
+/// queued again, unless they may still register, as are jobs answering
+/// vertexes don't know at all, like after a restart. Vertexes not answering
+/// keep their jobs until they are dead.
+async fn reconcile(state: &DispatcherCachedState) {
+    let recorded = state.queues.lock_read().running_vertexes();
+    for vertex in recorded {
+        if state.vertex_status.lock_read().contains_key(&vertex) {
+            continue;
+        }
+        if state.configuration.registration.is_some() {
+            // it may announce itself again, or its jobs are requeued once
+            // it expires
+            state.registered.lock_write().insert(vertex, (now_to_secs(), None));
+            continue;
+        }
+        let requeued = state.queues.lock_write().requeue_vertex(&vertex);
+        println!(
+            "Vertex {} is no longer configured, requeued {} jobs: {:?}",
+            vertex,
+            requeued.len(),
+            requeued
+        );
+    }
+    let frees = poll_vertexes(state).await;
+    refresh_vertex_jobs(state, &frees, true).await;
+}
+
+/// Fetch the free resources of every vertex, sorted by vertex name. Vertexes
+/// not answering are left out, and their jobs requeued once they are dead.
+async fn poll_vertexes(
+    state: &DispatcherCachedState,
+) -> Vec<(String, VertexClient, ResourcesProvider)> {
+    let mut vertexes = state
+        .vertex_status
+        .lock_read()
+        .iter()
+        .map(|(name, (client, _))| (name.clone(), client.clone()))
+        .collect::<Vec<_>>();
+    vertexes.sort_by(|(a, _), (b, _)| a.cmp(b));
+    let mut frees = Vec::new();
+    for (name, client) in vertexes {
+        let request_free = client.free();
+        let request_free = timeout(
+            Duration::from_micros(state.configuration.max_timeout),
+            request_free,
+        );
+        if let Ok(Ok(request_free)) = request_free.await {
+            if let Some((_, last_connected)) = state.vertex_status.lock_write().get_mut(&name)
+            {
                 *last_connected = now_to_micros();
-                let mut queues = cached_state.queues.write().unwrap();
-                while let Some((task_id, job, queue)) = queues.try_take_job(&request_free, false) {
-                    let resp = client.submit_job(&task_id, &job).await;
-                    if let Ok(resp) = resp {
-                        if let Some(_) = queues.truly_take_job(&queue, &task_id, &resp, &job) {
-                            println!("Submitted")
-                        } else {
-                            println!("Failed to submit job")
+            }
+            state
+                .vertex_free_cache
+                .lock_write()
+                .insert(name.clone(), request_free.clone());
+            frees.push((name, client, request_free));
+        } else if let Some(dead_after) = state.configuration.dead_after {
+            let last_connected = state
+                .vertex_status
+                .lock_read()
+                .get(&name)
+                .map(|(_, last_connected)| *last_connected)
+                .unwrap_or(0);
+            if now_to_micros().saturating_sub(last_connected) > dead_after as u128 {
+                let requeued = state.queues.lock_write().requeue_vertex(&name);
+                state.vertex_jobs_cache.lock_write().remove(&name);
+                state.vertex_free_cache.lock_write().remove(&name);
+                if !requeued.is_empty() {
+                    println!(
+                        "Vertex {} is dead, requeued {} jobs: {:?}",
+                        name,
+                        requeued.len(),
+                        requeued
+                    );
+                }
+            }
+        }
+    }
+    frees
+}
+
+/// Hand queued jobs to vertexes until no job fits anywhere. Among the
+/// vertexes able to run a job, the one best matching its affinity is tried
+/// first, then the one `packing` prefers. A vertex failing a submission
+/// `submit_retries` more times is left out for the rest of the pass, and
+/// the job goes to the next one. The free resources of a vertex are
+/// decreased locally as jobs are handed to it, so a stale snapshot doesn't
+/// overcommit it, and the pass stops once nothing fits anymore.
+async fn dispatch_jobs(
+    state: &DispatcherCachedState,
+    frees: &[(String, VertexClient, ResourcesProvider)],
+) {
+    let configuration = &state.configuration;
+    let mut frees = frees.to_vec();
+    // vertexes given a job in this pass, not yet running it in the cache
+    let mut busy = HashSet::new();
+    // vertexes failing a submission in this pass
+    let mut unavailable = HashSet::new();
+    let mut dispatched_count = 0;
+    while configuration
+        .max_dispatch_per_pass
+        .map(|max| dispatched_count < max)
+        .unwrap_or(true)
+    {
+        let now = now_to_secs();
+        let acceptable = |job: &JobConfiguration| {
+            frees.iter().any(|(name, _, free)| {
+                !unavailable.contains(name)
+                    && configuration
+                        .available_for(free, name, job.uid, now)
+                        .acceptable(&job.requirement)
+                    && (!job.requirement.whole_node || idle(state, name, &busy))
+            })
+        };
+        let next_job = state.scheduler.select(&state.queues.lock_read(), &acceptable);
+        if let Some((task_id, job, queue)) = next_job {
+            let mut candidates = frees
+                .iter()
+                .enumerate()
+                .map(|(index, (name, client, free))| {
+                    (index, name, client, configuration.available_for(free, name, job.uid, now))
+                })
+                .filter(|(_, name, _, available)| {
+                    !unavailable.contains(*name)
+                        && available.acceptable(&job.requirement)
+                        && (!job.requirement.whole_node || idle(state, name, &busy))
+                })
+                .collect::<Vec<_>>();
+            let packing = configuration.packing;
+            candidates.sort_by(|(_, _, _, a), (_, _, _, b)| {
+                let affinity = b
+                    .affinity(&job.prefer, &job.avoid)
+                    .cmp(&a.affinity(&job.prefer, &job.avoid));
+                affinity.then(
+                    packing
+                        .score(a, &job.requirement)
+                        .total_cmp(&packing.score(b, &job.requirement)),
+                )
+            });
+            let mut dispatched = None;
+            let skipped = unavailable.len();
+            for (index, name, client, available) in candidates {
+                let job = configuration.pin_cpus(&job, &available);
+                let mut submitted = client.submit_job(&task_id, &job).await;
+                for retry in 0..configuration.submit_retries {
+                    if submitted.is_ok() {
+                        break;
+                    }
+                    let delay = configuration.submit_retry_delay_ms << retry.min(16);
+                    tokio::time::sleep(Duration::from_millis(delay)).await;
+                    // the vertex answers a job it already runs with its id,
+                    // so a retry after a lost response doesn't run it twice
+                    submitted = client.submit_job(&task_id, &job).await;
+                }
+                if let Ok(resp) = submitted {
+                    if state
+                        .queues
+                        .lock_write()
+                        .truly_take_job(&queue, &task_id, &resp, &job, name)
+                        .is_ok()
+                    {
+                        state.metrics.dispatched();
+                        println!("Submitted");
+                        if let Some(notify_start) = &job.notify_start {
+                            let mut cpuset =
+                                job.requirement.cpus.take_set().iter().cloned().collect::<Vec<_>>();
+                            cpuset.sort();
+                            notify_start.fire(&StartNotification {
+                                task_id: resp.clone(),
+                                vertex: name.clone(),
+                                cpuset,
+                            });
+                        }
+                    } else {
+                        // deleted, modified or requeued while being submitted,
+                        // so the vertex runs a job nobody keeps track of
+                        state.metrics.failed();
+                        println!("Job {} left its queue while submitted, killing it", task_id);
+                        if let Err(err) = client.kill(&resp).await {
+                            println!("Failed to kill {} on {}: {}", resp, name, err);
                         }
                     }
+                    dispatched = Some((index, job));
+                    break;
+                } else {
+                    state.metrics.failed();
+                    println!("Vertex {} failed to take {}, skipped for this pass", name, task_id);
+                    unavailable.insert(name.clone());
                 }
             }
+            if let Some((index, job)) = dispatched {
+                frees[index].2.subtract(&job.requirement);
+                busy.insert(frees[index].0.clone());
+                if job.requirement.whole_node {
+                    frees[index].2.cpus.clear();
+                    frees[index].2.mems.clear();
+                }
+                dispatched_count += 1;
+            } else if unavailable.len() == skipped {
+                break;
+            }
+        } else {
+            break;
+        }
+    }
+}
+
+/// Whether `vertex` runs nothing, as far as the last poll and this pass tell.
+/// A vertex not polled yet may run anything, so it isn't.
+fn idle(state: &DispatcherCachedState, vertex: &str, busy: &HashSet<String>) -> bool {
+    !busy.contains(vertex)
+        && state
+            .vertex_jobs_cache
+            .lock_read()
+            .get(vertex)
+            .map(|jobs| {
+                !jobs
+                    .values()
+                    .any(|status| matches!(status, VertexJobStatus::Running { .. }))
+            })
+            .unwrap_or(false)
+}
 
-            let running_jobs = client.jobs();
-            let running_jobs = timeout(
-                Duration::from_micros(cached_state.configuration.max_timeout),
-                running_jobs,
-            );
+/// Forget the jobs the answering vertexes no longer run, firing `on_finish`
+/// for them. With `requeue_unknown`, jobs a vertex doesn't know at all are
+/// queued again instead.
+async fn refresh_vertex_jobs(
+    state: &DispatcherCachedState,
+    frees: &[(String, VertexClient, ResourcesProvider)],
+    requeue_unknown: bool,
+) {
+    for (name, client, _) in frees {
+        let running_jobs = client.jobs();
+        let running_jobs = timeout(
+            Duration::from_micros(state.configuration.max_timeout),
+            running_jobs,
+        );
 
-            if let Ok(Ok(runnings)) = running_jobs.await {
-                let running_ids = runnings.keys().cloned().collect::<HashSet<_>>();
-                cached_state
-                    .queues
-                    .write()
-                    .unwrap()
-                    .refresh_running(&running_ids);
+        if let Ok(Ok(runnings)) = running_jobs.await {
+            if requeue_unknown {
+                let known = runnings.keys().cloned().collect::<HashSet<_>>();
+                let requeued = state.queues.lock_write().requeue_unknown(name, &known);
+                if !requeued.is_empty() {
+                    println!(
+                        "Vertex {} doesn't know some of its jobs, requeued {} jobs: {:?}",
+                        name,
+                        requeued.len(),
+                        requeued
+                    );
+                }
+            }
+            let running_ids = runnings
+                .iter()
+                .filter(|(_, status)| matches!(status, VertexJobStatus::Running { .. }))
+                .map(|(id, _)| id.clone())
+                .collect::<HashSet<_>>();
+            let stopped = state
+                .queues
+                .lock_write()
+                .refresh_running(name, &running_ids);
+            if let Some(on_finish) = &state.configuration.on_finish {
+                for (task_id, _) in stopped {
+                    let (state, exit_code) = match runnings.get(&task_id) {
+                        Some(VertexJobStatus::Finished { exit_code, .. }) => {
+                            ("finished", Some(*exit_code))
+                        }
+                        Some(VertexJobStatus::Error { status_code, .. }) => {
+                            ("error", Some(*status_code))
+                        }
+                        _ => ("lost", None),
+                    };
+                    on_finish.fire(&FinishNotification {
+                        task_id,
+                        state: state.to_string(),
+                        exit_code,
+                    });
+                }
             }
+            state
+                .vertex_jobs_cache
+                .lock_write()
+                .insert(name.clone(), runnings);
         }
-        tokio::time::sleep(Duration::from_micros(
-            cached_state.configuration.loop_interval,
-        ))
-        .await;
     }
 }
 
-async fn get_request(stream: &mut UnixStream) -> Result<ClientRequest> {
-    let mut content = String::new();
-    let _size = stream.read_to_string(&mut content).await?;
-    let request: ClientRequest = serde_json::from_str(&content)?;
-    Ok(request)
+/// Have vertexes kill the jobs running longer than their queue allows.
+async fn kill_overruns(state: &DispatcherCachedState) {
+    let overruns = state.queues.lock_read().overruns(now_to_secs());
+    for (task_id, vertex) in overruns {
+        let client = state
+            .vertex_status
+            .lock_read()
+            .get(&vertex)
+            .map(|(client, _)| client.clone());
+        let killed = match client {
+            Some(client) => client.kill(&task_id).await,
+            None => Err(format!("vertex {} is gone", vertex)),
+        };
+        match killed {
+            Ok(()) => {
+                println!("Killed {} on {}, queue runtime limit reached", task_id, vertex);
+                state.queues.lock_write().forget_start(&task_id);
+            }
+            Err(err) => println!("Failed to kill {} on {}: {}", task_id, vertex, err),
+        }
+    }
+}
+
+fn save_queues(state: &DispatcherCachedState) {
+    let queues = state.queues.lock_read().queues().clone();
+    if let Err(err) = state.store.save(&queues) {
+        println!("Failed to save queues: {}", err);
+    }
+}
+
+async fn get_metrics(State(state): State<DispatcherCachedState>) -> String {
+    let depths = state.queues.lock_read().depths();
+    let alive_window = state.configuration.alive_window() as u128;
+    let now = now_to_micros();
+    let alive_vertexes = state
+        .vertex_status
+        .lock_read()
+        .values()
+        .filter(|(_, last_connected)| now.saturating_sub(*last_connected) <= alive_window)
+        .count();
+    state.metrics.render(&depths, alive_vertexes)
+}
+
+impl DispatcherCachedState {
+    fn new(
+        configuration: DispatcherConfig,
+        queues: HashMap<String, Queue>,
+        store: Arc<dyn QueueStore>,
+    ) -> Self {
+        let vertex_status = configuration
+            .vertexes
+            .iter()
+            .map(|(name, config)| (name.to_string(), (config.create(), now_to_micros())))
+            .collect::<HashMap<_, _>>();
+        let scheduler = configuration.scheduler.create();
+        Self {
+            configuration,
+            vertex_status: Arc::new(RwLock::new(vertex_status)),
+            queues: Arc::new(RwLock::new(QueueGroup::new(queues))),
+            vertex_jobs_cache: Arc::new(RwLock::new(HashMap::new())),
+            vertex_free_cache: Arc::new(RwLock::new(HashMap::new())),
+            submit_buckets: Arc::new(RwLock::new(HashMap::new())),
+            submissions: Arc::new(RwLock::new(HashMap::new())),
+            registered: Arc::new(RwLock::new(HashMap::new())),
+            metrics: Arc::new(Metrics::new()),
+            scheduler,
+            store,
+        }
+    }
+
+    /// Check the submit rate of `uid`, root is never limited.
+    fn rate_limit(&self, uid: u32) -> std::result::Result<(), u64> {
+        match &self.configuration.submit_rate {
+            Some(rate) if uid != 0 => {
+                let mut buckets = self.submit_buckets.lock_write();
+                let bucket = buckets.entry(uid).or_insert((rate.burst, now_to_micros()));
+                rate.take(bucket, now_to_micros())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Submit with `submit` unless `key` was used by `uid` recently, in which
+    /// case the queue and task id of the first submission are returned. Only
+    /// new submissions count in the metrics.
+    fn submit_once<E>(
+        &self,
+        uid: u32,
+        key: Option<String>,
+        submit: impl FnOnce(&mut QueueGroup) -> std::result::Result<(String, String), E>,
+    ) -> std::result::Result<(String, String), E> {
+        let key = if let Some(key) = key {
+            key
+        } else {
+            let submitted = submit(&mut self.queues.lock_write())?;
+            self.metrics.submitted();
+            return Ok(submitted);
+        };
+        let now = now_to_secs();
+        let mut submissions = self.submissions.lock_write();
+        submissions.retain(|_, (_, _, at)| now.saturating_sub(*at) < self.configuration.idempotency_ttl);
+        if let Some((queue, task_id, _)) = submissions.get(&(uid, key.clone())) {
+            return Ok((queue.clone(), task_id.clone()));
+        }
+        let (queue, task_id) = submit(&mut self.queues.lock_write())?;
+        self.metrics.submitted();
+        submissions.insert((uid, key), (queue.clone(), task_id.clone(), now));
+        Ok((queue, task_id))
+    }
+}
+
+/// Read a request up to the client half-closing the connection, giving up
+/// once it is larger than `max_request_bytes` or slower than
+/// `read_timeout_secs`.
+async fn get_request<S: AsyncRead + Unpin, T: DeserializeOwned>(
+    stream: &mut S,
+    configuration: &DispatcherConfig,
+) -> Result<T> {
+    let limit = configuration.max_request_bytes;
+    let read_timeout = Duration::from_secs(configuration.read_timeout_secs);
+    let mut content = Vec::new();
+    let mut stream = stream.take(limit + 1);
+    let size = if let Ok(size) = timeout(read_timeout, stream.read_to_end(&mut content)).await {
+        size?
+    } else {
+        println!(
+            "Warning: dropped a client not done sending its request within {}s",
+            configuration.read_timeout_secs
+        );
+        return Err(Error::new(ErrorKind::TimedOut, "request read timed out"));
+    };
+    if size as u64 > limit {
+        return Err(Error::new(ErrorKind::InvalidData, "request too large"));
+    }
+    decode(&content, limit)
+}
+
+/// Handle `request` as `uid`/`gid` in its own task, so a panicking handler
+/// still gets an answer.
+async fn handle_isolated(
+    request: ClientRequest,
+    state: &DispatcherCachedState,
+    uid: u32,
+    gid: u32,
+) -> DispatcherResponse {
+    let mut status = state.clone();
+    let handling = tokio::spawn(async move { request.handle(&mut status, uid, gid).await });
+    handling.await.unwrap_or_else(|err| {
+        println!("Request handling failed: {}", err);
+        DispatcherResponse::InternalError
+    })
+}
+
+async fn respond<S: AsyncWrite + Unpin>(stream: &mut S, response: &DispatcherResponse) {
+    let _ = stream
+        .write_all(serde_json::to_string(response).unwrap().as_bytes())
+        .await;
+    let _ = stream.shutdown().await;
 }
 
 impl ClientRequest {
-    async fn handle(self, status: &mut DispatcherCachedState, ucred: &UCred) -> DispatcherResponse {
+    async fn handle(self, status: &mut DispatcherCachedState, uid: u32, gid: u32) -> DispatcherResponse {
         match self {
-            Self::SubmitJob(queue, mut job) => {
-                if ucred.uid() != 0 {
-                    job.uid = ucred.uid();
-                    job.gid = ucred.gid();
-                }
-                let submit = status.queues.write().unwrap().add_to_queue(&queue, &job);
-                if let Ok(task_id) = submit {
-                    DispatcherResponse::SubmitSuccess(task_id)
+            Self::SubmitJob(queue, mut job, key) => {
+                if let Err(retry_after) = status.rate_limit(uid) {
+                    return DispatcherResponse::RateLimited(retry_after);
+                }
+                if uid != 0 {
+                    job.uid = uid;
+                    job.gid = gid;
+                }
+                if let Some(reason) = notify_rejection(&job, uid)
+                    .or(job.validate(status.configuration.allowed_commands.as_deref()).err()) {
+                    return DispatcherResponse::SubmitFailed(DispatcherFailReasons::Rejected(reason));
+                }
+                let queue = if let Some(queue) = queue.or(status.configuration.default_queue.clone()) {
+                    queue
+                } else {
+                    return DispatcherResponse::SubmitFailed(DispatcherFailReasons::NotFound);
+                };
+                let template = if let Some(template) = status.configuration.log_template(&job) {
+                    template
+                } else {
+                    return DispatcherResponse::SubmitFailed(DispatcherFailReasons::Rejected(
+                        format!("no home directory for uid {}", job.uid),
+                    ));
+                };
+                let submit = status.submit_once(job.uid, key, |queues| {
+                    queues.add_to_queue(&queue, &job).map(|task_id| {
+                        fill_log_files(queues, &task_id, &template);
+                        (queue.clone(), task_id)
+                    })
+                });
+                match submit {
+                    Ok((_, task_id)) => DispatcherResponse::SubmitSuccess(task_id),
+                    Err(err) => DispatcherResponse::SubmitFailed(err.into()),
+                }
+            }
+            Self::SubmitAuto(mut job, key) => {
+                if let Err(retry_after) = status.rate_limit(uid) {
+                    return DispatcherResponse::RateLimited(retry_after);
+                }
+                if uid != 0 {
+                    job.uid = uid;
+                    job.gid = gid;
+                }
+                if let Some(reason) = notify_rejection(&job, uid)
+                    .or(job.validate(status.configuration.allowed_commands.as_deref()).err()) {
+                    return DispatcherResponse::SubmitAutoFailed(vec![("*".to_string(), reason)]);
+                }
+                let template = if let Some(template) = status.configuration.log_template(&job) {
+                    template
                 } else {
-                    DispatcherResponse::SubmitFailed
+                    return DispatcherResponse::SubmitAutoFailed(vec![(
+                        "*".to_string(),
+                        format!("no home directory for uid {}", job.uid),
+                    )]);
+                };
+                let submit = status.submit_once(job.uid, key, |queues| {
+                    queues.add_to_any_queue(&job).map(|(queue, task_id)| {
+                        fill_log_files(queues, &task_id, &template);
+                        (queue, task_id)
+                    })
+                });
+                match submit {
+                    Ok((queue, task_id)) => DispatcherResponse::SubmitAutoSuccess { queue, task_id },
+                    Err(reasons) => DispatcherResponse::SubmitAutoFailed(reasons),
                 }
             }
             Self::DeleteJob(task_id) => {
-                let uid = ucred.uid();
-                if let Some(result) = status.queues.write().unwrap().remove_job(&task_id, uid) {
-                    if let Ok(_) = result {
+                let removed = status.queues.lock_write().remove_job(&task_id, uid);
+                match removed {
+                    Ok(()) => {
+                        status.metrics.deleted();
                         DispatcherResponse::DeleteSuccess
-                    } else {
-                        DispatcherResponse::DeleteFailed(DispatcherFailReasons::PermissionDenied)
                     }
-                } else {
-                    DispatcherResponse::DeleteFailed(DispatcherFailReasons::NotFound)
+                    Err(err) => DispatcherResponse::DeleteFailed(err.into()),
                 }
             }
+            Self::Modify(task_id, requirement) => {
+                let modified = status
+                    .queues
+                    .lock_write()
+                    .modify_job(&task_id, uid, &requirement);
+                match modified {
+                    Ok(()) => DispatcherResponse::ModifySuccess,
+                    Err(err) => DispatcherResponse::ModifyFailed(err.into()),
+                }
+            }
+            Self::RequeueJob(task_id, queue) => {
+                let requeued = status.queues.lock_write().requeue(&task_id, &queue, uid);
+                match requeued {
+                    Ok(()) => DispatcherResponse::RequeueSuccess,
+                    Err(err) => DispatcherResponse::RequeueFailed(err.into()),
+                }
+            }
+            Self::Permissions => {
+                let queues = status.queues.lock_read().permitted(uid, gid);
+                DispatcherResponse::Permissions { uid, gid, queues }
+            }
+            Self::DeleteByLabel(key, value) => {
+                let removed = status.queues.lock_write().remove_by_label(&key, &value, uid);
+                for _ in &removed {
+                    status.metrics.deleted();
+                }
+                DispatcherResponse::DeleteByLabelSuccess(removed)
+            }
             Self::Status => {
-                // DispatcherResponse::Status(())
-                todo!()
+                let jobs = status.queues.lock_read().summaries();
+                let vertex_jobs = status.vertex_jobs_cache.lock_read().clone();
+                let paused_queues = status.queues.lock_read().paused();
+                DispatcherResponse::Status {
+                    jobs,
+                    vertex_jobs,
+                    paused_queues,
+                }
+            }
+            Self::StatusByLabel(key, value) => {
+                let jobs = status.queues.lock_read().summaries_by_label(&key, &value);
+                let vertex_jobs = status
+                    .vertex_jobs_cache
+                    .lock_read()
+                    .iter()
+                    .map(|(vertex, jobs)| {
+                        let jobs = jobs
+                            .iter()
+                            .filter(|(_, job)| job.configuration().has_label(&key, &value))
+                            .map(|(task_id, job)| (task_id.clone(), job.clone()))
+                            .collect();
+                        (vertex.clone(), jobs)
+                    })
+                    .collect();
+                let paused_queues = status.queues.lock_read().paused();
+                DispatcherResponse::Status {
+                    jobs,
+                    vertex_jobs,
+                    paused_queues,
+                }
+            }
+            Self::Why(task_id) => {
+                let queued = status.queues.lock_read().queued_job(&task_id);
+                let (job, queue) = if let Some(queued) = queued {
+                    queued
+                } else {
+                    return DispatcherResponse::WhyFailed(DispatcherFailReasons::NotFound);
+                };
+                let alive_window = status.configuration.alive_window() as u128;
+                let now = now_to_micros();
+                let alive = status
+                    .vertex_status
+                    .lock_read()
+                    .iter()
+                    .filter(|(_, (_, last_connected))| {
+                        now.saturating_sub(*last_connected) <= alive_window
+                    })
+                    .map(|(name, _)| name.clone())
+                    .collect::<HashSet<_>>();
+                let now = now_to_secs();
+                let mut vertexes = status
+                    .vertex_free_cache
+                    .lock_read()
+                    .iter()
+                    .filter(|(name, _)| alive.contains(*name))
+                    .map(|(name, free)| {
+                        let mut failures = status
+                            .configuration
+                            .available_for(free, name, job.uid, now)
+                            .check(&job.requirement)
+                            .err()
+                            .unwrap_or_default()
+                            .iter()
+                            .map(|failure| failure.to_string())
+                            .collect::<Vec<_>>();
+                        if job.requirement.whole_node && !idle(status, name, &HashSet::new()) {
+                            failures.push("node not idle".to_string());
+                        }
+                        let reason = if failures.is_empty() {
+                            "fits".to_string()
+                        } else {
+                            failures.join(", ")
+                        };
+                        (name.clone(), reason)
+                    })
+                    .collect::<Vec<_>>();
+                vertexes.sort();
+                DispatcherResponse::Why { queue, vertexes }
+            }
+            Self::PauseQueue(_) | Self::ResumeQueue(_) if uid != 0 => {
+                DispatcherResponse::PauseFailed(DispatcherFailReasons::PermissionDenied)
+            }
+            Self::PauseQueue(queue) => set_paused(status, &queue, true),
+            Self::ResumeQueue(queue) => set_paused(status, &queue, false),
+            Self::Queues => DispatcherResponse::Queues(status.queues.lock_read().overview()),
+            Self::Log(task_id, from) => {
+                let found = status.vertex_jobs_cache.lock_read().iter().find_map(|(vertex, jobs)| {
+                    jobs.get(&task_id)
+                        .map(|job| (vertex.clone(), job.configuration().uid))
+                });
+                let (vertex, owner) = if let Some(found) = found {
+                    found
+                } else {
+                    return DispatcherResponse::LogFailed(DispatcherFailReasons::NotFound);
+                };
+                if owner != uid && uid != 0 {
+                    return DispatcherResponse::LogFailed(DispatcherFailReasons::PermissionDenied);
+                }
+                let client = status
+                    .vertex_status
+                    .lock_read()
+                    .get(&vertex)
+                    .map(|(client, _)| client.clone());
+                let log = match client {
+                    Some(client) => client.log(&task_id, from).await,
+                    None => Err(format!("vertex {} is gone", vertex)),
+                };
+                match log {
+                    Ok(data) => DispatcherResponse::Log(data),
+                    Err(err) => DispatcherResponse::LogFailed(DispatcherFailReasons::Rejected(err)),
+                }
+            }
+            Self::Suspend(_) if uid != 0 => {
+                DispatcherResponse::SuspendFailed(DispatcherFailReasons::PermissionDenied)
             }
+            Self::Suspend(task_id) => match set_frozen(status, &task_id, true).await {
+                Ok(()) => DispatcherResponse::SuspendSuccess,
+                Err(reason) => DispatcherResponse::SuspendFailed(reason),
+            },
+            Self::Resume(_) if uid != 0 => {
+                DispatcherResponse::ResumeFailed(DispatcherFailReasons::PermissionDenied)
+            }
+            Self::Resume(task_id) => match set_frozen(status, &task_id, false).await {
+                Ok(()) => DispatcherResponse::ResumeSuccess,
+                Err(reason) => DispatcherResponse::ResumeFailed(reason),
+            },
+            Self::PruneHistory(_) if uid != 0 => {
+                DispatcherResponse::PruneFailed(DispatcherFailReasons::PermissionDenied)
+            }
+            Self::PruneHistory(before) => {
+                let vertexes = status
+                    .vertex_status
+                    .lock_read()
+                    .iter()
+                    .map(|(name, (client, _))| (name.clone(), client.clone()))
+                    .collect::<Vec<_>>();
+                let mut pruned = HashMap::new();
+                for (name, client) in vertexes {
+                    pruned.insert(name, client.prune(before).await);
+                }
+                DispatcherResponse::PruneSuccess(pruned)
+            }
+            Self::DumpConfig => {
+                if uid == 0 {
+                    // passwords and the registration secret are redacted by
+                    // the serializers
+                    DispatcherResponse::Config(serde_json::to_value(&status.configuration).unwrap())
+                } else {
+                    DispatcherResponse::DumpConfigFailed(DispatcherFailReasons::PermissionDenied)
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::unix::encode;
+
+    /// State of a dispatcher with one open queue `q1` and no vertex, with
+    /// the fields of the `extra` YAML mapping set.
+    fn cached_state(extra: &str) -> DispatcherCachedState {
+        let mut configuration: serde_yaml::Mapping = serde_yaml::from_str(
+            "{listen: '', vertexes: {}, max_timeout: 1000, loop_interval: 1000, persistent: '', \
+             queues: {q1: {priority_rule: [], users: !Deny [], groups: !Deny [], properties: {}, \
+             global_limit: null, user_limit: null, group_limit: null}}}",
+        )
+        .unwrap();
+        let extra: serde_yaml::Mapping = serde_yaml::from_str(&format!("{{{}}}", extra)).unwrap();
+        configuration.extend(extra);
+        let configuration: DispatcherConfig =
+            serde_yaml::from_value(serde_yaml::Value::Mapping(configuration)).unwrap();
+        let queues = configuration
+            .queues
+            .iter()
+            .map(|(name, queue)| (name.clone(), Queue::new(queue)))
+            .collect();
+        let store = configuration.persistence.create("").unwrap();
+        DispatcherCachedState::new(configuration, queues, store)
+    }
+
+    fn job() -> Box<JobConfiguration> {
+        Box::new(
+            serde_yaml::from_str(
+                "{name: test, uid: 0, gid: 0, phases: [!Sh 'true'], \
+                 requirement: {cpus: !Use 1, mems: Auto, countables: {}, properties: {}}}",
+            )
+            .unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn repeated_idempotency_key_queues_once() {
+        let mut state = cached_state("");
+        let submit = |key: &str| ClientRequest::SubmitJob(Some("q1".to_string()), job(), Some(key.to_string()));
+        let first = submit("k").handle(&mut state, 0, 0).await;
+        let DispatcherResponse::SubmitSuccess(task_id) = first else {
+            panic!("submit failed: {:?}", first)
+        };
+        let again = submit("k").handle(&mut state, 0, 0).await;
+        assert!(matches!(again, DispatcherResponse::SubmitSuccess(id) if id == task_id));
+        assert_eq!(state.queues.lock_read().queues()["q1"].jobs_in_queue().len(), 1);
+        let other = submit("other").handle(&mut state, 0, 0).await;
+        assert!(matches!(other, DispatcherResponse::SubmitSuccess(id) if id != task_id));
+        assert_eq!(state.queues.lock_read().queues()["q1"].jobs_in_queue().len(), 2);
+        assert!(state.metrics.render(&[], 0).contains("job_dispatcher_jobs_submitted_total 2\n"));
+    }
+
+    #[test]
+    fn submit_rate_allows_a_burst_then_throttles() {
+        let rate = SubmitRate {
+            per_secs: 0.5,
+            burst: 3.,
+        };
+        let mut bucket = (rate.burst, 0);
+        for _ in 0..3 {
+            assert_eq!(rate.take(&mut bucket, 0), Ok(()));
         }
+        assert_eq!(rate.take(&mut bucket, 0), Err(2));
+        assert_eq!(rate.take(&mut bucket, 1_000_000), Err(1));
+        assert_eq!(rate.take(&mut bucket, 2_000_000), Ok(()));
+        assert_eq!(rate.take(&mut bucket, 2_000_000), Err(2));
+    }
+
+    #[tokio::test]
+    async fn oversized_requests_are_rejected() {
+        let state = cached_state("max_request_bytes: 100000");
+        let status = encode(&ClientRequest::Status);
+        let request: ClientRequest = get_request(&mut &status[..], &state.configuration).await.unwrap();
+        assert!(matches!(request, ClientRequest::Status));
+
+        let mut large = job();
+        large.name = "a".repeat(200_000);
+        let plain = serde_json::to_vec(&ClientRequest::SubmitJob(None, large.clone(), None)).unwrap();
+        let err = get_request::<_, ClientRequest>(&mut &plain[..], &state.configuration)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        // small once gzipped, too large once inflated
+        let gzipped = encode(&ClientRequest::SubmitJob(None, large, None));
+        assert!(gzipped.len() < 100_000);
+        let err = get_request::<_, ClientRequest>(&mut &gzipped[..], &state.configuration)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn whole_node_jobs_need_an_idle_vertex() {
+        let state = cached_state("");
+        let running = VertexJobStatus::Running {
+            configuration: *job(),
+            started_at: 0,
+            cpuset: vec![0],
+            memset: vec![0],
+            pid: 1,
+            frozen: false,
+        };
+        let finished = VertexJobStatus::Finished {
+            configuration: *job(),
+            exit_code: 0,
+            exit_at: 0,
+            runtime_secs: 0,
+            usage: Default::default(),
+        };
+        state.vertex_jobs_cache.lock_write().extend([
+            ("used".to_string(), HashMap::from([("a".to_string(), running), ("b".to_string(), finished.clone())])),
+            ("done".to_string(), HashMap::from([("c".to_string(), finished)])),
+        ]);
+        let no_busy = HashSet::new();
+        assert!(!idle(&state, "used", &no_busy));
+        assert!(idle(&state, "done", &no_busy));
+        assert!(!idle(&state, "new", &no_busy));
+        assert!(!idle(&state, "done", &HashSet::from(["done".to_string()])));
+    }
+
+    #[test]
+    fn unpolled_vertex_is_not_idle() {
+        let state = cached_state("");
+        let no_busy = HashSet::new();
+        assert!(!idle(&state, "unpolled", &no_busy));
+        state.vertex_jobs_cache.lock_write().insert("unpolled".to_string(), HashMap::new());
+        assert!(idle(&state, "unpolled", &no_busy));
+    }
+
+    #[cfg(feature = "mock-vertex")]
+    #[tokio::test]
+    async fn overrunning_job_is_killed_on_its_vertex() {
+        use crate::mock_vertex::MockVertex;
+        let resources = serde_yaml::from_str("{cpus: '0-3', mems: [0], countables: {}, properties: {}}").unwrap();
+        let (connect, _server) = MockVertex::new(resources, None)
+            .serve("127.0.0.1:0".parse().unwrap())
+            .await;
+        let state = cached_state(&format!(
+            "vertexes: {{m: {}}}, queues: {{q1: {{priority_rule: [], users: !Deny [], groups: !Deny [], \
+             properties: {{}}, global_limit: null, user_limit: null, group_limit: null, max_runtime_secs: 0}}}}",
+            serde_json::to_string(&connect).unwrap()
+        ));
+        let vertex = connect.create();
+        let send_id = state.queues.lock_write().add_to_queue("q1", &job()).unwrap();
+        let task_id = vertex.submit_job(&send_id, &job()).await.unwrap();
+        state
+            .queues
+            .lock_write()
+            .truly_take_job("q1", &send_id, &task_id, &job(), "m")
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        kill_overruns(&state).await;
+        let jobs = vertex.jobs().await.unwrap();
+        assert!(matches!(jobs[&task_id], VertexJobStatus::Error { .. }));
+        assert!(state.queues.lock_read().overruns(now_to_secs()).is_empty());
+    }
+
+    #[test]
+    fn vertex_gone_from_discovery_has_its_jobs_requeued() {
+        let directory = std::env::temp_dir().join(format!("jd_discovery_{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let discovery: DiscoveryConfig =
+            serde_yaml::from_str(&format!("{{directory: {}, interval: 1}}", directory.display())).unwrap();
+        let state = cached_state("");
+        let mut known = HashMap::new();
+        let vertex_file = directory.join("d.yaml");
+        fs::write(&vertex_file, "{url: 'http://127.0.0.1:1', username: u, password: p}").unwrap();
+        discover_vertexes(&state, &discovery, &mut known);
+        assert!(state.vertex_status.lock_read().contains_key("d"));
+
+        let send_id = state.queues.lock_write().add_to_queue("q1", &job()).unwrap();
+        state.queues.lock_write().truly_take_job("q1", &send_id, "on-d", &job(), "d").unwrap();
+        fs::remove_file(&vertex_file).unwrap();
+        discover_vertexes(&state, &discovery, &mut known);
+        fs::remove_dir_all(&directory).unwrap();
+
+        assert!(!state.vertex_status.lock_read().contains_key("d"));
+        let summaries = state.queues.lock_read().summaries();
+        assert_eq!(summaries.len(), 1);
+        assert!(summaries[0].requeued && summaries[0].vertex.is_none());
+        assert_ne!(summaries[0].task_id, "on-d");
+    }
+
+    #[cfg(feature = "mock-vertex")]
+    #[tokio::test]
+    async fn jobs_unknown_to_a_vertex_are_requeued_at_startup() {
+        use crate::mock_vertex::MockVertex;
+        let resources = serde_yaml::from_str("{cpus: '0-3', mems: [0], countables: {}, properties: {}}").unwrap();
+        let (connect, _server) = MockVertex::new(resources, None)
+            .serve("127.0.0.1:0".parse().unwrap())
+            .await;
+        let state = cached_state(&format!("vertexes: {{m: {}}}", serde_json::to_string(&connect).unwrap()));
+        let vertex = connect.create();
+        let send_id = state.queues.lock_write().add_to_queue("q1", &job()).unwrap();
+        let task_id = vertex.submit_job(&send_id, &job()).await.unwrap();
+        state.queues.lock_write().truly_take_job("q1", &send_id, &task_id, &job(), "m").unwrap();
+        // handed to the vertex before it restarted
+        let send_id = state.queues.lock_write().add_to_queue("q1", &job()).unwrap();
+        state.queues.lock_write().truly_take_job("q1", &send_id, "forgotten", &job(), "m").unwrap();
+        reconcile(&state).await;
+        let summaries = state.queues.lock_read().summaries();
+        assert_eq!(summaries.len(), 2);
+        assert!(summaries.iter().any(|summary| summary.task_id == task_id && summary.vertex.is_some()));
+        assert!(summaries.iter().any(|summary| summary.requeued && summary.vertex.is_none()));
+    }
+
+    #[tokio::test]
+    async fn dumped_config_hides_the_registration_secret() {
+        let mut state = cached_state("registration: {listen: '127.0.0.1:0', secret: hunter2}");
+        let DispatcherResponse::Config(config) = ClientRequest::DumpConfig.handle(&mut state, 0, 0).await else {
+            panic!("config not dumped")
+        };
+        assert_eq!(config["registration"]["secret"], "<redacted>");
+        assert!(!config.to_string().contains("hunter2"));
     }
 }
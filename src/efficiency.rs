@@ -0,0 +1,95 @@
+use std::collections::HashMap;
+
+use crate::{resources_management::NodesRequirement, vertex::VertexJobStatus};
+
+/// Share of a request below which a job is flagged as oversized.
+pub const WASTEFUL: f64 = 0.25;
+
+/// How much of what a finished job requested it actually used.
+#[derive(Debug, Clone)]
+pub struct Efficiency {
+    pub task_id: String,
+    pub name: String,
+    pub vertex: String,
+    pub uid: u32,
+    /// Cpu time over runtime times the cpus given, unknown if the cgroup
+    /// didn't tell or the job ran no time.
+    pub cpu: Option<f64>,
+    /// Peak memory over the requested `memory` countable.
+    pub memory: Option<f64>,
+}
+
+impl Efficiency {
+    /// The lowest known ratio, which the jobs are sorted by.
+    pub fn worst(&self) -> Option<f64> {
+        [self.cpu, self.memory].into_iter().flatten().reduce(f64::min)
+    }
+
+    pub fn wasteful(&self) -> bool {
+        self.worst().map(|worst| worst < WASTEFUL).unwrap_or(false)
+    }
+}
+
+/// Efficiency of a job finished at or after `since`, `None` for other
+/// statuses.
+pub fn efficiency(
+    task_id: &str,
+    vertex: &str,
+    status: &VertexJobStatus,
+    since: u64,
+) -> Option<Efficiency> {
+    let (configuration, usage, runtime_secs) = match status {
+        VertexJobStatus::Finished {
+            configuration,
+            usage,
+            runtime_secs,
+            exit_at,
+            ..
+        } if *exit_at >= since => (configuration, usage, *runtime_secs),
+        _ => return None,
+    };
+    let requirement = &configuration.requirement;
+    let cpus = match &requirement.cpus {
+        NodesRequirement::Select(set) => set.len(),
+        NodesRequirement::Use(size) => *size,
+        NodesRequirement::Auto => 0,
+    };
+    let cpu = usage
+        .cpu_time_usecs
+        .filter(|_| cpus > 0 && runtime_secs > 0)
+        .map(|usecs| usecs as f64 / (runtime_secs as f64 * 1e6 * cpus as f64));
+    let requested_memory = requirement.countables.get("memory");
+    let memory = usage
+        .peak_memory_bytes
+        .filter(|_| requested_memory > 0)
+        .map(|peak| peak as f64 / requested_memory as f64);
+    Some(Efficiency {
+        task_id: task_id.to_string(),
+        name: configuration.name.clone(),
+        vertex: vertex.to_string(),
+        uid: configuration.uid,
+        cpu,
+        memory,
+    })
+}
+
+/// Efficiency of every job of `vertex_jobs` finished since `since`, least
+/// efficient first. Jobs without any known ratio come last.
+pub fn least_efficient(
+    vertex_jobs: &HashMap<String, HashMap<String, VertexJobStatus>>,
+    since: u64,
+) -> Vec<Efficiency> {
+    let mut jobs = vertex_jobs
+        .iter()
+        .flat_map(|(vertex, statuses)| {
+            statuses
+                .iter()
+                .filter_map(move |(task_id, status)| efficiency(task_id, vertex, status, since))
+        })
+        .collect::<Vec<_>>();
+    jobs.sort_by(|a, b| match (a.worst(), b.worst()) {
+        (Some(a), Some(b)) => a.total_cmp(&b),
+        (a, b) => a.is_none().cmp(&b.is_none()),
+    });
+    jobs
+}
@@ -1,6 +1,11 @@
-use crate::jobs_management::JobConfiguration;
+use std::process;
 
-pub fn executor(input: &str) {
+use crate::jobs_management::{failure_exit_code, JobConfiguration};
+
+pub async fn executor(input: &str) {
     let job_configuration: JobConfiguration = serde_json::from_str(input).unwrap();
-    job_configuration.execute().unwrap();
-}
\ No newline at end of file
+    if let Err(err) = job_configuration.execute().await {
+        eprintln!("{}", err);
+        process::exit(failure_exit_code(&err));
+    }
+}
@@ -1,5 +1,15 @@
 use serde::{Deserialize, Serialize};
-use std::{net::IpAddr, collections::HashMap};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufReader},
+    net::IpAddr,
+    sync::Arc,
+};
+
+use axum_server::tls_rustls::RustlsConfig;
+use rustls::{server::AllowAnyAuthenticatedClient, Certificate, PrivateKey, RootCertStore, ServerConfig};
+use rustls_pemfile::Item;
 
 use axum::{
     TypedHeader,
@@ -25,6 +35,63 @@ impl Default for HttpServerConfig {
     }
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TlsConfig {
+    pub cert: String,
+    pub key: String,
+    /// When set, clients must present a certificate signed by this CA.
+    #[serde(default)]
+    pub client_ca: Option<String>,
+}
+
+impl TlsConfig {
+    pub fn rustls_config(&self) -> io::Result<RustlsConfig> {
+        let certs = read_certs(&self.cert)?;
+        let key = read_key(&self.key)?;
+        let builder = ServerConfig::builder().with_safe_defaults();
+        let builder = if let Some(client_ca) = &self.client_ca {
+            let mut roots = RootCertStore::empty();
+            for cert in read_certs(client_ca)? {
+                roots
+                    .add(&cert)
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            }
+            builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots).boxed())
+        } else {
+            builder.with_no_client_auth()
+        };
+        let mut config = builder
+            .with_single_cert(certs, key)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Ok(RustlsConfig::from_config(Arc::new(config)))
+    }
+}
+
+fn read_certs(path: &str) -> io::Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    Ok(rustls_pemfile::certs(&mut reader)?
+        .into_iter()
+        .map(Certificate)
+        .collect())
+}
+
+fn read_key(path: &str) -> io::Result<PrivateKey> {
+    let mut reader = BufReader::new(File::open(path)?);
+    while let Some(item) = rustls_pemfile::read_one(&mut reader)? {
+        match item {
+            Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key) => {
+                return Ok(PrivateKey(key))
+            }
+            _ => {}
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("No private key found in {}", path),
+    ))
+}
+
 pub async fn basic_check<B>(
     State(user_table): State<HashMap<String, String>>,
     TypedHeader(Authorization(basic)): TypedHeader<Authorization<Basic>>,
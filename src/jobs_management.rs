@@ -1,9 +1,26 @@
-use std::{collections::HashMap, env, process::Command};
+use std::{
+    collections::HashMap,
+    env, fmt,
+    fs::{self, File},
+    io,
+    os::unix::{fs::PermissionsExt, process::ExitStatusExt},
+    path::{Path, PathBuf},
+    process::{ExitStatus, Stdio},
+    time::Duration,
+};
 
 use reqwest::Body;
 use serde::{Deserialize, Serialize};
+use tokio::{
+    io::AsyncWriteExt,
+    process::{Child, Command},
+    time::{timeout, Instant},
+};
 
-use crate::resources_management::ResourcesRequirement;
+use crate::{
+    notify::NotifyConfig,
+    resources_management::{Properties, ResourcesRequirement},
+};
 
 #[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
 pub enum ExecutePhase {
@@ -11,61 +28,715 @@ pub enum ExecutePhase {
     Run(Vec<String>),
     WorkDir(String),
     Env(HashMap<String, String>),
+    /// `Sh` and `Run` phases started together. The group waits for all of
+    /// them and fails if any does.
+    Parallel(Vec<ExecutePhase>),
+}
+
+/// Standard input given to every command of a job.
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
+pub enum JobInput {
+    /// Path of a file, opened by each command.
+    File(String),
+    /// Data written to each command, which then sees EOF.
+    Inline(String),
+}
+
+/// Mount namespace of a job. Setting it up needs the supervisor to run as
+/// root, which it does when started by a vertex running as root.
+#[derive(PartialEq, Debug, Clone, Default, Serialize, Deserialize)]
+pub struct IsolationConfig {
+    /// Give the job its own empty `/tmp`.
+    #[serde(default)]
+    pub private_tmp: bool,
+    #[serde(default)]
+    pub binds: Vec<BindMount>,
+}
+
+/// Directory `source` of the vertex mounted at the absolute path `target`.
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
+pub struct BindMount {
+    pub source: String,
+    pub target: String,
+    #[serde(default)]
+    pub read_only: bool,
+}
+
+impl JobInput {
+    fn spawn(&self, command: &mut Command) -> Result<Child, std::io::Error> {
+        match self {
+            Self::File(path) => command.stdin(Stdio::from(File::open(path)?)).spawn(),
+            Self::Inline(data) => {
+                let mut child = command.stdin(Stdio::piped()).spawn()?;
+                let mut stdin = child.stdin.take().unwrap();
+                let data = data.clone();
+                // dropping the pipe once written closes it
+                tokio::spawn(async move {
+                    let _ = stdin.write_all(data.as_bytes()).await;
+                });
+                Ok(child)
+            }
+        }
+    }
 }
 
 impl ExecutePhase {
-    pub fn execute(&self) -> Result<(), std::io::Error> {
+    /// Run the phase. A child process still running after `time_limit` is
+    /// killed and the phase fails.
+    pub async fn execute(
+        &self,
+        time_limit: Option<Duration>,
+        stdin: Option<&JobInput>,
+    ) -> Result<(), std::io::Error> {
+        let spawn = |command: &mut Command| match stdin {
+            Some(stdin) => stdin.spawn(command),
+            None => command.spawn(),
+        };
         match self {
-            Self::Sh(script) => Command::new("sh")
-                .arg("-c")
-                .arg(script)
-                .spawn()
-                .map(|mut child| child.wait())
-                .map(|_| ()),
-            Self::Run(commands) => {
-                let program = &commands[0];
-                let arguments = commands.iter().skip(1).collect::<Vec<_>>();
-                Command::new(program)
-                    .args(arguments)
-                    .spawn()
-                    .map(|mut child| child.wait())
-                    .map(|_| ())
+            Self::Sh(_) | Self::Run(_) => {
+                let child = spawn(&mut self.command().unwrap())?;
+                wait_child(child, time_limit).await
             }
-            Self::WorkDir(workdir) => env::set_current_dir(workdir).map(|_| ()),
+            Self::WorkDir(workdir) => env::set_current_dir(workdir)
+                .map_err(|err| io::Error::new(err.kind(), format!("{}: {}", workdir, err))),
             Self::Env(envs) => {
                 for (k, v) in envs.iter() {
                     env::set_var(k, v);
                 }
                 Ok(())
             }
+            Self::Parallel(phases) => {
+                let deadline = time_limit.map(|time_limit| Instant::now() + time_limit);
+                let children = phases
+                    .iter()
+                    .map(|phase| match phase.command() {
+                        Some(mut command) => spawn(&mut command),
+                        None => Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            "only Sh and Run phases run in parallel",
+                        )),
+                    })
+                    .collect::<Vec<_>>();
+                let mut result = Ok(());
+                for (index, child) in children.into_iter().enumerate() {
+                    let remaining =
+                        deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+                    let exit = match child {
+                        Ok(child) => wait_child(child, remaining).await,
+                        Err(err) => Err(err),
+                    };
+                    if let (Err(err), true) = (exit, result.is_ok()) {
+                        result = Err(phase_failed(err, format!("parallel phase {} failed", index)));
+                    }
+                }
+                result
+            }
+        }
+    }
+
+    /// The command a `Sh` or `Run` phase spawns.
+    fn command(&self) -> Option<Command> {
+        match self {
+            Self::Sh(script) => {
+                let mut command = Command::new("sh");
+                command.arg("-c").arg(script);
+                Some(command)
+            }
+            Self::Run(commands) => {
+                let mut command = Command::new(&commands[0]);
+                command.args(&commands[1..]);
+                Some(command)
+            }
+            _ => None,
+        }
+    }
+
+    /// Programs the phase starts, `sh` for `Sh` phases.
+    fn programs(&self) -> Vec<&str> {
+        match self {
+            Self::Sh(_) => vec!["sh"],
+            Self::Run(commands) => commands.iter().take(1).map(String::as_str).collect(),
+            Self::Parallel(phases) => phases.iter().flat_map(Self::programs).collect(),
+            Self::WorkDir(_) | Self::Env(_) => Vec::new(),
+        }
+    }
+
+    /// Whether the phase has something to run or change to.
+    fn is_valid(&self) -> bool {
+        match self {
+            Self::Run(commands) => {
+                commands.first().map(|program| !program.is_empty()).unwrap_or(false)
+            }
+            Self::WorkDir(workdir) => !workdir.is_empty(),
+            Self::Sh(_) | Self::Env(_) => true,
+            Self::Parallel(phases) => {
+                !phases.is_empty()
+                    && phases.iter().all(|phase| {
+                        matches!(phase, Self::Sh(_) | Self::Run(_)) && phase.is_valid()
+                    })
+            }
         }
     }
 }
 
+async fn wait_child(mut child: Child, time_limit: Option<Duration>) -> Result<(), std::io::Error> {
+    let status = if let Some(time_limit) = time_limit {
+        match timeout(time_limit, child.wait()).await {
+            Ok(status) => status?,
+            Err(_) => {
+                child.kill().await?;
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("timed out after {}s", time_limit.as_secs()),
+                ));
+            }
+        }
+    } else {
+        child.wait().await?
+    };
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(PhaseExit { exit_code: exit_code(status), message: status.to_string() }))
+    }
+}
+
+/// Exit code of a process, `128 + signal` if it was killed by a signal, like
+/// shells report it.
+pub fn exit_code(status: ExitStatus) -> i32 {
+    status.code().unwrap_or_else(|| 128 + status.signal().unwrap_or(0))
+}
+
+/// Error of a phase whose program exited unsuccessfully.
+#[derive(Debug)]
+struct PhaseExit {
+    exit_code: i32,
+    message: String,
+}
+
+impl fmt::Display for PhaseExit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for PhaseExit {}
+
+/// `err` prefixed with `context`, keeping the exit code of a failed program.
+fn phase_failed(err: io::Error, context: String) -> io::Error {
+    let message = format!("{}: {}", context, err);
+    match err.get_ref().and_then(|inner| inner.downcast_ref::<PhaseExit>()) {
+        Some(PhaseExit { exit_code, .. }) => {
+            io::Error::new(err.kind(), PhaseExit { exit_code: *exit_code, message })
+        }
+        None => io::Error::new(err.kind(), message),
+    }
+}
+
+/// Exit code for a job that failed with `err`: the one of the program that
+/// failed, 1 if none did.
+pub fn failure_exit_code(err: &io::Error) -> i32 {
+    err.get_ref()
+        .and_then(|inner| inner.downcast_ref::<PhaseExit>())
+        .map(|exit| exit.exit_code)
+        .unwrap_or(1)
+}
+
 #[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
 pub struct JobConfiguration {
     pub name: String,
     pub uid: u32,
     pub gid: u32,
+    /// Log files, left empty to use the `log_dir_template` of the dispatcher.
+    #[serde(default)]
     pub stdout_file: String,
+    #[serde(default)]
     pub stderr_file: String,
+    /// Total bytes of stdout and stderr kept before the job is killed.
+    #[serde(default)]
+    pub max_log_bytes: Option<u64>,
+    #[serde(default)]
+    pub stdin: Option<JobInput>,
+    /// Cgroup cpu shares, set from the queue the job is submitted to.
+    #[serde(default)]
+    pub cpu_weight: Option<u64>,
+    /// Cpu time allowed in percent of one cpu, even on an idle node.
+    #[serde(default)]
+    pub cpu_quota_percent: Option<u32>,
+    /// Upper bound of processes in the job.
+    #[serde(default)]
+    pub pids_max: Option<u64>,
+    /// Fail the job instead of warning when its cpu affinity doesn't match
+    /// the cpuset it was given.
+    #[serde(default)]
+    pub strict_affinity: bool,
+    /// Confine the job to the cpus and memory nodes it was given. When off,
+    /// they only count for placement and the job gets cpu shares in
+    /// proportion to its cpus instead: it may spread over idle cpus of the
+    /// node, but competes with other jobs for them and loses its numa
+    /// locality.
+    #[serde(default = "default_strict_pinning")]
+    pub strict_pinning: bool,
+    /// Create the missing directories of `WorkDir` phases before running,
+    /// owned by the job owner with `workdir_mode` (0o755 by default).
+    #[serde(default)]
+    pub create_workdir: bool,
+    #[serde(default)]
+    pub workdir_mode: Option<u32>,
+    /// Start the job with only `PATH` and `HOME` instead of the environment
+    /// of the vertex, `Env` phases still apply.
+    #[serde(default)]
+    pub clean_env: bool,
+    /// File of `KEY=VALUE` lines set in the environment before the first
+    /// phase, read as the job owner. See `parse_env_file`.
+    #[serde(default)]
+    pub env_file: Option<String>,
+    /// Told once the job is handed to a vertex.
+    #[serde(default)]
+    pub notify_start: Option<NotifyConfig>,
+    /// Run the job in its own mount namespace.
+    #[serde(default)]
+    pub isolate: Option<IsolationConfig>,
+    /// Report the stderr of a failed job base64 encoded instead of as
+    /// possibly mangled text.
+    #[serde(default)]
+    pub binary_output: bool,
+    /// Free-form tags to find the job by, like `experiment: exp42`.
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+    /// Unix time the job must start before, it is cancelled once missed.
+    #[serde(default)]
+    pub start_before: Option<u64>,
     pub requirement: ResourcesRequirement,
+    /// Properties of vertexes this job would rather run on.
+    #[serde(default)]
+    pub prefer: Properties,
+    /// Properties of vertexes this job would rather not run on.
+    #[serde(default)]
+    pub avoid: Properties,
     phases: Vec<ExecutePhase>,
+    /// Timeout in seconds of the phase at the same index, `None` or a missing
+    /// entry leaves the phase bounded by the job time limit only.
+    #[serde(default)]
+    phase_timeouts: Vec<Option<u64>>,
+    /// Phases run once the job ended, however it ended, within
+    /// `cleanup_timeout` seconds. Their failure doesn't fail the job.
+    #[serde(default)]
+    cleanup: Vec<ExecutePhase>,
+    #[serde(default = "default_cleanup_timeout")]
+    pub cleanup_timeout: u64,
 }
 
-impl Into<Body> for JobConfiguration {
-    fn into(self) -> Body {
+fn default_strict_pinning() -> bool {
+    true
+}
+
+fn default_cleanup_timeout() -> u64 {
+    60
+}
+
+/// Fluent construction of a `JobConfiguration` for library users, the
+/// binary itself only deserializes jobs.
+pub struct JobConfigurationBuilder {
+    job: JobConfiguration,
+}
+
+impl JobConfigurationBuilder {
+    pub fn new(name: &str, uid: u32, gid: u32, requirement: ResourcesRequirement) -> Self {
+        Self {
+            job: JobConfiguration {
+                name: name.to_string(),
+                uid,
+                gid,
+                stdout_file: String::new(),
+                stderr_file: String::new(),
+                max_log_bytes: None,
+                stdin: None,
+                cpu_weight: None,
+                cpu_quota_percent: None,
+                pids_max: None,
+                strict_affinity: false,
+                strict_pinning: default_strict_pinning(),
+                create_workdir: false,
+                workdir_mode: None,
+                clean_env: false,
+                env_file: None,
+                notify_start: None,
+                isolate: None,
+                binary_output: false,
+                labels: HashMap::new(),
+                start_before: None,
+                requirement,
+                prefer: Properties::default(),
+                avoid: Properties::default(),
+                phases: Vec::new(),
+                phase_timeouts: Vec::new(),
+                cleanup: Vec::new(),
+                cleanup_timeout: default_cleanup_timeout(),
+            },
+        }
+    }
+
+    pub fn phase(mut self, phase: ExecutePhase) -> Self {
+        self.job.phases.push(phase);
+        self.job.phase_timeouts.push(None);
+        self
+    }
+
+    /// Add a phase failing after `timeout` seconds.
+    pub fn phase_with_timeout(mut self, phase: ExecutePhase, timeout: u64) -> Self {
+        self.job.phases.push(phase);
+        self.job.phase_timeouts.push(Some(timeout));
+        self
+    }
+
+    pub fn log_files(mut self, stdout_file: &str, stderr_file: &str) -> Self {
+        self.job.stdout_file = stdout_file.to_string();
+        self.job.stderr_file = stderr_file.to_string();
+        self
+    }
+
+    pub fn max_log_bytes(mut self, max_log_bytes: u64) -> Self {
+        self.job.max_log_bytes = Some(max_log_bytes);
+        self
+    }
+
+    pub fn stdin(mut self, stdin: JobInput) -> Self {
+        self.job.stdin = Some(stdin);
+        self
+    }
+
+    pub fn cpu_quota_percent(mut self, percent: u32) -> Self {
+        self.job.cpu_quota_percent = Some(percent);
+        self
+    }
+
+    pub fn pids_max(mut self, pids_max: u64) -> Self {
+        self.job.pids_max = Some(pids_max);
+        self
+    }
+
+    pub fn soft_pinning(mut self) -> Self {
+        self.job.strict_pinning = false;
+        self
+    }
+
+    pub fn strict_affinity(mut self) -> Self {
+        self.job.strict_affinity = true;
+        self
+    }
+
+    /// Create missing working directories, with `mode` or 0o755.
+    pub fn create_workdir(mut self, mode: Option<u32>) -> Self {
+        self.job.create_workdir = true;
+        self.job.workdir_mode = mode;
+        self
+    }
+
+    pub fn clean_env(mut self) -> Self {
+        self.job.clean_env = true;
+        self
+    }
+
+    pub fn env_file(mut self, path: &str) -> Self {
+        self.job.env_file = Some(path.to_string());
+        self
+    }
+
+    pub fn notify_start(mut self, notify: NotifyConfig) -> Self {
+        self.job.notify_start = Some(notify);
+        self
+    }
+
+    pub fn isolate(mut self, isolate: IsolationConfig) -> Self {
+        self.job.isolate = Some(isolate);
+        self
+    }
+
+    pub fn binary_output(mut self) -> Self {
+        self.job.binary_output = true;
+        self
+    }
+
+    pub fn label(mut self, key: &str, value: &str) -> Self {
+        self.job.labels.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    pub fn start_before(mut self, deadline: u64) -> Self {
+        self.job.start_before = Some(deadline);
+        self
+    }
+
+    pub fn prefer(mut self, prefer: Properties) -> Self {
+        self.job.prefer = prefer;
+        self
+    }
+
+    pub fn avoid(mut self, avoid: Properties) -> Self {
+        self.job.avoid = avoid;
+        self
+    }
+
+    /// Add a phase run after the job, see `JobConfiguration::cleanup`.
+    pub fn cleanup(mut self, phase: ExecutePhase) -> Self {
+        self.job.cleanup.push(phase);
+        self
+    }
+
+    pub fn build(self) -> Result<JobConfiguration, String> {
+        self.job.validate(None)?;
+        Ok(self.job)
+    }
+}
+
+/// Resources a job actually used, as far as its cgroup tells.
+#[derive(PartialEq, Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ResourceUsage {
+    pub peak_memory_bytes: Option<u64>,
+    pub cpu_time_usecs: Option<u64>,
+}
+
+impl From<JobConfiguration> for Body {
+    fn from(job: JobConfiguration) -> Self {
         Body::from(
-            serde_json::to_string(&self).unwrap()
+            serde_json::to_string(&job).unwrap()
         )
     }
 }
 
 impl JobConfiguration {
-    pub fn execute(&self) -> Result<(), std::io::Error> {
-        for phase in &self.phases {
-            phase.execute()?
+    /// Check what deserializing can't: a name, at least one phase, no empty
+    /// command or directory, no timeout without its phase, complete bind
+    /// mounts and, with `allowed_commands`, only those programs started.
+    /// Cleanup phases are numbered after the job phases.
+    pub fn validate(&self, allowed_commands: Option<&[String]>) -> Result<(), String> {
+        if self.name.is_empty() {
+            return Err("job name is empty".to_string());
+        }
+        if self.phases.is_empty() {
+            return Err("job has no phases".to_string());
+        }
+        for (index, phase) in self.phases.iter().chain(self.cleanup.iter()).enumerate() {
+            if !phase.is_valid() {
+                return Err(format!("phase {} has nothing to run or change to", index));
+            }
+            let disallowed = allowed_commands.and_then(|allowed| {
+                phase
+                    .programs()
+                    .into_iter()
+                    .find(|program| !allowed.iter().any(|command| command == program))
+            });
+            if let Some(program) = disallowed {
+                return Err(format!("phase {} runs {}, which is not allowed", index, program));
+            }
+        }
+        if self.phase_timeouts.len() > self.phases.len() {
+            return Err("more phase timeouts than phases".to_string());
+        }
+        let binds = self.isolate.iter().flat_map(|isolate| isolate.binds.iter());
+        for bind in binds {
+            if bind.source.is_empty() || !bind.target.starts_with('/') {
+                return Err(format!("bind mount {} needs a source and an absolute target", bind.target));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn has_label(&self, key: &str, value: &str) -> bool {
+        self.labels.get(key).map(String::as_str) == Some(value)
+    }
+
+    /// The job running the cleanup phases of this one, if it has any.
+    pub fn cleanup_job(&self) -> Option<JobConfiguration> {
+        if self.cleanup.is_empty() {
+            return None;
+        }
+        Some(JobConfiguration {
+            phases: self.cleanup.clone(),
+            phase_timeouts: Vec::new(),
+            cleanup: Vec::new(),
+            stdin: None,
+            ..self.clone()
+        })
+    }
+
+    /// Directories the `WorkDir` phases change to, relative ones resolved
+    /// against `base` and the previous phases.
+    pub fn workdirs(&self, base: &Path) -> Vec<PathBuf> {
+        let mut current = base.to_path_buf();
+        let mut workdirs = Vec::new();
+        for phase in self.phases.iter() {
+            if let ExecutePhase::WorkDir(workdir) = phase {
+                current = current.join(workdir);
+                workdirs.push(current.clone());
+            }
+        }
+        workdirs
+    }
+
+    /// Create the missing working directories with `workdir_mode`, as whoever
+    /// runs the executor, the job owner.
+    fn create_workdirs(&self, base: &Path) -> Result<(), std::io::Error> {
+        let mode = self.workdir_mode.unwrap_or(0o755);
+        for workdir in self.workdirs(base) {
+            if workdir.exists() {
+                continue;
+            }
+            fs::create_dir_all(&workdir)
+                .and_then(|_| fs::set_permissions(&workdir, fs::Permissions::from_mode(mode)))
+                .map_err(|err| io::Error::new(err.kind(), format!("{}: {}", workdir.display(), err)))?;
+        }
+        Ok(())
+    }
+
+    pub async fn execute(&self) -> Result<(), std::io::Error> {
+        // the executor already runs as the job owner
+        if let Some(env_file) = &self.env_file {
+            let content = fs::read_to_string(env_file)
+                .map_err(|err| io::Error::new(err.kind(), format!("{}: {}", env_file, err)))?;
+            let envs = parse_env_file(&content).map_err(|err| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", env_file, err))
+            })?;
+            for (k, v) in envs {
+                env::set_var(k, v);
+            }
+        }
+        if self.create_workdir {
+            self.create_workdirs(&env::current_dir()?)?;
+        }
+        for (index, phase) in self.phases.iter().enumerate() {
+            let time_limit = self
+                .phase_timeouts
+                .get(index)
+                .cloned()
+                .flatten()
+                .map(Duration::from_secs);
+            phase
+                .execute(time_limit, self.stdin.as_ref())
+                .await
+                .map_err(|err| phase_failed(err, format!("Phase {} failed", index)))?
         }
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+/// Variables of an env file: `KEY=VALUE` lines, optionally prefixed with
+/// `export`. Blank lines and lines starting with `#` are skipped, and a value
+/// wrapped in single or double quotes is taken without them.
+pub fn parse_env_file(content: &str) -> Result<Vec<(String, String)>, String> {
+    let mut envs = Vec::new();
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (key, value) = line
+            .split_once('=')
+            .ok_or(format!("line {} is not KEY=VALUE", index + 1))?;
+        let key = key.trim();
+        if key.is_empty() {
+            return Err(format!("line {} has no variable name", index + 1));
+        }
+        let value = value.trim();
+        let value = ['"', '\'']
+            .iter()
+            .find_map(|quote| {
+                value
+                    .strip_prefix(*quote)
+                    .and_then(|value| value.strip_suffix(*quote))
+            })
+            .unwrap_or(value);
+        envs.push((key.to_string(), value.to_string()));
+    }
+    Ok(envs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn parallel_fails_if_a_phase_fails() {
+        let phases = vec![ExecutePhase::Sh("true".to_string()), ExecutePhase::Sh("false".to_string())];
+        let err = ExecutePhase::Parallel(phases).execute(None, None).await.unwrap_err();
+        assert!(err.to_string().starts_with("parallel phase 1 failed"));
+        let phases = vec![ExecutePhase::Sh("true".to_string()), ExecutePhase::Sh("true".to_string())];
+        assert!(ExecutePhase::Parallel(phases).execute(None, None).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn phase_fails_on_non_zero_exit() {
+        assert!(ExecutePhase::Sh("exit 3".to_string()).execute(None, None).await.is_err());
+        let timed_out = ExecutePhase::Sh("sleep 5".to_string())
+            .execute(Some(Duration::from_millis(100)), None)
+            .await
+            .unwrap_err();
+        assert_eq!(timed_out.kind(), io::ErrorKind::TimedOut);
+    }
+
+    fn job(phases: &str) -> JobConfiguration {
+        serde_yaml::from_str(&format!(
+            "{{name: test, uid: 1000, gid: 1000, phases: {}, \
+             requirement: {{cpus: Auto, mems: Auto, countables: {{}}, properties: {{}}}}}}",
+            phases
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn allowed_commands_gate_run_phases() {
+        let allowed = ["/usr/bin/python3".to_string(), "sh".to_string()];
+        let allowed = Some(&allowed[..]);
+        assert!(job("[!Run [/usr/bin/python3, train.py]]").validate(allowed).is_ok());
+        assert_eq!(
+            job("[!WorkDir /tmp, !Run [/usr/bin/curl, evil]]").validate(allowed),
+            Err("phase 1 runs /usr/bin/curl, which is not allowed".to_string())
+        );
+        assert!(job("[!Parallel [!Sh 'true', !Run [/bin/nc]]]").validate(allowed).is_err());
+        assert!(job("[!Sh 'true']").validate(allowed).is_ok());
+        assert!(job("[!Sh 'true']").validate(Some(&[])).is_err());
+        assert!(job("[!Run [/usr/bin/curl]]").validate(None).is_ok());
+    }
+
+    #[test]
+    fn env_file_skips_comments_and_unquotes() {
+        let content = "# data set\n\nDATA=/scratch/data\nexport MODE = fast \n\
+                       GREETING=\"hello world\"\nSINGLE='a=b'\nHASH=#kept\nEMPTY=\n";
+        let pairs = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            parse_env_file(content),
+            Ok(pairs(&[
+                ("DATA", "/scratch/data"),
+                ("MODE", "fast"),
+                ("GREETING", "hello world"),
+                ("SINGLE", "a=b"),
+                ("HASH", "#kept"),
+                ("EMPTY", ""),
+            ]))
+        );
+        assert_eq!(parse_env_file("A=1\nnot a pair\n"), Err("line 2 is not KEY=VALUE".to_string()));
+        assert_eq!(parse_env_file("=1"), Err("line 1 has no variable name".to_string()));
+    }
+
+    #[test]
+    fn workdirs_are_created_by_the_executor() {
+        use std::os::unix::fs::MetadataExt;
+        let root = env::temp_dir().join(format!("jd_workdir_{}", std::process::id()));
+        let mut job = job(&format!("[!WorkDir '{}/scratch', !WorkDir run, !Sh 'true']", root.display()));
+        job.create_workdir = true;
+        job.workdir_mode = Some(0o750);
+        job.create_workdirs(Path::new("/")).unwrap();
+        let metadata = fs::metadata(root.join("scratch/run")).unwrap();
+        fs::remove_dir_all(&root).unwrap();
+        assert!(metadata.is_dir());
+        assert_eq!(metadata.uid(), unsafe { libc::geteuid() });
+        assert_eq!(metadata.mode() & 0o777, 0o750);
+    }
+}
@@ -0,0 +1,47 @@
+//! Jobs dispatched from queues to vertexes running them in cgroups. The
+//! `job_dispatcher` binary runs each part, this library lets other programs
+//! build jobs and talk to a dispatcher or vertex.
+//!
+//! ```
+//! use job_dispatcher::jobs_management::{ExecutePhase, JobConfigurationBuilder};
+//! use job_dispatcher::resources_management::{
+//!     Countables, NodesRequirement, Properties, ResourcesRequirement,
+//! };
+//!
+//! let requirement = ResourcesRequirement {
+//!     cpus: NodesRequirement::Use(1),
+//!     mems: NodesRequirement::Auto,
+//!     countables: Countables::default(),
+//!     properties: Properties::default(),
+//!     whole_node: false,
+//! };
+//! let job = JobConfigurationBuilder::new("hello", 1000, 1000, requirement.clone())
+//!     .phase(ExecutePhase::Sh("echo hello".to_string()))
+//!     .build()
+//!     .unwrap();
+//! assert_eq!(job.name, "hello");
+//! assert!(JobConfigurationBuilder::new("", 1000, 1000, requirement).build().is_err());
+//! ```
+
+pub mod auth;
+pub mod client;
+pub mod dispatcher;
+pub mod efficiency;
+pub mod executor;
+pub mod http;
+pub mod jobs_management;
+pub mod metrics;
+#[cfg(feature = "mock-vertex")]
+pub mod mock_vertex;
+pub mod notify;
+pub mod priority_expr;
+pub mod queue_management;
+pub mod queue_store;
+pub mod resources;
+pub mod resources_management;
+pub mod selftest;
+pub mod supervisor;
+pub mod unix;
+pub mod utils;
+pub mod vertex;
+pub mod vertex_client;
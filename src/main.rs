@@ -1,19 +1,10 @@
 use clap::{Parser, Subcommand};
-use client::ClientCommands;
-
-pub mod client;
-pub mod http;
-pub mod unix;
-pub mod utils;
-pub mod auth;
-pub mod vertex_client;
-mod executor;
-mod supervisor;
-mod vertex;
-mod dispatcher;
-pub mod jobs_management;
-pub mod queue_management;
-pub mod resources_management;
+#[cfg(feature = "mock-vertex")]
+use job_dispatcher::mock_vertex;
+use job_dispatcher::{
+    client::{self, ClientCommands},
+    dispatcher, executor, selftest, supervisor, vertex,
+};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -38,9 +29,29 @@ enum SubCommands {
         data: String,
     },
     Client {
+        /// Seconds to wait for the dispatcher to answer, defaults to the
+        /// JOB_DISPATCHER_TIMEOUT environment variable or 5.
+        #[arg(long)]
+        timeout: Option<u64>,
         #[command(subcommand)]
         operation: ClientCommands
-    }
+    },
+    /// Check that this host can run jobs as a vertex, printing a pass or
+    /// fail line per check.
+    Selftest {
+        /// Directory to try creating a log file in.
+        #[arg(long, default_value = "/tmp")]
+        log_dir: String,
+    },
+    /// Serve a vertex with the resources in `resources_path` which only
+    /// pretends to run jobs, each taking `runtime` seconds.
+    #[cfg(feature = "mock-vertex")]
+    MockVertex {
+        resources_path: String,
+        listen: std::net::SocketAddr,
+        #[arg(long, default_value_t = 1)]
+        runtime: u64,
+    },
 }
 
 #[tokio::main]
@@ -48,7 +59,7 @@ async fn main() {
     let Cli { command } = Cli::parse();
     match command {
         SubCommands::Executor { data } => {
-            executor::executor(&data);
+            executor::executor(&data).await;
         }
         SubCommands::Supervisor { task_id, data } => {
             supervisor::supervisor(&task_id, &data).await;
@@ -59,8 +70,23 @@ async fn main() {
         SubCommands::Dispatcher { config_path } => {
             dispatcher::dispatcher(&config_path).await;
         }
-        SubCommands::Client { operation } => {
-            client::client(operation).await;
+        SubCommands::Client { timeout, operation } => {
+            client::client(operation, timeout).await;
+        }
+        SubCommands::Selftest { log_dir } => {
+            selftest::selftest(&log_dir);
+        }
+        #[cfg(feature = "mock-vertex")]
+        SubCommands::MockVertex { resources_path, listen, runtime } => {
+            let resources =
+                serde_yaml::from_str(&std::fs::read_to_string(resources_path).unwrap()).unwrap();
+            let mock = mock_vertex::MockVertex::new(
+                resources,
+                Some(std::time::Duration::from_secs(runtime)),
+            );
+            let (connect, server) = mock.serve(listen).await;
+            println!("{}", serde_yaml::to_string(&connect).unwrap());
+            server.await.unwrap();
         }
     }
 }
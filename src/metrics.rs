@@ -0,0 +1,68 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    submitted: AtomicU64,
+    dispatched: AtomicU64,
+    failed: AtomicU64,
+    deleted: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn submitted(&self) {
+        self.submitted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dispatched(&self) {
+        self.dispatched.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn failed(&self) {
+        self.failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn deleted(&self) {
+        self.deleted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Render the counters and the given gauges in Prometheus text
+    /// exposition format. `queues` holds `(name, queued, running)`.
+    pub fn render(&self, queues: &[(String, usize, usize)], alive_vertexes: usize) -> String {
+        let mut output = String::new();
+        for (name, help, counter) in [
+            ("jobs_submitted_total", "Jobs accepted into a queue.", &self.submitted),
+            ("jobs_dispatched_total", "Jobs handed to a vertex.", &self.dispatched),
+            ("jobs_failed_total", "Jobs failed to be handed to a vertex.", &self.failed),
+            ("jobs_deleted_total", "Jobs deleted from a queue.", &self.deleted),
+        ] {
+            output.push_str(&format!(
+                "# HELP job_dispatcher_{name} {help}\n# TYPE job_dispatcher_{name} counter\njob_dispatcher_{name} {}\n",
+                counter.load(Ordering::Relaxed)
+            ));
+        }
+        output.push_str("# HELP job_dispatcher_queued_jobs Jobs waiting in queue.\n");
+        output.push_str("# TYPE job_dispatcher_queued_jobs gauge\n");
+        for (queue, queued, _) in queues {
+            output.push_str(&format!(
+                "job_dispatcher_queued_jobs{{queue=\"{}\"}} {}\n",
+                queue, queued
+            ));
+        }
+        output.push_str("# HELP job_dispatcher_running_jobs Jobs running on vertexes.\n");
+        output.push_str("# TYPE job_dispatcher_running_jobs gauge\n");
+        for (queue, _, running) in queues {
+            output.push_str(&format!(
+                "job_dispatcher_running_jobs{{queue=\"{}\"}} {}\n",
+                queue, running
+            ));
+        }
+        output.push_str("# HELP job_dispatcher_alive_vertexes Vertexes responding recently.\n");
+        output.push_str("# TYPE job_dispatcher_alive_vertexes gauge\n");
+        output.push_str(&format!("job_dispatcher_alive_vertexes {}\n", alive_vertexes));
+        output
+    }
+}
@@ -0,0 +1,202 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use tokio::task::JoinHandle;
+
+use crate::{
+    jobs_management::JobConfiguration,
+    resources_management::ResourcesProvider,
+    utils::{now_to_secs, RwLockExt},
+    vertex::{PruneQuery, VertexJobStatus},
+    vertex_client::VertexConnect,
+};
+
+/// A vertex answering `/free`, `/jobs` and `/job/:task_id` like a real one,
+/// but only bookkeeping jobs instead of running them. A job finishes after
+/// `runtime`, or never if it is `None`. Credentials are not checked.
+#[derive(Clone)]
+pub struct MockVertex {
+    resources: ResourcesProvider,
+    runtime: Option<Duration>,
+    jobs: Arc<RwLock<HashMap<String, VertexJobStatus>>>,
+}
+
+impl MockVertex {
+    pub fn new(resources: ResourcesProvider, runtime: Option<Duration>) -> Self {
+        Self {
+            resources,
+            runtime,
+            jobs: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    /// Serve on `addr`, port 0 picks a free one. Returns the connection a
+    /// dispatcher can use and the server task.
+    pub async fn serve(self, addr: SocketAddr) -> (VertexConnect, JoinHandle<()>) {
+        let app = Router::new()
+            .route("/free", get(get_free))
+            .route("/jobs", get(get_jobs).delete(prune_jobs))
+            .route("/job/:task_id", post(submit_job).delete(kill_job))
+            .route("/job/:task_id/log", get(get_log))
+            .route("/job/:task_id/suspend", post(suspend_job))
+            .route("/job/:task_id/resume", post(resume_job))
+            .with_state(self);
+        let server = axum::Server::bind(&addr).serve(app.into_make_service());
+        let connect = VertexConnect::new(
+            &format!("http://{}", server.local_addr()),
+            "mock",
+            "mock",
+        );
+        let handle = tokio::spawn(async move {
+            server.await.unwrap();
+        });
+        (connect, handle)
+    }
+
+    fn free(&self) -> ResourcesProvider {
+        let mut free = self.resources.clone();
+        for status in self.jobs.lock_read().values() {
+            if let VertexJobStatus::Running { configuration, .. } = status {
+                free.subtract(&configuration.requirement);
+            }
+        }
+        free
+    }
+}
+
+async fn get_free(State(mock): State<MockVertex>) -> Json<ResourcesProvider> {
+    Json(mock.free())
+}
+
+async fn get_jobs(State(mock): State<MockVertex>) -> Json<HashMap<String, VertexJobStatus>> {
+    Json(mock.jobs.lock_read().clone())
+}
+
+async fn prune_jobs(
+    State(mock): State<MockVertex>,
+    Query(PruneQuery { before }): Query<PruneQuery>,
+) -> Json<usize> {
+    let mut jobs = mock.jobs.lock_write();
+    let count = jobs.len();
+    jobs.retain(|_, status| !status.ended_before(before));
+    Json(count - jobs.len())
+}
+
+/// Jobs don't run, so their stdout stays empty.
+async fn get_log(Path(task_id): Path<String>, State(mock): State<MockVertex>) -> Response {
+    if mock.jobs.lock_read().contains_key(&task_id) {
+        (StatusCode::OK, Vec::new()).into_response()
+    } else {
+        (StatusCode::NOT_FOUND, "No such job").into_response()
+    }
+}
+
+async fn kill_job(Path(task_id): Path<String>, State(mock): State<MockVertex>) -> Response {
+    let mut jobs = mock.jobs.lock_write();
+    let (configuration, started_at) = match jobs.get(&task_id) {
+        Some(VertexJobStatus::Running { configuration, started_at, .. }) => {
+            (configuration.clone(), *started_at)
+        }
+        _ => return (StatusCode::NOT_FOUND, "No such running job").into_response(),
+    };
+    let exit_at = now_to_secs();
+    jobs.insert(
+        task_id,
+        VertexJobStatus::Error {
+            configuration,
+            status_code: 1,
+            error_message: "killed on request".to_string(),
+            exit_at,
+            runtime_secs: exit_at.saturating_sub(started_at),
+            stderr: String::new(),
+        },
+    );
+    (StatusCode::OK, "Killed").into_response()
+}
+
+async fn suspend_job(Path(task_id): Path<String>, State(mock): State<MockVertex>) -> Response {
+    set_frozen(&mock, &task_id, true)
+}
+
+async fn resume_job(Path(task_id): Path<String>, State(mock): State<MockVertex>) -> Response {
+    set_frozen(&mock, &task_id, false)
+}
+
+/// Only the flag changes, there is no process to freeze.
+fn set_frozen(mock: &MockVertex, task_id: &str, frozen: bool) -> Response {
+    match mock.jobs.lock_write().get_mut(task_id) {
+        Some(VertexJobStatus::Running { frozen: current, .. }) => {
+            *current = frozen;
+            (StatusCode::OK, if frozen { "Suspended" } else { "Resumed" }).into_response()
+        }
+        Some(_) => (StatusCode::CONFLICT, "Job already ended").into_response(),
+        None => (StatusCode::NOT_FOUND, "No such job").into_response(),
+    }
+}
+
+async fn submit_job(
+    Path(task_id): Path<String>,
+    State(mock): State<MockVertex>,
+    Json(job): Json<JobConfiguration>,
+) -> Response {
+    if matches!(mock.jobs.lock_read().get(&task_id), Some(VertexJobStatus::Running { .. })) {
+        return (StatusCode::OK, task_id).into_response();
+    }
+    if !mock.free().acceptable(&job.requirement) {
+        return (StatusCode::SERVICE_UNAVAILABLE, "Resources not enough").into_response();
+    }
+    let running = mock
+        .jobs
+        .lock_read()
+        .values()
+        .any(|status| matches!(status, VertexJobStatus::Running { .. }));
+    if job.requirement.whole_node && running {
+        return (StatusCode::SERVICE_UNAVAILABLE, "Node not idle").into_response();
+    }
+    let started_at = now_to_secs();
+    mock.jobs.lock_write().insert(
+        task_id.clone(),
+        VertexJobStatus::Running {
+            configuration: job.clone(),
+            started_at,
+            cpuset: vec![],
+            memset: vec![],
+            pid: 0,
+            frozen: false,
+        },
+    );
+    if let Some(runtime) = mock.runtime {
+        let jobs = mock.jobs.clone();
+        let finished_id = task_id.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(runtime).await;
+            let exit_at = now_to_secs();
+            let mut jobs = jobs.lock_write();
+            if !matches!(jobs.get(&finished_id), Some(VertexJobStatus::Running { .. })) {
+                return;
+            }
+            jobs.insert(
+                finished_id,
+                VertexJobStatus::Finished {
+                    configuration: job,
+                    exit_code: 0,
+                    exit_at,
+                    runtime_secs: exit_at.saturating_sub(started_at),
+                    usage: Default::default(),
+                },
+            );
+        });
+    }
+    (StatusCode::OK, task_id).into_response()
+}
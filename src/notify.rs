@@ -0,0 +1,80 @@
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+
+/// Where to deliver a notification: a URL receiving the payload as a JSON
+/// POST, or a shell command template whose `{key}` placeholders are replaced
+/// by the payload fields.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum NotifyConfig {
+    Url(String),
+    Command(String),
+}
+
+impl NotifyConfig {
+    /// Deliver `payload` in the background, failures are only logged.
+    pub fn fire<T: Serialize>(&self, payload: &T) {
+        let config = self.clone();
+        let payload = serde_json::to_value(payload).unwrap();
+        tokio::spawn(async move {
+            if let Err(err) = config.deliver(&payload).await {
+                println!("Failed to notify: {}", err);
+            }
+        });
+    }
+
+    async fn deliver(&self, payload: &serde_json::Value) -> Result<(), String> {
+        match self {
+            Self::Url(url) => {
+                let resp = reqwest::Client::new()
+                    .post(url)
+                    .json(payload)
+                    .send()
+                    .await
+                    .map_err(|e| e.to_string())?;
+                if resp.status().is_success() {
+                    Ok(())
+                } else {
+                    Err(format!("{} responded {}", url, resp.status()))
+                }
+            }
+            Self::Command(template) => {
+                let mut command = template.clone();
+                if let Some(fields) = payload.as_object() {
+                    for (key, value) in fields {
+                        let value = match value {
+                            serde_json::Value::String(value) => value.clone(),
+                            serde_json::Value::Null => String::new(),
+                            value => value.to_string(),
+                        };
+                        command = command.replace(&format!("{{{}}}", key), &value);
+                    }
+                }
+                let status = Command::new("sh")
+                    .arg("-c")
+                    .arg(&command)
+                    .status()
+                    .await
+                    .map_err(|e| e.to_string())?;
+                if status.success() {
+                    Ok(())
+                } else {
+                    Err(format!("{} exited with {}", command, status))
+                }
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FinishNotification {
+    pub task_id: String,
+    pub state: String,
+    pub exit_code: Option<i32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StartNotification {
+    pub task_id: String,
+    pub vertex: String,
+    pub cpuset: Vec<usize>,
+}
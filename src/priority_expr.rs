@@ -0,0 +1,171 @@
+//! Priorities written as expressions in a queue configuration, like
+//! `waited / 60 + countable("gpu") * 10 + if(property("partition") == "fast", 100, 0)`.
+//!
+//! Numbers are floats, so `waited / 60` isn't truncated. The variables are
+//! `waited` (seconds), `until_deadline` (seconds before `start_before`, -1
+//! without one), `cpus` and `mems` (node counts, 0 for `Auto`) and
+//! `whole_node`. `countable(name)` is 0 and `property(name)` is "" when the
+//! job doesn't set them.
+
+use evalexpr::{build_operator_tree, Context, EvalexprError, EvalexprResult, Node, Value};
+use serde::{Deserialize, Serialize};
+
+use crate::resources_management::{
+    Countables, NodesRequirement, Properties, ResourcesRequirement,
+};
+
+/// A parsed priority expression, written and read back as its source.
+#[derive(Debug, Clone)]
+pub struct PriorityExpr {
+    source: String,
+    node: Node,
+}
+
+impl PriorityExpr {
+    /// Parse `source`, checking that it gives a number for a job asking for
+    /// nothing, which catches unknown variables and functions.
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let node = build_operator_tree(source).map_err(|err| format!("{}: {}", source, err))?;
+        let expr = Self {
+            source: source.to_string(),
+            node,
+        };
+        let requirement = ResourcesRequirement {
+            cpus: NodesRequirement::Auto,
+            mems: NodesRequirement::Auto,
+            countables: Countables::default(),
+            properties: Properties::default(),
+            whole_node: false,
+        };
+        expr.evaluate(&requirement, 0, None)
+            .map_err(|err| format!("{}: {}", source, err))?;
+        Ok(expr)
+    }
+
+    /// Priority of a job with `requirement`.
+    pub fn evaluate(
+        &self,
+        requirement: &ResourcesRequirement,
+        waited: u64,
+        until_deadline: Option<u64>,
+    ) -> Result<f64, String> {
+        let context = JobContext {
+            requirement,
+            waited: Value::Float(waited as f64),
+            until_deadline: Value::Float(until_deadline.map(|secs| secs as f64).unwrap_or(-1.)),
+            cpus: Value::Float(node_count(&requirement.cpus)),
+            mems: Value::Float(node_count(&requirement.mems)),
+            whole_node: Value::Boolean(requirement.whole_node),
+        };
+        self.node
+            .eval_number_with_context(&context)
+            .map_err(|err| err.to_string())
+    }
+}
+
+impl Serialize for PriorityExpr {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.source)
+    }
+}
+
+impl<'de> Deserialize<'de> for PriorityExpr {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Self::parse(&String::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+    }
+}
+
+/// The variables and functions of one job, borrowing its requirement.
+struct JobContext<'a> {
+    requirement: &'a ResourcesRequirement,
+    waited: Value,
+    until_deadline: Value,
+    cpus: Value,
+    mems: Value,
+    whole_node: Value,
+}
+
+impl Context for JobContext<'_> {
+    fn get_value(&self, identifier: &str) -> Option<&Value> {
+        match identifier {
+            "waited" => Some(&self.waited),
+            "until_deadline" => Some(&self.until_deadline),
+            "cpus" => Some(&self.cpus),
+            "mems" => Some(&self.mems),
+            "whole_node" => Some(&self.whole_node),
+            _ => None,
+        }
+    }
+
+    fn call_function(&self, identifier: &str, argument: &Value) -> EvalexprResult<Value> {
+        match identifier {
+            "countable" => Ok(Value::Float(
+                self.requirement.countables.get(&argument.as_string()?) as f64,
+            )),
+            "property" => Ok(Value::String(
+                self.requirement
+                    .properties
+                    .get(&argument.as_string()?)
+                    .cloned()
+                    .unwrap_or_default(),
+            )),
+            _ => Err(EvalexprError::FunctionIdentifierNotFound(identifier.to_string())),
+        }
+    }
+
+    fn are_builtin_functions_disabled(&self) -> bool {
+        false
+    }
+
+    fn set_builtin_functions_disabled(&mut self, _disabled: bool) -> EvalexprResult<()> {
+        Err(EvalexprError::CustomMessage(
+            "builtin functions can't be disabled".to_string(),
+        ))
+    }
+}
+
+fn node_count(nodes: &NodesRequirement) -> f64 {
+    match nodes {
+        NodesRequirement::Select(set) => set.len() as f64,
+        NodesRequirement::Use(size) => *size as f64,
+        NodesRequirement::Auto => 0.,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn requirement(yaml: &str) -> ResourcesRequirement {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn formulas() {
+        let gpu_job = requirement(
+            "{cpus: !Use 4, mems: Auto, countables: {gpu: 2}, properties: {p: fast}}",
+        );
+        let expr = PriorityExpr::parse(
+            r#"countable("gpu") * 10 + cpus + if(property("p") == "fast", 100, 0)"#,
+        )
+        .unwrap();
+        assert_eq!(expr.evaluate(&gpu_job, 0, None), Ok(124.));
+        let expr = PriorityExpr::parse("waited / 60 + if(until_deadline < 0, 0, 5)").unwrap();
+        assert_eq!(expr.evaluate(&gpu_job, 90, None), Ok(1.5));
+        assert_eq!(expr.evaluate(&gpu_job, 90, Some(10)), Ok(6.5));
+    }
+
+    #[test]
+    fn rejects_unknown_names() {
+        assert!(PriorityExpr::parse("gpus * 2").is_err());
+        assert!(PriorityExpr::parse(r#"countables("gpu")"#).is_err());
+        assert!(PriorityExpr::parse(r#"property("p")"#).is_err());
+        assert!(serde_yaml::from_str::<PriorityExpr>("waited +").is_err());
+    }
+
+    #[test]
+    fn round_trips_as_its_source() {
+        let expr: PriorityExpr = serde_yaml::from_str("waited * 2").unwrap();
+        assert_eq!(serde_json::to_string(&expr).unwrap(), r#""waited * 2""#);
+    }
+}
@@ -1,75 +1,204 @@
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
 use uuid::Uuid;
 
 use crate::{
     jobs_management::JobConfiguration,
-    resources_management::{NodesRequirement, Properties, ResourcesProvider, ResourcesRequirement},
+    priority_expr::PriorityExpr,
+    resources_management::{NodesRequirement, Properties, ResourcesRequirement},
     utils::now_to_secs,
 };
 
 pub struct QueueGroup(HashMap<String, Queue>);
 
 impl QueueGroup {
-    pub fn new(queues: HashMap<String, Queue>) -> Self {
+    pub fn new(mut queues: HashMap<String, Queue>) -> Self {
+        for queue in queues.values_mut() {
+            queue.refresh_jobs();
+        }
         Self(queues)
     }
 
-    pub fn add_to_queue(&mut self, queue: &str, job: &JobConfiguration) -> Result<String, ()> {
+    pub fn queues(&self) -> &HashMap<String, Queue> {
+        &self.0
+    }
+
+    pub fn add_to_queue(&mut self, queue: &str, job: &JobConfiguration) -> Result<String, QueueError> {
         if let Some(queue) = self.0.get_mut(queue) {
-            queue.add_to_queue(job)
+            queue.add_to_queue(job).map_err(QueueError::Rejected)
         } else {
-            Err(())
+            Err(QueueError::NoSuchQueue)
+        }
+    }
+
+    /// Replace the requirement of a queued job owned by `uid` and restart its
+    /// wait.
+    pub fn modify_job(
+        &mut self,
+        task_id: &str,
+        uid: u32,
+        requirement: &ResourcesRequirement,
+    ) -> Result<(), QueueError> {
+        for (_, queue) in self.0.iter_mut() {
+            if let Some((_, job)) = queue.running.get(task_id) {
+                return if job.uid == uid || uid == 0 {
+                    Err(QueueError::Running)
+                } else {
+                    Err(QueueError::PermissionDenied)
+                };
+            }
+            if let Some(index) = queue.jobs.iter().position(|(id, _, _)| id == task_id) {
+                return queue.modify_job(index, uid, requirement);
+            }
+        }
+        Err(QueueError::NotFound)
+    }
+
+    /// Move a queued job owned by `uid` to the queue `target`, keeping its
+    /// task id and enqueue time.
+    pub fn requeue(&mut self, task_id: &str, target: &str, uid: u32) -> Result<(), QueueError> {
+        let found = self.0.iter().find_map(|(name, queue)| {
+            if let Some((_, job)) = queue.running.get(task_id) {
+                Some((name.clone(), job.clone(), None))
+            } else {
+                queue
+                    .jobs
+                    .iter()
+                    .find(|(id, _, _)| id == task_id)
+                    .map(|(_, job, waited)| {
+                        let enqueued = queue.enqueued.get(task_id).or(waited.as_ref()).cloned();
+                        (name.clone(), job.clone(), Some(enqueued.unwrap_or(now_to_secs())))
+                    })
+            }
+        });
+        let (source, job, enqueued) = found.ok_or(QueueError::NotFound)?;
+        let attempts = self.0[&source].attempts.get(task_id).cloned();
+        if job.uid != uid && uid != 0 {
+            return Err(QueueError::PermissionDenied);
+        }
+        let enqueued = enqueued.ok_or(QueueError::Running)?;
+        if source == target {
+            return Ok(());
         }
+        // judged by the target on what the job asked for, not what the
+        // source queue added
+        let mut job = job;
+        self.0[&source].configuration.strip_properties(&mut job);
+        self.0[&source].configuration.strip_time_limit(&mut job);
+        let queue = self.0.get_mut(target).ok_or(QueueError::NoSuchQueue)?;
+        if let Some(reason) = queue.rejection_reason(&job) {
+            return Err(QueueError::Rejected(reason));
+        }
+        queue.configuration.inject_properties(&mut job);
+        job.cpu_weight = queue.configuration.cpu_weight;
+        queue.configuration.limit_time(&mut job);
+        queue.push_job(task_id, job, enqueued);
+        if let Some(attempts) = attempts {
+            queue.attempts.insert(task_id.to_string(), attempts);
+        }
+        if let Some(source) = self.0.get_mut(&source) {
+            source.remove_from_queue(task_id);
+            source.refresh_jobs();
+        }
+        Ok(())
+    }
+
+    /// Add the job to the first queue accepting it. Queues are tried by
+    /// descending `preference`, then by name. On failure every queue's
+    /// rejection reason is returned.
+    pub fn add_to_any_queue(
+        &mut self,
+        job: &JobConfiguration,
+    ) -> Result<(String, String), Vec<(String, String)>> {
+        let mut names = self
+            .0
+            .iter()
+            .map(|(name, queue)| (queue.configuration.preference, name.clone()))
+            .collect::<Vec<_>>();
+        names.sort_by(|(a, a_name), (b, b_name)| b.cmp(a).then(a_name.cmp(b_name)));
+        let mut reasons = Vec::new();
+        for (_, name) in names {
+            let queue = self.0.get_mut(&name).unwrap();
+            match queue.add_to_queue(job) {
+                Ok(task_id) => return Ok((name, task_id)),
+                Err(reason) => reasons.push((name, reason)),
+            }
+        }
+        Err(reasons)
     }
 
-    pub fn remove_job(&mut self, task_id: &str, uid: u32) -> Option<Result<(), ()>> {
+    pub fn remove_job(&mut self, task_id: &str, uid: u32) -> Result<(), QueueError> {
         for (_, queue) in self.0.iter_mut() {
             if let Some(index) = queue.jobs.iter().position(|(id, _, _)| id == task_id) {
-                return Some(if queue.jobs[index].1.uid == uid || uid == 0 {
-                    queue.jobs.remove(index);
+                return if queue.jobs[index].1.uid == uid || uid == 0 {
+                    queue.remove_from_queue(task_id);
                     Ok(())
                 } else {
-                    Err(())
-                });
+                    Err(QueueError::PermissionDenied)
+                };
             }
         }
-        None
+        Err(QueueError::NotFound)
     }
 
-    pub fn try_take_job(
-        &self,
-        provider: &ResourcesProvider,
-        exlusive_mem: bool,
-    ) -> Option<(String, JobConfiguration, String)> {
-        let Self(queues) = &self;
-        let mut submitables = queues
+    /// Remove the queued jobs labelled `key: value`, only those owned by
+    /// `uid` unless it is root, and return their task ids.
+    pub fn remove_by_label(&mut self, key: &str, value: &str, uid: u32) -> Vec<String> {
+        let mut removed = Vec::new();
+        for queue in self.0.values_mut() {
+            let matching = queue
+                .jobs
+                .iter()
+                .filter(|(_, job, _)| job.has_label(key, value) && (job.uid == uid || uid == 0))
+                .map(|(id, _, _)| id.clone())
+                .collect::<Vec<_>>();
+            for task_id in matching {
+                queue.remove_from_queue(&task_id);
+                removed.push(task_id);
+            }
+        }
+        removed.sort();
+        removed
+    }
+
+    /// Change a queued job in place.
+    pub fn update_queued<F: FnOnce(&mut JobConfiguration)>(&mut self, task_id: &str, update: F) {
+        if let Some((_, job, _)) = self
+            .0
+            .values_mut()
+            .flat_map(|queue| queue.jobs.iter_mut())
+            .find(|(id, _, _)| id == task_id)
+        {
+            update(job)
+        }
+    }
+
+    /// A queued job with the reason its queue holds it back, if any.
+    pub fn queued_job(&self, task_id: &str) -> Option<(JobConfiguration, Option<String>)> {
+        self.0.values().find_map(|queue| queue.queued_job(task_id))
+    }
+
+    /// Every job its queue would dispatch now, in no particular order. The
+    /// priorities are normalized by their queue to compare across queues.
+    pub fn submitables(&self) -> Vec<Submitable<'_>> {
+        self.0
             .iter()
-            .map(|(name, queue)| (name, queue.jobs_submitable()))
-            .map(|(name, submitables)| {
-                submitables
+            .flat_map(|(name, queue)| {
+                queue
+                    .jobs_submitable()
                     .into_iter()
-                    .map(|(task_id, job_conf, _, priority)| {
-                        (task_id, job_conf, priority, name.clone())
+                    .map(move |(task_id, job, queued_at, priority)| Submitable {
+                        task_id,
+                        job,
+                        queue: name,
+                        queued_at: *queued_at,
+                        priority: queue.configuration.normalize_priority(priority),
                     })
             })
-            .flatten()
-            .collect::<Vec<_>>();
-        submitables.sort_by(|(_, _, a, _), (_, _, b, _)| b.partial_cmp(a).unwrap());
-        let available_job = submitables.into_iter().find(|(_, job, _, _)| {
-            if exlusive_mem {
-                provider.execlusive_mem_acceptable(&job.requirement)
-            } else {
-                provider.acceptable(&job.requirement)
-            }
-        });
-        if let Some((id, job, _, queue)) = available_job {
-            let id = id.clone();
-            let job = job.clone();
-            Some((id.clone(), job.clone(), queue))
-        } else {
-            None
-        }
+            .collect()
     }
 
     pub fn truly_take_job(
@@ -78,32 +207,203 @@ impl QueueGroup {
         send_id: &str,
         received_id: &str,
         job: &JobConfiguration,
-    ) -> Option<()> {
-        if let Some(queue) = self.0.get_mut(queue) {
-            if let Some(_) = queue.remove_from_queue(send_id) {
-                queue.add_to_running(received_id, job);
-                queue.refresh_jobs();
-                Some(())
-            } else {
-                None
-            }
-        } else {
-            None
+        vertex: &str,
+    ) -> Result<(), QueueError> {
+        let queue = self.0.get_mut(queue).ok_or(QueueError::NoSuchQueue)?;
+        let attempts = queue.attempts.get(send_id).cloned();
+        queue.remove_from_queue(send_id).ok_or(QueueError::NotFound)?;
+        queue.add_to_running(received_id, job, vertex);
+        if let Some(attempts) = attempts {
+            queue.attempts.insert(received_id.to_string(), attempts);
         }
+        queue.refresh_jobs();
+        Ok(())
+    }
+
+    /// Forget the jobs on `vertex` which are no longer running there and
+    /// return them.
+    pub fn refresh_running(
+        &mut self,
+        vertex: &str,
+        running_ids: &HashSet<String>,
+    ) -> Vec<(String, JobConfiguration)> {
+        self.0
+            .iter_mut()
+            .flat_map(|(_, v)| v.refresh_running(vertex, running_ids))
+            .collect()
+    }
+
+    /// Every queued and running job, sorted by queue name.
+    pub fn summaries(&self) -> Vec<JobSummary> {
+        let mut names = self.0.keys().collect::<Vec<_>>();
+        names.sort();
+        names
+            .into_iter()
+            .flat_map(|name| self.0[name].summaries(name))
+            .collect()
+    }
+
+    /// Like `summaries`, only the jobs labelled `key: value`.
+    pub fn summaries_by_label(&self, key: &str, value: &str) -> Vec<JobSummary> {
+        self.summaries()
+            .into_iter()
+            .filter(|summary| summary.labels.get(key).map(String::as_str) == Some(value))
+            .collect()
     }
 
-    pub fn refresh_running(&mut self, running_ids: &HashSet<String>) {
-        for (_, v) in self.0.iter_mut() {
-            v.refresh_running(running_ids)
+    /// Limits of the queues the given identity is allowed to submit to,
+    /// sorted by queue name.
+    pub fn permitted(&self, uid: u32, gid: u32) -> Vec<QueueLimits> {
+        let mut permitted = self
+            .0
+            .iter()
+            .filter(|(_, queue)| queue.configuration.allows(uid, gid))
+            .map(|(name, queue)| QueueLimits {
+                name: name.clone(),
+                global_limit: queue.configuration.global_limit.clone(),
+                user_limit: queue.configuration.user_limit.clone(),
+                group_limit: queue.configuration.group_limit.clone(),
+            })
+            .collect::<Vec<_>>();
+        permitted.sort_by(|a, b| a.name.cmp(&b.name));
+        permitted
+    }
+
+    /// Job counts and limits of every queue, sorted by queue name.
+    pub fn overview(&self) -> Vec<QueueOverview> {
+        let mut overview = self
+            .0
+            .iter()
+            .map(|(name, queue)| QueueOverview {
+                limits: QueueLimits {
+                    name: name.clone(),
+                    global_limit: queue.configuration.global_limit.clone(),
+                    user_limit: queue.configuration.user_limit.clone(),
+                    group_limit: queue.configuration.group_limit.clone(),
+                },
+                queued: queue.jobs.len(),
+                running: queue.running.len(),
+                held: queue.jobs.len() - queue.jobs_submitable().len(),
+                paused: queue.paused,
+            })
+            .collect::<Vec<_>>();
+        overview.sort_by(|a, b| a.limits.name.cmp(&b.limits.name));
+        overview
+    }
+
+    /// Queued and running job counts of every queue, sorted by queue name.
+    pub fn depths(&self) -> Vec<(String, usize, usize)> {
+        let mut depths = self
+            .0
+            .iter()
+            .map(|(name, queue)| (name.clone(), queue.jobs.len(), queue.running.len()))
+            .collect::<Vec<_>>();
+        depths.sort_by(|(a, _, _), (b, _, _)| a.cmp(b));
+        depths
+    }
+
+    /// Pause or resume dispatching from `queue`. `None` if there's no such
+    /// queue.
+    pub fn set_paused(&mut self, queue: &str, paused: bool) -> Option<()> {
+        self.0.get_mut(queue).map(|queue| queue.paused = paused)
+    }
+
+    /// Names of the paused queues, sorted.
+    pub fn paused(&self) -> Vec<String> {
+        let mut paused = self
+            .0
+            .iter()
+            .filter(|(_, queue)| queue.paused)
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+        paused.sort();
+        paused
+    }
+
+    /// Cancel the jobs waiting too long in their queue, see `max_wait_secs`.
+    pub fn reap_stale(&mut self, now: u64) -> Vec<String> {
+        self.0
+            .values_mut()
+            .flat_map(|queue| queue.reap_stale(now))
+            .collect()
+    }
+
+    /// Cancel the queued jobs whose `start_before` passed at `now`.
+    pub fn miss_deadlines(&mut self, now: u64) -> Vec<String> {
+        self.0
+            .values_mut()
+            .flat_map(|queue| queue.miss_deadlines(now))
+            .collect()
+    }
+
+    /// Running jobs past the `max_runtime_secs` of their queue at `now`,
+    /// with the vertex running them.
+    pub fn overruns(&self, now: u64) -> Vec<(String, String)> {
+        self.0.values().flat_map(|queue| queue.overruns(now)).collect()
+    }
+
+    /// Stop checking the runtime of a job once it was told to stop.
+    pub fn forget_start(&mut self, task_id: &str) {
+        for queue in self.0.values_mut() {
+            queue.started.remove(task_id);
         }
     }
+
+    /// Vertexes with jobs recorded as running on them.
+    pub fn running_vertexes(&self) -> HashSet<String> {
+        self.0
+            .values()
+            .flat_map(|queue| queue.running.values().map(|(vertex, _)| vertex.clone()))
+            .collect()
+    }
+
+    /// Move every job recorded as running on `vertex` back into its origin
+    /// queue. Returns the new task ids of the requeued jobs.
+    pub fn requeue_vertex(&mut self, vertex: &str) -> Vec<String> {
+        self.requeue_unknown(vertex, &HashSet::new())
+    }
+
+    /// Like `requeue_vertex`, sparing the jobs in `known`, which the vertex
+    /// still has running or finished.
+    pub fn requeue_unknown(&mut self, vertex: &str, known: &HashSet<String>) -> Vec<String> {
+        self.0
+            .iter_mut()
+            .flat_map(|(_, queue)| queue.requeue_unknown(vertex, known))
+            .collect()
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Queue {
     configuration: QueueConfiguration,
     jobs: Vec<(String, JobConfiguration, Option<u64>)>,
-    running: HashMap<String, JobConfiguration>,
+    running: HashMap<String, (String, JobConfiguration)>,
+    #[serde(default)]
+    requeued: HashSet<String>,
+    /// Runs each job lost on a dead vertex before, keyed by its current
+    /// task id, see `requeue_vertex`.
+    #[serde(default)]
+    attempts: HashMap<String, u32>,
+    /// When each running job was handed to its vertex.
+    #[serde(default)]
+    started: HashMap<String, u64>,
+    /// Submission time of each queued job. Priority ages a job from this
+    /// moment rather than from when it became queueable, so it survives
+    /// restarts with the persisted snapshot.
+    #[serde(default)]
+    enqueued: HashMap<String, u64>,
+    /// Jobs removed from the queue without running, with the reason.
+    #[serde(default)]
+    cancelled: HashMap<String, (JobConfiguration, String)>,
+    /// When each cancelled job was cancelled, see `prune_cancelled`.
+    #[serde(default)]
+    cancelled_at: HashMap<String, u64>,
+    /// Last number used for a short task id, see `id_prefix`.
+    #[serde(default)]
+    last_id: u64,
+    /// A paused queue accepts jobs but doesn't dispatch them.
+    #[serde(default)]
+    paused: bool,
 }
 
 impl Queue {
@@ -112,11 +412,38 @@ impl Queue {
             configuration: configuration.clone(),
             jobs: Vec::new(),
             running: HashMap::new(),
+            requeued: HashSet::new(),
+            attempts: HashMap::new(),
+            started: HashMap::new(),
+            enqueued: HashMap::new(),
+            cancelled: HashMap::new(),
+            cancelled_at: HashMap::new(),
+            last_id: 0,
+            paused: false,
+        }
+    }
+
+    /// A task id unused in this queue, `<id_prefix>-<n>` if the queue has a
+    /// prefix and a random UUID otherwise.
+    fn new_task_id(&mut self) -> String {
+        if let Some(prefix) = &self.configuration.id_prefix {
+            loop {
+                self.last_id += 1;
+                let task_id = format!("{}-{}", prefix, self.last_id);
+                let used = self.jobs.iter().any(|(id, _, _)| id == &task_id)
+                    || self.running.contains_key(&task_id)
+                    || self.cancelled.contains_key(&task_id);
+                if !used {
+                    return task_id;
+                }
+            }
+        } else {
+            Uuid::new_v4().to_string()
         }
     }
 
     pub fn jobs_submitable(&self) -> Vec<(&String, &JobConfiguration, &u64, f64)> {
-        if self.running_full() {
+        if self.paused || self.running_full() {
             Vec::new()
         } else {
             self.jobs_in_queue()
@@ -129,59 +456,314 @@ impl Queue {
     }
 
     pub fn jobs_in_queue(&self) -> Vec<(&String, &JobConfiguration, &u64, f64)> {
+        let now = now_to_secs();
         self.jobs
             .iter()
             .filter_map(|(id, job, waited)| {
-                if let Some(waited) = waited {
-                    Some((
+                waited.as_ref().map(|waited| {
+                    let enqueued = self.enqueued.get(id).unwrap_or(waited);
+                    (
                         id,
                         job,
                         waited,
-                        self.configuration.priority(&job.requirement, *waited),
-                    ))
-                } else {
-                    None
-                }
+                        self.configuration.priority(
+                            &job.requirement,
+                            now.saturating_sub(*enqueued),
+                            job.start_before.map(|deadline| deadline.saturating_sub(now)),
+                        ),
+                    )
+                })
             })
             .collect::<Vec<_>>()
     }
 
-    pub fn add_to_queue(&mut self, job: &JobConfiguration) -> Result<String, ()> {
-        if self.configuration.can_be_added(job) {
-            let task_id = Uuid::new_v4();
+    /// Record a new queued job, waiting since `enqueued`.
+    fn push_job(&mut self, task_id: &str, job: JobConfiguration, enqueued: u64) {
+        self.jobs.push((task_id.to_string(), job, None));
+        self.enqueued.insert(task_id.to_string(), enqueued);
+        self.refresh_jobs();
+    }
+
+    /// Queue the job, or tell why it can't be queued here.
+    pub fn add_to_queue(&mut self, job: &JobConfiguration) -> Result<String, String> {
+        if let Some(reason) = self.rejection_reason(job) {
+            Err(reason)
+        } else {
+            let task_id = self.new_task_id();
             let mut job_configuration = job.clone();
-            job_configuration
-                .requirement
-                .properties
-                .extend(&self.configuration.properties);
-            self.jobs.push((task_id.to_string(), job.clone(), None));
-            Ok(task_id.to_string())
+            self.configuration.inject_properties(&mut job_configuration);
+            job_configuration.cpu_weight = self.configuration.cpu_weight;
+            self.configuration.limit_time(&mut job_configuration);
+            self.push_job(&task_id, job_configuration, now_to_secs());
+            Ok(task_id)
+        }
+    }
+
+    fn rejection_reason(&self, job: &JobConfiguration) -> Option<String> {
+        if let Some(reason) = self.configuration.rejection_reason(job) {
+            Some(reason)
+        } else if self.configuration.reject_duplicate_names && self.has_name(job.uid, &job.name) {
+            Some(format!("user {} already has a job named {}", job.uid, job.name))
         } else {
-            Err(())
+            None
         }
     }
 
+    /// Whether `uid` owns a queued or running job named `name`.
+    fn has_name(&self, uid: u32, name: &str) -> bool {
+        self.jobs
+            .iter()
+            .map(|(_, job, _)| job)
+            .chain(self.running.values().map(|(_, job)| job))
+            .any(|job| job.uid == uid && job.name == name)
+    }
+
     pub fn remove_from_queue(&mut self, task_id: &str) -> Option<()> {
         let index = self.jobs.iter().position(|(id, _, _)| id == task_id);
         if let Some(index) = index {
             self.jobs.remove(index);
+            self.requeued.remove(task_id);
+            self.attempts.remove(task_id);
+            self.enqueued.remove(task_id);
             Some(())
         } else {
             None
         }
     }
 
-    pub fn add_to_running(&mut self, task_id: &str, job: &JobConfiguration) {
-        self.running.insert(task_id.to_string(), job.clone());
+    fn modify_job(
+        &mut self,
+        index: usize,
+        uid: u32,
+        requirement: &ResourcesRequirement,
+    ) -> Result<(), QueueError> {
+        let (_, job, _) = &self.jobs[index];
+        if job.uid != uid && uid != 0 {
+            return Err(QueueError::PermissionDenied);
+        }
+        let mut job = job.clone();
+        job.requirement = requirement.clone();
+        if let Some(reason) = self.configuration.rejection_reason(&job) {
+            return Err(QueueError::Rejected(reason));
+        }
+        self.configuration.inject_properties(&mut job);
+        self.configuration.limit_time(&mut job);
+        self.jobs[index].1 = job;
+        self.jobs[index].2 = None;
+        self.enqueued.insert(self.jobs[index].0.clone(), now_to_secs());
+        self.refresh_jobs();
+        Ok(())
+    }
+
+    pub fn add_to_running(&mut self, task_id: &str, job: &JobConfiguration, vertex: &str) {
+        self.running
+            .insert(task_id.to_string(), (vertex.to_string(), job.clone()));
+        self.started.insert(task_id.to_string(), now_to_secs());
     }
 
-    pub fn refresh_running(&mut self, running_ids: &HashSet<String>) {
-        self.running = self
+    pub fn refresh_running(
+        &mut self,
+        vertex: &str,
+        running_ids: &HashSet<String>,
+    ) -> Vec<(String, JobConfiguration)> {
+        let stopped = self
             .running
-            .clone()
+            .iter()
+            .filter(|(id, (on_vertex, _))| on_vertex == vertex && !running_ids.contains(*id))
+            .map(|(id, _)| id.clone())
+            .collect::<Vec<_>>();
+        stopped
+            .into_iter()
+            .filter_map(|id| {
+                self.attempts.remove(&id);
+                self.started.remove(&id);
+                self.running.remove(&id).map(|(_, job)| (id, job))
+            })
+            .collect()
+    }
+
+    pub fn requeue_unknown(&mut self, vertex: &str, known: &HashSet<String>) -> Vec<String> {
+        let lost = self
+            .running
+            .iter()
+            .filter(|(id, (on_vertex, _))| on_vertex == vertex && !known.contains(*id))
+            .map(|(id, _)| id.clone())
+            .collect::<Vec<_>>();
+        let mut requeued = Vec::new();
+        for id in lost {
+            if let Some((_, job)) = self.running.remove(&id) {
+                self.started.remove(&id);
+                let task_id = self.new_task_id();
+                let attempts = self.attempts.remove(&id).unwrap_or(0) + 1;
+                self.push_job(&task_id, job, now_to_secs());
+                self.requeued.insert(task_id.clone());
+                self.attempts.insert(task_id.clone(), attempts);
+                requeued.push(task_id);
+            }
+        }
+        requeued
+    }
+
+    fn queued_job(&self, task_id: &str) -> Option<(JobConfiguration, Option<String>)> {
+        let (_, job, waited) = self.jobs.iter().find(|(id, _, _)| id == task_id)?;
+        let reason = if waited.is_none() {
+            Some("queue limit reached")
+        } else if self.running_full() {
+            Some("queue running limit reached")
+        } else if self.running_full_user(job.uid) {
+            Some("user running limit reached")
+        } else if self.running_full_group(job.gid) {
+            Some("group running limit reached")
+        } else {
+            None
+        };
+        Some((job.clone(), reason.map(str::to_string)))
+    }
+
+    pub fn is_requeued(&self, task_id: &str) -> bool {
+        self.requeued.contains(task_id)
+    }
+
+    /// Which run of the job `task_id` is, counting from 1.
+    fn attempt(&self, task_id: &str) -> u32 {
+        self.attempts.get(task_id).cloned().unwrap_or(0) + 1
+    }
+
+    pub fn summaries(&self, queue: &str) -> Vec<JobSummary> {
+        let queued = self.jobs.iter().map(|(task_id, job, _)| JobSummary {
+            task_id: task_id.clone(),
+            queue: queue.to_string(),
+            name: job.name.clone(),
+            uid: job.uid,
+            gid: job.gid,
+            vertex: None,
+            requeued: self.is_requeued(task_id),
+            attempt: self.attempt(task_id),
+            labels: job.labels.clone(),
+            cancelled: None,
+        });
+        let running = self.running.iter().map(|(task_id, (vertex, job))| JobSummary {
+            task_id: task_id.clone(),
+            queue: queue.to_string(),
+            name: job.name.clone(),
+            uid: job.uid,
+            gid: job.gid,
+            vertex: Some(vertex.clone()),
+            requeued: false,
+            attempt: self.attempt(task_id),
+            labels: job.labels.clone(),
+            cancelled: None,
+        });
+        let cancelled = self.cancelled.iter().map(|(task_id, (job, reason))| JobSummary {
+            task_id: task_id.clone(),
+            queue: queue.to_string(),
+            name: job.name.clone(),
+            uid: job.uid,
+            gid: job.gid,
+            vertex: None,
+            requeued: false,
+            attempt: 1,
+            labels: job.labels.clone(),
+            cancelled: Some(reason.clone()),
+        });
+        queued.chain(running).chain(cancelled).collect()
+    }
+
+    fn overruns(&self, now: u64) -> Vec<(String, String)> {
+        let max_runtime = if let Some(max_runtime) = self.configuration.max_runtime_secs {
+            max_runtime
+        } else {
+            return Vec::new();
+        };
+        self.running
+            .iter()
+            .filter(|(task_id, _)| {
+                self.started
+                    .get(*task_id)
+                    .map(|started| now.saturating_sub(*started) > max_runtime)
+                    .unwrap_or(false)
+            })
+            .map(|(task_id, (vertex, _))| (task_id.clone(), vertex.clone()))
+            .collect()
+    }
+
+    /// Cancel the queued jobs waiting longer than `max_wait_secs` at `now`.
+    /// Cancellations past their retention are forgotten on the way.
+    pub fn reap_stale(&mut self, now: u64) -> Vec<String> {
+        self.prune_cancelled(now);
+        let max_wait = if let Some(max_wait) = self.configuration.max_wait_secs {
+            max_wait
+        } else {
+            return Vec::new();
+        };
+        let stale = self
+            .jobs
+            .iter()
+            .filter(|(task_id, _, waited)| {
+                let enqueued = self.enqueued.get(task_id).or(waited.as_ref());
+                enqueued
+                    .map(|enqueued| now.saturating_sub(*enqueued) > max_wait)
+                    .unwrap_or(false)
+            })
+            .map(|(task_id, job, _)| (task_id.clone(), job.clone()))
+            .collect::<Vec<_>>();
+        for (task_id, job) in &stale {
+            self.remove_from_queue(task_id);
+            self.cancel(task_id, job, "wait timeout", now);
+        }
+        if !stale.is_empty() {
+            self.refresh_jobs();
+        }
+        stale.into_iter().map(|(task_id, _)| task_id).collect()
+    }
+
+    /// Keep `job` listed as cancelled for `reason` since `now`.
+    fn cancel(&mut self, task_id: &str, job: &JobConfiguration, reason: &str, now: u64) {
+        self.cancelled.insert(task_id.to_string(), (job.clone(), reason.to_string()));
+        self.cancelled_at.insert(task_id.to_string(), now);
+        if self.cancelled.len() > MAX_CANCELLED {
+            self.prune_cancelled(now);
+        }
+    }
+
+    /// Forget the jobs cancelled over `cancelled_retention_secs` before
+    /// `now`, and the oldest ones beyond `MAX_CANCELLED`. Jobs without a
+    /// cancellation time, from older snapshots, go first.
+    pub fn prune_cancelled(&mut self, now: u64) {
+        let retention = self.configuration.cancelled_retention_secs;
+        let mut kept = self
+            .cancelled
+            .keys()
+            .map(|task_id| (self.cancelled_at.get(task_id).cloned().unwrap_or(0), task_id.clone()))
+            .filter(|(at, _)| now.saturating_sub(*at) <= retention)
+            .collect::<Vec<_>>();
+        kept.sort();
+        let kept = kept
             .into_iter()
-            .filter(|(id, _)| running_ids.contains(id))
-            .collect::<HashMap<_, _>>()
+            .rev()
+            .take(MAX_CANCELLED)
+            .map(|(_, task_id)| task_id)
+            .collect::<HashSet<_>>();
+        self.cancelled.retain(|task_id, _| kept.contains(task_id));
+        self.cancelled_at.retain(|task_id, _| kept.contains(task_id));
+    }
+
+    /// Cancel the queued jobs whose `start_before` passed at `now`.
+    pub fn miss_deadlines(&mut self, now: u64) -> Vec<String> {
+        let missed = self
+            .jobs
+            .iter()
+            .filter(|(_, job, _)| job.start_before.map(|deadline| now > deadline).unwrap_or(false))
+            .map(|(task_id, job, _)| (task_id.clone(), job.clone()))
+            .collect::<Vec<_>>();
+        for (task_id, job) in &missed {
+            self.remove_from_queue(task_id);
+            self.cancel(task_id, job, "deadline missed", now);
+        }
+        if !missed.is_empty() {
+            self.refresh_jobs();
+        }
+        missed.into_iter().map(|(task_id, _)| task_id).collect()
     }
 
     pub fn refresh_jobs(&mut self) {
@@ -201,101 +783,236 @@ impl Queue {
     }
 
     fn queue_full(&self) -> bool {
-        Some(self.jobs_in_queue().len())
-            >= self
-                .configuration
-                .global_limit
-                .as_ref()
-                .map(|limit| limit.max_queue)
+        limit_reached(self.jobs_in_queue().len(), &self.configuration.global_limit, |limit| {
+            limit.max_queue
+        })
     }
     fn queue_full_user(&self, uid: u32) -> bool {
-        Some(
-            self.jobs_in_queue()
-                .iter()
-                .filter(|(_, job, _, _)| job.uid == uid)
-                .collect::<Vec<_>>()
-                .len(),
-        ) >= self
-            .configuration
-            .user_limit
-            .as_ref()
-            .map(|limit| limit.max_queue)
+        let queued = self.jobs_in_queue().iter().filter(|(_, job, _, _)| job.uid == uid).count();
+        limit_reached(queued, &self.configuration.user_limit, |limit| limit.max_queue)
     }
     fn queue_full_group(&self, gid: u32) -> bool {
-        Some(
-            self.jobs_in_queue()
-                .iter()
-                .filter(|(_, job, _, _)| job.gid == gid)
-                .collect::<Vec<_>>()
-                .len(),
-        ) >= self
-            .configuration
-            .group_limit
-            .as_ref()
-            .map(|limit| limit.max_queue)
+        let queued = self.jobs_in_queue().iter().filter(|(_, job, _, _)| job.gid == gid).count();
+        limit_reached(queued, &self.configuration.group_limit, |limit| limit.max_queue)
     }
 
     fn running_full(&self) -> bool {
-        Some(self.jobs_in_queue().len())
-            >= self
-                .configuration
-                .global_limit
-                .as_ref()
-                .map(|limit| limit.max_running)
+        limit_reached(self.running.len(), &self.configuration.global_limit, |limit| {
+            limit.max_running
+        })
     }
     fn running_full_user(&self, uid: u32) -> bool {
-        Some(
-            self.jobs_in_queue()
-                .iter()
-                .filter(|(_, job, _, _)| job.uid == uid)
-                .collect::<Vec<_>>()
-                .len(),
-        ) >= self
-            .configuration
-            .user_limit
-            .as_ref()
-            .map(|limit| limit.max_running)
+        let running = self.running.values().filter(|(_, job)| job.uid == uid).count();
+        limit_reached(running, &self.configuration.user_limit, |limit| limit.max_running)
     }
     fn running_full_group(&self, gid: u32) -> bool {
-        Some(
-            self.jobs_in_queue()
-                .iter()
-                .filter(|(_, job, _, _)| job.gid == gid)
-                .collect::<Vec<_>>()
-                .len(),
-        ) >= self
-            .configuration
-            .group_limit
-            .as_ref()
-            .map(|limit| limit.max_running)
+        let running = self.running.values().filter(|(_, job)| job.gid == gid).count();
+        limit_reached(running, &self.configuration.group_limit, |limit| limit.max_running)
     }
 }
 
+/// How the `properties` of a queue are applied to a job setting some of
+/// the same keys.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub enum PropertyInject {
+    /// The queue values are set, jobs asking for other values are rejected.
+    #[default]
+    Overwrite,
+    /// The job values are kept, only the keys it doesn't set are added.
+    FillMissing,
+    /// Jobs setting any key of the queue are rejected.
+    Reject,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct QueueConfiguration {
     priority_rule: Vec<PriorityRule>,
+    /// Priority as an expression of the job, see `priority_expr`. Replaces
+    /// `priority_rule` when set, and is checked as the configuration loads.
+    #[serde(default)]
+    priority_expr: Option<PriorityExpr>,
     users: IdControl,
     groups: IdControl,
     properties: Properties,
+    #[serde(default)]
+    property_inject: PropertyInject,
     global_limit: Option<AmountLimit>,
     user_limit: Option<AmountLimit>,
     group_limit: Option<AmountLimit>,
+    #[serde(default)]
+    preference: i64,
+    #[serde(default)]
+    reject_duplicate_names: bool,
+    /// Cpu shares of the jobs of this queue, the system default if unset.
+    #[serde(default)]
+    cpu_weight: Option<u64>,
+    /// Seconds a job may wait in this queue before it is cancelled.
+    #[serde(default)]
+    max_wait_secs: Option<u64>,
+    /// Seconds cancelled jobs stay listed.
+    #[serde(default = "default_cancelled_retention_secs")]
+    cancelled_retention_secs: u64,
+    /// Seconds a job of this queue may run before the dispatcher has its
+    /// vertex kill it, whatever time limit the job asked for.
+    #[serde(default)]
+    max_runtime_secs: Option<u64>,
+    /// Priorities of this queue's jobs are compared with other queues' as
+    /// `priority * priority_scale + priority_bias`, so queues with rules
+    /// of different magnitudes can be brought to a common range.
+    #[serde(default = "default_priority_scale")]
+    priority_scale: f64,
+    #[serde(default)]
+    priority_bias: f64,
+    /// Users allowed to request each property value, like only some users
+    /// getting `partition: reserved`.
+    #[serde(default)]
+    property_acl: Vec<(String, String, IdControl)>,
+    /// Give jobs short ids like `<id_prefix>-42` instead of UUIDs. Prefixes
+    /// should differ between queues.
+    #[serde(default)]
+    id_prefix: Option<String>,
+    /// Seconds given to jobs submitted without a `time_limit` countable,
+    /// `max_time_limit` if unset.
+    #[serde(default)]
+    default_time_limit: Option<u64>,
+    /// Jobs asking for a longer `time_limit` are rejected.
+    #[serde(default)]
+    max_time_limit: Option<u64>,
+}
+
+fn default_priority_scale() -> f64 {
+    1.
+}
+
+fn default_cancelled_retention_secs() -> u64 {
+    24 * 3600
+}
+
+/// Most cancelled jobs a queue keeps listed.
+const MAX_CANCELLED: usize = 1000;
+
+/// Tell which queues share an `id_prefix`, as they could give two jobs the
+/// same id.
+pub fn shared_id_prefix(queues: &HashMap<String, QueueConfiguration>) -> Option<String> {
+    let mut names = queues.keys().collect::<Vec<_>>();
+    names.sort();
+    let mut prefixes = HashMap::new();
+    for name in names {
+        if let Some(prefix) = &queues[name].id_prefix {
+            if let Some(other) = prefixes.insert(prefix, name) {
+                return Some(format!("Queues {} and {} share the id_prefix {}", other, name, prefix));
+            }
+        }
+    }
+    None
 }
 
 impl QueueConfiguration {
+    /// `priority` of a job of this queue on the scale shared by all queues.
+    pub fn normalize_priority(&self, priority: f64) -> f64 {
+        priority * self.priority_scale + self.priority_bias
+    }
+
     pub fn can_be_added(&self, job: &JobConfiguration) -> bool {
+        self.rejection_reason(job).is_none()
+    }
+
+    pub fn allows(&self, uid: u32, gid: u32) -> bool {
+        self.users.allow(&uid) && self.groups.allow(&gid)
+    }
+
+    pub fn rejection_reason(&self, job: &JobConfiguration) -> Option<String> {
         let JobConfiguration {
             uid,
             gid,
             requirement,
             ..
         } = job;
-        self.users.allow(uid)
-            && self.groups.allow(gid)
-            && !self.properties.conflict(&requirement.properties)
+        if !self.users.allow(uid) {
+            Some(format!("user {} is not allowed", uid))
+        } else if !self.groups.allow(gid) {
+            Some(format!("group {} is not allowed", gid))
+        } else if matches!(self.property_inject, PropertyInject::Overwrite)
+            && self.properties.conflict(&requirement.properties)
+        {
+            Some("requested properties conflict with queue properties".to_string())
+        } else if let (PropertyInject::Reject, Some(key)) = (
+            &self.property_inject,
+            requirement.properties.shared_key(&self.properties),
+        ) {
+            Some(format!("property {} is set by the queue", key))
+        } else if let Some((k, v, _)) = self
+            .property_acl
+            .iter()
+            .find(|(k, v, control)| requirement.properties.matches(k, v) && !control.allow(uid))
+        {
+            Some(format!("user {} may not request property {}={}", uid, k, v))
+        } else {
+            self.max_time_limit
+                .filter(|max| requirement.countables.get("time_limit") as u64 > *max)
+                .map(|max| format!("time limit exceeds the queue maximum of {}s", max))
+        }
+    }
+
+    /// Add the queue properties to `job` as `property_inject` tells, once
+    /// it passed `rejection_reason`.
+    pub fn inject_properties(&self, job: &mut JobConfiguration) {
+        let properties = &mut job.requirement.properties;
+        match self.property_inject {
+            PropertyInject::FillMissing => properties.extend_missing(&self.properties),
+            // with Reject, jobs setting a queue property never get here
+            PropertyInject::Overwrite | PropertyInject::Reject => {
+                properties.extend(&self.properties)
+            }
+        }
+    }
+
+    /// Undo `inject_properties`, dropping the properties set to the queue
+    /// value. A job which asked for that value itself can't be told apart
+    /// and loses it too.
+    pub fn strip_properties(&self, job: &mut JobConfiguration) {
+        job.requirement.properties.remove_matching(&self.properties);
+    }
+
+    /// Undo `limit_time`, dropping a time limit equal to the one the queue
+    /// gives. Like with `strip_properties`, a job which asked for it itself
+    /// loses it too.
+    pub fn strip_time_limit(&self, job: &mut JobConfiguration) {
+        let countables = &mut job.requirement.countables;
+        let given = self.default_time_limit.or(self.max_time_limit);
+        if given.is_some() && countables.get_all().get("time_limit").map(|limit| *limit as u64) == given {
+            countables.remove("time_limit");
+        }
     }
 
-    pub fn priority(&self, requirement: &ResourcesRequirement, waited: u64) -> f64 {
+    /// Give `job` the default time limit if it asks for none.
+    pub fn limit_time(&self, job: &mut JobConfiguration) {
+        let countables = &mut job.requirement.countables;
+        if countables.get_all().contains_key("time_limit") {
+            return;
+        }
+        if let Some(limit) = self.default_time_limit.or(self.max_time_limit) {
+            countables.set("time_limit", limit as usize);
+        }
+    }
+
+    /// Priority of a job with `requirement` which has waited `waited`
+    /// seconds since submission, and must start within `until_deadline`
+    /// seconds if it has a deadline.
+    pub fn priority(
+        &self,
+        requirement: &ResourcesRequirement,
+        waited: u64,
+        until_deadline: Option<u64>,
+    ) -> f64 {
+        if let Some(expr) = &self.priority_expr {
+            // a job it fails on, or gets no finite number for, has no priority
+            return expr
+                .evaluate(requirement, waited, until_deadline)
+                .ok()
+                .filter(|priority| priority.is_finite())
+                .unwrap_or(0.);
+        }
         let mut priority = 0.;
         for rule in &self.priority_rule {
             match rule {
@@ -321,12 +1038,41 @@ impl QueueConfiguration {
                     }
                 }
                 PriorityRule::WaitingRule(factor) => priority += waited as f64 * factor,
+                PriorityRule::DeadlineRule(factor) => {
+                    if let Some(until_deadline) = until_deadline {
+                        priority += factor / (until_deadline + 1) as f64;
+                    }
+                }
+                PriorityRule::AgeBucket(buckets, cumulative) => {
+                    let mut buckets = buckets.clone();
+                    buckets.sort_by_key(|(threshold, _)| *threshold);
+                    let mut passed = buckets
+                        .iter()
+                        .filter(|(threshold, _)| waited > *threshold)
+                        .map(|(_, bonus)| bonus);
+                    if *cumulative {
+                        priority += passed.sum::<f64>();
+                    } else if let Some(bonus) = passed.next_back() {
+                        priority += bonus;
+                    }
+                }
             }
         }
         priority
     }
 }
 
+/// Why an operation on the queues failed.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum QueueError {
+    NoSuchQueue,
+    /// No queue holds the job, or not in the state needed.
+    NotFound,
+    PermissionDenied,
+    Running,
+    Rejected(String),
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum IdControl {
     Allow(HashSet<u32>),
@@ -344,8 +1090,143 @@ impl IdControl {
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct AmountLimit {
-    max_running: usize,
-    max_queue: usize,
+    pub max_running: usize,
+    pub max_queue: usize,
+}
+
+fn first_attempt() -> u32 {
+    1
+}
+
+/// A job as reported to clients. `vertex` is set once the job is running.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct JobSummary {
+    pub task_id: String,
+    pub queue: String,
+    pub name: String,
+    pub uid: u32,
+    pub gid: u32,
+    pub vertex: Option<String>,
+    pub requeued: bool,
+    /// Which run of the job this is, above 1 once it was requeued after
+    /// its vertex died.
+    #[serde(default = "first_attempt")]
+    pub attempt: u32,
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+    /// Why the job was cancelled before running.
+    pub cancelled: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct QueueLimits {
+    pub name: String,
+    pub global_limit: Option<AmountLimit>,
+    pub user_limit: Option<AmountLimit>,
+    pub group_limit: Option<AmountLimit>,
+}
+
+/// A queued job its queue would dispatch now.
+pub struct Submitable<'a> {
+    pub task_id: &'a String,
+    pub job: &'a JobConfiguration,
+    pub queue: &'a String,
+    /// Since when the job is queueable.
+    pub queued_at: u64,
+    pub priority: f64,
+}
+
+/// Picks the next job to dispatch, as `(task_id, job, queue)`, among the
+/// submitable jobs for which `acceptable` holds.
+pub trait Scheduler: Send + Sync {
+    fn select(
+        &self,
+        queues: &QueueGroup,
+        acceptable: &dyn Fn(&JobConfiguration) -> bool,
+    ) -> Option<(String, JobConfiguration, String)>;
+}
+
+/// Pick the first of `submitables` in `order` which is acceptable.
+fn select_in_order(
+    mut submitables: Vec<Submitable<'_>>,
+    acceptable: &dyn Fn(&JobConfiguration) -> bool,
+    order: impl Fn(&Submitable<'_>, &Submitable<'_>) -> std::cmp::Ordering,
+) -> Option<(String, JobConfiguration, String)> {
+    submitables.sort_by(order);
+    submitables
+        .into_iter()
+        .find(|submitable| acceptable(submitable.job))
+        .map(|submitable| {
+            (submitable.task_id.clone(), submitable.job.clone(), submitable.queue.clone())
+        })
+}
+
+/// Highest priority first, across all queues.
+pub struct PrioritySchedule;
+
+impl Scheduler for PrioritySchedule {
+    fn select(
+        &self,
+        queues: &QueueGroup,
+        acceptable: &dyn Fn(&JobConfiguration) -> bool,
+    ) -> Option<(String, JobConfiguration, String)> {
+        select_in_order(queues.submitables(), acceptable, |a, b| {
+            b.priority.total_cmp(&a.priority)
+        })
+    }
+}
+
+/// Longest queueable first, ignoring priorities.
+pub struct FifoSchedule;
+
+impl Scheduler for FifoSchedule {
+    fn select(
+        &self,
+        queues: &QueueGroup,
+        acceptable: &dyn Fn(&JobConfiguration) -> bool,
+    ) -> Option<(String, JobConfiguration, String)> {
+        select_in_order(queues.submitables(), acceptable, |a, b| {
+            a.queued_at.cmp(&b.queued_at)
+        })
+    }
+}
+
+/// The `Scheduler` a dispatcher uses.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub enum SchedulerKind {
+    #[default]
+    Priority,
+    Fifo,
+}
+
+impl SchedulerKind {
+    pub fn create(&self) -> Arc<dyn Scheduler> {
+        match self {
+            Self::Priority => Arc::new(PrioritySchedule),
+            Self::Fifo => Arc::new(FifoSchedule),
+        }
+    }
+}
+
+/// Whether `count` reached the bound `max` picks from `limit`, a queue
+/// without a limit is never full.
+fn limit_reached(
+    count: usize,
+    limit: &Option<AmountLimit>,
+    max: impl Fn(&AmountLimit) -> usize,
+) -> bool {
+    limit.as_ref().map(|limit| count >= max(limit)).unwrap_or(false)
+}
+
+/// Job counts of a queue. `held` counts the queued jobs its limits or a
+/// pause keep from being dispatched.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct QueueOverview {
+    pub limits: QueueLimits,
+    pub queued: usize,
+    pub running: usize,
+    pub held: usize,
+    pub paused: bool,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -354,4 +1235,394 @@ pub enum PriorityRule {
     CountableRule(String, f64, f64),
     PropertyRule(String, String, f64),
     WaitingRule(f64),
+    /// `factor / (seconds left + 1)` for jobs with a `start_before`, so the
+    /// earliest deadline goes first as deadlines get close.
+    DeadlineRule(f64),
+    /// `(threshold_secs, bonus)` pairs applied once the job waited longer
+    /// than the threshold. With the flag set every passed bonus is summed,
+    /// otherwise only the one of the highest passed threshold applies.
+    AgeBucket(Vec<(u64, f64)>, bool),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A queue open to everyone, with the fields of the `extra` YAML mapping
+    /// set over the defaults.
+    fn queue_configuration(extra: &str) -> QueueConfiguration {
+        let mut configuration: serde_yaml::Mapping = serde_yaml::from_str(
+            "{priority_rule: [], users: !Deny [], groups: !Deny [], properties: {}, \
+             global_limit: null, user_limit: null, group_limit: null}",
+        )
+        .unwrap();
+        let extra: serde_yaml::Mapping = serde_yaml::from_str(&format!("{{{}}}", extra)).unwrap();
+        configuration.extend(extra);
+        serde_yaml::from_value(serde_yaml::Value::Mapping(configuration)).unwrap()
+    }
+
+    fn requirement(yaml: &str) -> ResourcesRequirement {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn job(yaml_requirement: &str) -> JobConfiguration {
+        serde_yaml::from_str(&format!(
+            "{{name: test, uid: 1000, gid: 1000, requirement: {}, phases: [!Sh 'true']}}",
+            yaml_requirement
+        ))
+        .unwrap()
+    }
+
+    fn group(queues: &[(&str, &str)]) -> QueueGroup {
+        QueueGroup::new(
+            queues
+                .iter()
+                .map(|(name, extra)| (name.to_string(), Queue::new(&queue_configuration(extra))))
+                .collect(),
+        )
+    }
+
+    fn queued_job(group: &QueueGroup, task_id: &str) -> JobConfiguration {
+        group.queued_job(task_id).unwrap().0
+    }
+
+    #[test]
+    fn requeue_replaces_injected_properties() {
+        let mut queues = group(&[
+            ("a", "properties: {partition: a}"),
+            ("b", "properties: {partition: b}"),
+        ]);
+        let task_id = queues
+            .add_to_queue("a", &job("{cpus: Auto, mems: Auto, countables: {}, properties: {}}"))
+            .unwrap();
+        assert_eq!(queued_job(&queues, &task_id).requirement.properties.get("partition").unwrap(), "a");
+        queues.requeue(&task_id, "b", 1000).unwrap();
+        let moved = queued_job(&queues, &task_id);
+        assert_eq!(moved.requirement.properties.get("partition").unwrap(), "b");
+        assert!(queues.queues()["a"].jobs_in_queue().is_empty());
+    }
+
+    #[test]
+    fn id_prefixes_are_unique() {
+        let mut queues = HashMap::from([
+            ("a".to_string(), queue_configuration("id_prefix: x")),
+            ("b".to_string(), queue_configuration("id_prefix: y")),
+            ("c".to_string(), queue_configuration("")),
+            ("d".to_string(), queue_configuration("")),
+        ]);
+        assert_eq!(shared_id_prefix(&queues), None);
+        queues.insert("e".to_string(), queue_configuration("id_prefix: x"));
+        assert_eq!(
+            shared_id_prefix(&queues),
+            Some("Queues a and e share the id_prefix x".to_string())
+        );
+    }
+
+    #[test]
+    fn countable_rule_scales_one_countable() {
+        let configuration =
+            queue_configuration("priority_rule: [!CountableRule [gpu, 1.0, 2.5]]");
+        let gpus = requirement("{cpus: Auto, mems: Auto, countables: {gpu: 2, cpu: 8}, properties: {}}");
+        assert_eq!(configuration.priority(&gpus, 0, None), 6.);
+        let none = requirement("{cpus: Auto, mems: Auto, countables: {}, properties: {}}");
+        assert_eq!(configuration.priority(&none, 0, None), 1.);
+    }
+
+    #[test]
+    fn non_finite_priority_expr_gives_zero() {
+        let configuration = queue_configuration("priority_expr: 'waited / cpus'");
+        let auto = requirement("{cpus: Auto, mems: Auto, countables: {}, properties: {}}");
+        assert_eq!(configuration.priority(&auto, 0, None), 0.);
+        assert_eq!(configuration.priority(&auto, 10, None), 0.);
+        let two = requirement("{cpus: !Use 2, mems: Auto, countables: {}, properties: {}}");
+        assert_eq!(configuration.priority(&two, 10, None), 5.);
+    }
+
+    #[test]
+    fn missing_limits_never_fill_a_queue() {
+        let mut queue = Queue::new(&queue_configuration(""));
+        let job = job("{cpus: Auto, mems: Auto, countables: {}, properties: {}}");
+        for _ in 0..5 {
+            let task_id = queue.add_to_queue(&job).unwrap();
+            queue.add_to_running(&task_id, &job, "v");
+        }
+        assert!(queue.queueable(job.uid, job.gid));
+        assert!(!queue.running_full());
+        assert!(!queue.running_full_user(job.uid));
+        assert!(!queue.running_full_group(job.gid));
+    }
+
+    #[test]
+    fn limits_cap_queued_and_running_jobs() {
+        let mut queue = Queue::new(&queue_configuration(
+            "global_limit: {max_running: 2, max_queue: 2}, \
+             user_limit: {max_running: 1, max_queue: 3}",
+        ));
+        let job = job("{cpus: Auto, mems: Auto, countables: {}, properties: {}}");
+        let first = queue.add_to_queue(&job).unwrap();
+        assert!(queue.queueable(job.uid, job.gid));
+        queue.add_to_queue(&job).unwrap();
+        assert!(!queue.queueable(job.uid, job.gid));
+
+        assert_eq!(queue.jobs_submitable().len(), 2);
+        queue.remove_from_queue(&first);
+        queue.add_to_running(&first, &job, "v");
+        assert!(!queue.running_full());
+        assert!(queue.running_full_user(job.uid));
+        assert!(!queue.running_full_user(job.uid + 1));
+        assert!(queue.jobs_submitable().is_empty());
+        queue.add_to_running("other", &job, "v");
+        assert!(queue.running_full());
+    }
+
+    #[test]
+    fn waited_time_survives_a_restart() {
+        let mut queue = Queue::new(&queue_configuration("priority_rule: [!WaitingRule 1.0]"));
+        let task_id = queue
+            .add_to_queue(&job("{cpus: Auto, mems: Auto, countables: {}, properties: {}}"))
+            .unwrap();
+        // submitted 100s ago, and not queueable yet when the snapshot is taken
+        queue.enqueued.insert(task_id.clone(), now_to_secs() - 100);
+        queue.jobs[0].2 = None;
+        let snapshot = serde_json::to_string(&queue).unwrap();
+
+        let mut restarted: Queue = serde_json::from_str(&snapshot).unwrap();
+        restarted.refresh_jobs();
+        let (_, _, _, priority) = restarted.jobs_in_queue()[0];
+        assert!((100. ..110.).contains(&priority), "priority {}", priority);
+    }
+
+    #[test]
+    fn duplicate_names_rejected_per_user() {
+        let mut queue = Queue::new(&queue_configuration("reject_duplicate_names: true"));
+        let mut first = job("{cpus: Auto, mems: Auto, countables: {}, properties: {}}");
+        let task_id = queue.add_to_queue(&first).unwrap();
+        assert_eq!(
+            queue.add_to_queue(&first),
+            Err("user 1000 already has a job named test".to_string())
+        );
+        first.uid = 1001;
+        assert!(queue.add_to_queue(&first).is_ok());
+
+        queue.remove_from_queue(&task_id);
+        first.uid = 1000;
+        queue.add_to_running(&task_id, &first, "v");
+        assert!(queue.add_to_queue(&first).is_err());
+        first.name = "other".to_string();
+        assert!(queue.add_to_queue(&first).is_ok());
+    }
+
+    #[test]
+    fn jobs_waiting_too_long_are_cancelled() {
+        let mut queue = Queue::new(&queue_configuration("max_wait_secs: 60"));
+        let job = job("{cpus: Auto, mems: Auto, countables: {}, properties: {}}");
+        let stuck = queue.add_to_queue(&job).unwrap();
+        let now = now_to_secs();
+        assert!(queue.reap_stale(now + 60).is_empty());
+        let fresh = queue.add_to_queue(&job).unwrap();
+        queue.enqueued.insert(fresh.clone(), now + 30);
+        assert_eq!(queue.reap_stale(now + 61), vec![stuck.clone()]);
+        assert_eq!(queue.jobs_in_queue().len(), 1);
+        let summaries = queue.summaries("q");
+        let cancelled = summaries.iter().find(|summary| summary.task_id == stuck).unwrap();
+        assert_eq!(cancelled.cancelled.as_deref(), Some("wait timeout"));
+        assert!(Queue::new(&queue_configuration("")).reap_stale(u64::MAX).is_empty());
+    }
+
+    #[test]
+    fn cancelled_jobs_age_out_and_are_capped() {
+        let mut queue = Queue::new(&queue_configuration("max_wait_secs: 60, cancelled_retention_secs: 100"));
+        let job = job("{cpus: Auto, mems: Auto, countables: {}, properties: {}}");
+        let stuck = queue.add_to_queue(&job).unwrap();
+        let now = now_to_secs();
+        assert_eq!(queue.reap_stale(now + 61), vec![stuck]);
+        assert!(queue.reap_stale(now + 161).is_empty());
+        assert_eq!(queue.summaries("q").len(), 1);
+        queue.reap_stale(now + 162);
+        assert!(queue.summaries("q").is_empty());
+        assert!(queue.cancelled_at.is_empty());
+
+        for index in 0..MAX_CANCELLED + 5 {
+            queue.cancel(&index.to_string(), &job, "wait timeout", now + index as u64 / 10);
+        }
+        assert_eq!(queue.cancelled.len(), MAX_CANCELLED);
+        assert!(!queue.cancelled.contains_key("0"));
+        assert!(queue.cancelled.contains_key(&(MAX_CANCELLED + 4).to_string()));
+    }
+
+    #[test]
+    fn time_limits_default_and_cap() {
+        let mut group = group(&[
+            ("q", "default_time_limit: 600, max_time_limit: 3600"),
+            ("capped", "max_time_limit: 3600"),
+        ]);
+        let unlimited = job("{cpus: Auto, mems: Auto, countables: {}, properties: {}}");
+        let task_id = group.add_to_queue("q", &unlimited).unwrap();
+        assert_eq!(queued_job(&group, &task_id).requirement.countables.get("time_limit"), 600);
+        let task_id = group.add_to_queue("capped", &unlimited).unwrap();
+        assert_eq!(queued_job(&group, &task_id).requirement.countables.get("time_limit"), 3600);
+        let explicit = job("{cpus: Auto, mems: Auto, countables: {time_limit: 1200}, properties: {}}");
+        let task_id = group.add_to_queue("q", &explicit).unwrap();
+        assert_eq!(queued_job(&group, &task_id).requirement.countables.get("time_limit"), 1200);
+        let too_long = job("{cpus: Auto, mems: Auto, countables: {time_limit: 7200}, properties: {}}");
+        assert!(matches!(
+            group.add_to_queue("q", &too_long),
+            Err(QueueError::Rejected(reason)) if reason == "time limit exceeds the queue maximum of 3600s"
+        ));
+    }
+
+    #[test]
+    fn requeue_applies_the_time_limits_of_the_target() {
+        let mut group = group(&[
+            ("long", "default_time_limit: 86400"),
+            ("short", "default_time_limit: 600, max_time_limit: 3600"),
+        ]);
+        let unlimited = job("{cpus: Auto, mems: Auto, countables: {}, properties: {}}");
+        let task_id = group.add_to_queue("long", &unlimited).unwrap();
+        group.requeue(&task_id, "short", 0).unwrap();
+        assert_eq!(queued_job(&group, &task_id).requirement.countables.get("time_limit"), 600);
+        let too_long = job("{cpus: Auto, mems: Auto, countables: {time_limit: 7200}, properties: {}}");
+        let task_id = group.add_to_queue("long", &too_long).unwrap();
+        assert!(matches!(group.requeue(&task_id, "short", 0), Err(QueueError::Rejected(_))));
+        assert_eq!(queued_job(&group, &task_id).requirement.countables.get("time_limit"), 7200);
+    }
+
+    #[test]
+    fn delete_by_label_removes_exactly_the_tagged_jobs() {
+        let mut queues = group(&[("a", ""), ("b", "")]);
+        let labelled = |value: &str, uid: u32| {
+            let mut job = job("{cpus: Auto, mems: Auto, countables: {}, properties: {}}");
+            job.labels.insert("experiment".to_string(), value.to_string());
+            job.uid = uid;
+            job
+        };
+        let mut tagged = vec![
+            queues.add_to_queue("a", &labelled("exp42", 1000)).unwrap(),
+            queues.add_to_queue("b", &labelled("exp42", 1000)).unwrap(),
+        ];
+        let other_value = queues.add_to_queue("a", &labelled("exp43", 1000)).unwrap();
+        let other_user = queues.add_to_queue("b", &labelled("exp42", 1001)).unwrap();
+        tagged.sort();
+        assert_eq!(queues.summaries_by_label("experiment", "exp42").len(), 3);
+        assert_eq!(queues.remove_by_label("experiment", "exp42", 1000), tagged);
+        assert!(queues.queued_job(&other_value).is_some());
+        assert!(queues.queued_job(&other_user).is_some());
+        assert_eq!(queues.remove_by_label("experiment", "exp42", 0), vec![other_user]);
+        assert_eq!(queues.summaries_by_label("experiment", "exp43").len(), 1);
+    }
+
+    #[test]
+    fn jobs_past_max_runtime_are_overruns() {
+        let mut queues = group(&[("capped", "max_runtime_secs: 60"), ("free", "")]);
+        let job = job("{cpus: Auto, mems: Auto, countables: {time_limit: 86400}, properties: {}}");
+        let now = now_to_secs();
+        for (queue, task_id) in [("capped", "long"), ("free", "unbounded")] {
+            queues.0.get_mut(queue).unwrap().add_to_running(task_id, &job, "v");
+        }
+        assert!(queues.overruns(now + 60).is_empty());
+        assert_eq!(queues.overruns(now + 61), vec![("long".to_string(), "v".to_string())]);
+        queues.forget_start("long");
+        assert!(queues.overruns(now + 61).is_empty());
+    }
+
+    #[test]
+    fn scaled_priorities_compare_across_queues() {
+        let gpu_job = job("{cpus: Auto, mems: Auto, countables: {gpu: 1}, properties: {}}");
+        let pick = |large: &str| {
+            let mut queues = group(&[
+                ("large", large),
+                ("small", "priority_rule: [!CountableRule [gpu, 0.0, 2.0]]"),
+            ]);
+            queues.add_to_queue("large", &gpu_job).unwrap();
+            queues.add_to_queue("small", &gpu_job).unwrap();
+            PrioritySchedule.select(&queues, &|_| true).unwrap().2
+        };
+        assert_eq!(pick("priority_rule: [!CountableRule [gpu, 0.0, 1000.0]]"), "large");
+        assert_eq!(
+            pick("priority_rule: [!CountableRule [gpu, 0.0, 1000.0]], priority_scale: 0.001"),
+            "small"
+        );
+        assert_eq!(
+            pick("priority_rule: [!CountableRule [gpu, 0.0, 1000.0]], priority_scale: 0.001, priority_bias: 5.0"),
+            "large"
+        );
+    }
+
+    #[test]
+    fn property_acl_denies_unauthorized_users() {
+        let configuration =
+            queue_configuration("property_acl: [[partition, reserved, !Allow [1001]]]");
+        let mut reserved = job("{cpus: Auto, mems: Auto, countables: {}, properties: {partition: reserved}}");
+        assert_eq!(
+            configuration.rejection_reason(&reserved),
+            Some("user 1000 may not request property partition=reserved".to_string())
+        );
+        reserved.uid = 1001;
+        assert_eq!(configuration.rejection_reason(&reserved), None);
+        let shared = job("{cpus: Auto, mems: Auto, countables: {}, properties: {partition: shared}}");
+        assert!(configuration.can_be_added(&shared));
+    }
+
+    #[test]
+    fn earliest_deadline_goes_first_and_missed_ones_are_cancelled() {
+        let mut queue = Queue::new(&queue_configuration("priority_rule: [!DeadlineRule 1000.0]"));
+        let now = now_to_secs();
+        let with_deadline = |start_before: u64| {
+            let mut job = job("{cpus: Auto, mems: Auto, countables: {}, properties: {}}");
+            job.start_before = Some(start_before);
+            job
+        };
+        let later = queue.add_to_queue(&with_deadline(now + 3600)).unwrap();
+        let sooner = queue.add_to_queue(&with_deadline(now + 60)).unwrap();
+        let mut queued = queue.jobs_in_queue();
+        queued.sort_by(|(_, _, _, a), (_, _, _, b)| b.total_cmp(a));
+        let order = queued.iter().map(|(id, _, _, _)| id.as_str()).collect::<Vec<_>>();
+        assert_eq!(order, vec![sooner.as_str(), later.as_str()]);
+
+        assert!(queue.miss_deadlines(now + 60).is_empty());
+        assert_eq!(queue.miss_deadlines(now + 61), vec![sooner.clone()]);
+        let summaries = queue.summaries("q");
+        let missed = summaries.iter().find(|summary| summary.task_id == sooner).unwrap();
+        assert_eq!(missed.cancelled.as_deref(), Some("deadline missed"));
+        assert_eq!(queue.jobs_in_queue().len(), 1);
+    }
+
+    #[test]
+    fn property_inject_policies() {
+        let mut queues = group(&[
+            ("overwrite", "properties: {partition: gpu, site: a}"),
+            ("fill", "properties: {partition: gpu, site: a}, property_inject: FillMissing"),
+            ("reject", "properties: {partition: gpu, site: a}, property_inject: Reject"),
+        ]);
+        let unset = job("{cpus: Auto, mems: Auto, countables: {}, properties: {}}");
+        let same = job("{cpus: Auto, mems: Auto, countables: {}, properties: {partition: gpu}}");
+        let other = job("{cpus: Auto, mems: Auto, countables: {}, properties: {partition: cpu}}");
+        let properties = |queues: &QueueGroup, task_id: &str| {
+            let mut properties = queued_job(queues, task_id)
+                .requirement
+                .properties
+                .get_all()
+                .iter()
+                .map(|(k, v)| format!("{}={}", k, v))
+                .collect::<Vec<_>>();
+            properties.sort();
+            properties
+        };
+
+        let task_id = queues.add_to_queue("overwrite", &same).unwrap();
+        assert_eq!(properties(&queues, &task_id), ["partition=gpu", "site=a"]);
+        assert!(queues.add_to_queue("overwrite", &other).is_err());
+
+        let task_id = queues.add_to_queue("fill", &other).unwrap();
+        assert_eq!(properties(&queues, &task_id), ["partition=cpu", "site=a"]);
+        let task_id = queues.add_to_queue("fill", &unset).unwrap();
+        assert_eq!(properties(&queues, &task_id), ["partition=gpu", "site=a"]);
+
+        let task_id = queues.add_to_queue("reject", &unset).unwrap();
+        assert_eq!(properties(&queues, &task_id), ["partition=gpu", "site=a"]);
+        assert!(matches!(
+            queues.add_to_queue("reject", &same),
+            Err(QueueError::Rejected(reason)) if reason == "property partition is set by the queue"
+        ));
+    }
 }
@@ -0,0 +1,137 @@
+use std::{
+    collections::HashMap,
+    fs,
+    sync::{Arc, Mutex},
+};
+
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+
+use crate::queue_management::Queue;
+
+/// Where the dispatcher keeps its queues across restarts.
+pub trait QueueStore: Send + Sync {
+    /// The saved queues, none if nothing was saved yet.
+    fn load(&self) -> Result<HashMap<String, Queue>, String>;
+    fn save(&self, queues: &HashMap<String, Queue>) -> Result<(), String>;
+}
+
+/// The `QueueStore` a dispatcher uses, at its `persistent` path.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub enum QueueStoreKind {
+    /// Every queue in one JSON file, rewritten on each save.
+    #[default]
+    File,
+    /// A sqlite database holding a row per queue. Only the queues changed
+    /// since the last save are written, and readers don't block the
+    /// dispatcher.
+    Sqlite,
+}
+
+impl QueueStoreKind {
+    pub fn create(&self, path: &str) -> Result<Arc<dyn QueueStore>, String> {
+        match self {
+            Self::File => Ok(Arc::new(FileStore(path.to_string()))),
+            Self::Sqlite => Ok(Arc::new(SqliteStore::open(path)?)),
+        }
+    }
+}
+
+struct FileStore(String);
+
+impl QueueStore for FileStore {
+    fn load(&self) -> Result<HashMap<String, Queue>, String> {
+        match fs::read_to_string(&self.0) {
+            Ok(content) if !content.is_empty() => {
+                serde_json::from_str(&content).map_err(|err| format!("{}: {}", self.0, err))
+            }
+            _ => Ok(HashMap::new()),
+        }
+    }
+
+    fn save(&self, queues: &HashMap<String, Queue>) -> Result<(), String> {
+        let snapshot = serde_json::to_string(queues).unwrap();
+        fs::write(&self.0, snapshot).map_err(|err| format!("{}: {}", self.0, err))
+    }
+}
+
+struct SqliteStore {
+    connection: Mutex<Connection>,
+    /// Queues as last written, to skip the unchanged ones.
+    saved: Mutex<HashMap<String, String>>,
+}
+
+impl SqliteStore {
+    fn open(path: &str) -> Result<Self, String> {
+        let connection = Connection::open(path).map_err(|err| format!("{}: {}", path, err))?;
+        connection
+            .execute_batch(
+                "PRAGMA journal_mode = WAL;
+                CREATE TABLE IF NOT EXISTS queues (name TEXT PRIMARY KEY, data TEXT NOT NULL);",
+            )
+            .map_err(|err| format!("{}: {}", path, err))?;
+        Ok(Self {
+            connection: Mutex::new(connection),
+            saved: Mutex::new(HashMap::new()),
+        })
+    }
+}
+
+impl QueueStore for SqliteStore {
+    fn load(&self) -> Result<HashMap<String, Queue>, String> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection
+            .prepare("SELECT name, data FROM queues")
+            .map_err(|err| err.to_string())?;
+        let rows = statement
+            .query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))
+            .map_err(|err| err.to_string())?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| err.to_string())?;
+        let mut queues = HashMap::new();
+        for (name, data) in &rows {
+            let queue = serde_json::from_str(data).map_err(|err| format!("queue {}: {}", name, err))?;
+            queues.insert(name.clone(), queue);
+        }
+        *self.saved.lock().unwrap() = rows.into_iter().collect();
+        Ok(queues)
+    }
+
+    fn save(&self, queues: &HashMap<String, Queue>) -> Result<(), String> {
+        let mut saved = self.saved.lock().unwrap();
+        let changed = queues
+            .iter()
+            .map(|(name, queue)| (name.clone(), serde_json::to_string(queue).unwrap()))
+            .filter(|(name, data)| saved.get(name) != Some(data))
+            .collect::<Vec<_>>();
+        let removed = saved
+            .keys()
+            .filter(|name| !queues.contains_key(*name))
+            .cloned()
+            .collect::<Vec<_>>();
+        if changed.is_empty() && removed.is_empty() {
+            return Ok(());
+        }
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction().map_err(|err| err.to_string())?;
+        for (name, data) in &changed {
+            transaction
+                .execute(
+                    "INSERT OR REPLACE INTO queues (name, data) VALUES (?1, ?2)",
+                    params![name, data],
+                )
+                .map_err(|err| err.to_string())?;
+        }
+        for name in &removed {
+            transaction
+                .execute("DELETE FROM queues WHERE name = ?1", params![name])
+                .map_err(|err| err.to_string())?;
+        }
+        transaction.commit().map_err(|err| err.to_string())?;
+        for name in removed {
+            saved.remove(&name);
+        }
+        saved.extend(changed);
+        Ok(())
+    }
+}
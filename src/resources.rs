@@ -1,7 +1,12 @@
+//! The resource model of the old `vertex` and `jobs` binaries, kept so their
+//! configurations and job specs still load. Convert with `.into()` to the
+//! types in `resources_management`.
+#![allow(deprecated)]
+
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 
-
+#[deprecated(note = "use resources_management::NodesRequirement")]
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Nodes {
     Select(HashSet<usize>),
@@ -19,16 +24,16 @@ impl Nodes {
     }
 }
 
-impl ToString for Nodes {
-    fn to_string(&self) -> String {
-        let mut string = String::new();
+impl std::fmt::Display for Nodes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         for item in self.to_hashset().iter() {
-            string.push_str(&format!("{},", item))
-        };
-        string
+            write!(f, "{},", item)?
+        }
+        Ok(())
     }
 }
 
+#[deprecated(note = "use resources_management::ResourcesProvider")]
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Resources {
     countables: HashMap<String, usize>,
@@ -2,20 +2,106 @@ use std::collections::{HashMap, HashSet};
 
 use serde::{Deserialize, Serialize};
 
-#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
-pub struct Countables(HashMap<String, usize>);
+/// Amounts of countable resources. Amounts may be written with a size unit,
+/// like `4G` or `2Gi`, and are kept as plain numbers.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Countables(#[serde(deserialize_with = "deserialize_amounts")] HashMap<String, usize>);
+
+fn deserialize_amounts<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<HashMap<String, usize>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Amount {
+        Number(usize),
+        Text(String),
+    }
+    HashMap::<String, Amount>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(k, amount)| match amount {
+            Amount::Number(amount) => Ok((k, amount)),
+            Amount::Text(amount) => parse_size(&amount)
+                .map(|amount| (k, amount))
+                .map_err(serde::de::Error::custom),
+        })
+        .collect()
+}
+
+/// An optional amount which may be written with a size unit, like the
+/// countables.
+pub fn deserialize_optional_size<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<usize>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Amount {
+        Number(usize),
+        Text(String),
+    }
+    match Option::<Amount>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(Amount::Number(amount)) => Ok(Some(amount)),
+        Some(Amount::Text(amount)) => parse_size(&amount)
+            .map(Some)
+            .map_err(serde::de::Error::custom),
+    }
+}
+
+/// Parse a size like `512M` (powers of 1000) or `2Gi` (powers of 1024). A
+/// plain number is taken as is.
+pub fn parse_size(size: &str) -> Result<usize, String> {
+    let size = size.trim();
+    let split = size
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+    let number = number
+        .parse::<usize>()
+        .map_err(|_| format!("Invalid size {}", size))?;
+    let multiplier: usize = match unit.trim() {
+        "" => 1,
+        "K" | "k" => 1000,
+        "M" => 1000usize.pow(2),
+        "G" => 1000usize.pow(3),
+        "T" => 1000usize.pow(4),
+        "Ki" => 1 << 10,
+        "Mi" => 1 << 20,
+        "Gi" => 1 << 30,
+        "Ti" => 1 << 40,
+        unit => return Err(format!("Unknown size unit {} in {}", unit, size)),
+    };
+    number
+        .checked_mul(multiplier)
+        .ok_or(format!("Size {} is too large", size))
+}
+
+/// Missing countables count as zero, so `{cpu: 4}` equals `{cpu: 4, gpu: 0}`.
+impl PartialEq for Countables {
+    fn eq(&self, other: &Self) -> bool {
+        self.get_all()
+            .keys()
+            .chain(other.get_all().keys())
+            .all(|k| self.get(k) == other.get(k))
+    }
+}
 
+/// Ordered when one has at least as much of every countable as the other,
+/// missing countables counting as zero, and incomparable otherwise.
 impl PartialOrd for Countables {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        if self == other {
-            Some(std::cmp::Ordering::Equal)
-        } else {
-            for (k, v) in self.get_all() {
-                if v > &other.get(k) {
-                    return Some(std::cmp::Ordering::Greater);
-                }
+        let (mut less, mut greater) = (false, false);
+        for k in self.get_all().keys().chain(other.get_all().keys()) {
+            match self.get(k).cmp(&other.get(k)) {
+                std::cmp::Ordering::Less => less = true,
+                std::cmp::Ordering::Greater => greater = true,
+                std::cmp::Ordering::Equal => {}
             }
-            Some(std::cmp::Ordering::Less)
+        }
+        match (less, greater) {
+            (false, false) => Some(std::cmp::Ordering::Equal),
+            (true, false) => Some(std::cmp::Ordering::Less),
+            (false, true) => Some(std::cmp::Ordering::Greater),
+            (true, true) => None,
         }
     }
 }
@@ -37,12 +123,57 @@ impl Countables {
         *self.get_all().get(k).unwrap_or(&0)
     }
 
+    pub fn remove(&mut self, k: &str) {
+        self.get_all_mut().remove(k);
+    }
+
     pub fn enough(&self, k: &str, usage: usize) -> bool {
         self.get(k) >= usage
     }
+
+    /// Add every countable of `other` to this one, missing keys count as zero.
+    pub fn add(&mut self, other: &Countables) {
+        for (k, v) in other.get_all() {
+            self.set(k, self.get(k) + v);
+        }
+    }
+
+    /// What remains once `requirement` is taken, or how much of each
+    /// countable is missing for it.
+    pub fn try_subtract(
+        &self,
+        requirement: &Countables,
+    ) -> Result<Countables, HashMap<String, usize>> {
+        let shortfall = requirement
+            .get_all()
+            .iter()
+            .filter(|(k, v)| !self.enough(k, **v))
+            .map(|(k, v)| (k.clone(), v - self.get(k)))
+            .collect::<HashMap<_, _>>();
+        if !shortfall.is_empty() {
+            return Err(shortfall);
+        }
+        let mut remaining = self.clone();
+        remaining.saturating_subtract(requirement);
+        Ok(remaining)
+    }
+
+    /// Take `requirement` out, countables running short drop to zero.
+    pub fn saturating_subtract(&mut self, requirement: &Countables) {
+        for (k, v) in requirement.get_all() {
+            self.set(k, self.get(k).saturating_sub(*v));
+        }
+    }
+
+    pub fn scale(&self, f: f64) -> HashMap<String, f64> {
+        self.get_all()
+            .iter()
+            .map(|(k, v)| (k.clone(), *v as f64 * f))
+            .collect()
+    }
 }
 
-#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
+#[derive(PartialEq, Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Properties(HashMap<String, String>);
 
 impl PartialOrd for Properties {
@@ -81,6 +212,23 @@ impl Properties {
         self.0.extend(other.clone())
     }
 
+    /// Add the properties of `other` whose keys are not set here.
+    pub fn extend_missing(&mut self, Self(other): &Self) {
+        for (k, v) in other {
+            self.0.entry(k.clone()).or_insert_with(|| v.clone());
+        }
+    }
+
+    /// Drop the keys set to the same value in `other`.
+    pub fn remove_matching(&mut self, Self(other): &Self) {
+        self.0.retain(|k, v| other.get(k) != Some(v));
+    }
+
+    /// A key set both here and in `other`, whatever the values.
+    pub fn shared_key(&self, Self(other): &Self) -> Option<&String> {
+        self.0.keys().find(|key| other.contains_key(*key))
+    }
+
     pub fn conflict(&self, Self(other): &Self) -> bool {
         self.0.keys().any(|key| {
             if let Some(other_value) = other.get(key) {
@@ -94,6 +242,69 @@ impl Properties {
 
 pub type NodeSet = HashSet<usize>;
 
+/// Which vertex a job goes to among those able to run it, after its
+/// `prefer` and `avoid` affinity.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub enum PackingStrategy {
+    /// The first by vertex name.
+    #[default]
+    FirstFit,
+    /// The one left with the least free, filling vertexes one by one.
+    BestFit,
+    /// The one left with the most free, spreading jobs out.
+    WorstFit,
+}
+
+impl PackingStrategy {
+    /// Sort key of a vertex with `available` resources for `requirement`,
+    /// lower goes first.
+    pub fn score(&self, available: &ResourcesProvider, requirement: &ResourcesRequirement) -> f64 {
+        match self {
+            Self::FirstFit => 0.,
+            Self::BestFit => available.leftover(requirement),
+            Self::WorstFit => -available.leftover(requirement),
+        }
+    }
+}
+
+/// Parse lists like `0-63,96-127`, the syntax of the kernel cpu lists.
+pub fn parse_node_list(list: &str) -> Result<NodeSet, String> {
+    let mut nodes = NodeSet::new();
+    for range in list.trim().split(',').map(str::trim).filter(|range| !range.is_empty()) {
+        let invalid = || format!("Invalid node range {} in {}", range, list);
+        match range.split_once('-') {
+            Some((start, end)) => {
+                let start = start.trim().parse::<usize>().map_err(|_| invalid())?;
+                let end = end.trim().parse::<usize>().map_err(|_| invalid())?;
+                if start > end {
+                    return Err(invalid());
+                }
+                nodes.extend(start..=end)
+            }
+            None => {
+                nodes.insert(range.parse().map_err(|_| invalid())?);
+            }
+        }
+    }
+    Ok(nodes)
+}
+
+/// A node set written either as a list or as a range string.
+fn deserialize_node_set<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<NodeSet, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Nodes {
+        List(NodeSet),
+        Ranges(String),
+    }
+    match Nodes::deserialize(deserializer)? {
+        Nodes::List(nodes) => Ok(nodes),
+        Nodes::Ranges(ranges) => parse_node_list(&ranges).map_err(serde::de::Error::custom),
+    }
+}
+
 #[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
 pub enum NodesRequirement {
     Select(HashSet<usize>),
@@ -104,7 +315,7 @@ pub enum NodesRequirement {
 impl NodesRequirement {
     fn is_zero(&self) -> bool {
         match self {
-            Self::Select(set) => set.len() == 0,
+            Self::Select(set) => set.is_empty(),
             Self::Use(size) => *size == 0,
             Self::Auto => false,
         }
@@ -130,6 +341,18 @@ impl NodesRequirement {
     }
 }
 
+#[allow(deprecated)]
+impl From<crate::resources::Nodes> for NodesRequirement {
+    fn from(nodes: crate::resources::Nodes) -> Self {
+        use crate::resources::Nodes;
+        match nodes {
+            Nodes::Select(set) => Self::Select(set),
+            Nodes::Use(size) => Self::Use(size),
+            Nodes::Auto => Self::Auto,
+        }
+    }
+}
+
 impl PartialOrd for NodesRequirement {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
         if self == other {
@@ -174,25 +397,149 @@ pub struct ResourcesRequirement {
     pub mems: NodesRequirement,
     pub countables: Countables,
     pub properties: Properties,
+    /// Only run on a vertex with nothing else running, taking all its cpus
+    /// and memory nodes.
+    #[serde(default)]
+    pub whole_node: bool,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct ResourcesProvider {
+    #[serde(deserialize_with = "deserialize_node_set")]
     pub cpus: NodeSet,
+    #[serde(deserialize_with = "deserialize_node_set")]
     pub mems: NodeSet,
     pub countables: Countables,
     pub properties: Properties,
 }
 
+/// The old model has no node sets, its `cpus` and `mems` countables become
+/// the nodes counted from 0.
+#[allow(deprecated)]
+impl From<crate::resources::Resources> for ResourcesProvider {
+    fn from(resources: crate::resources::Resources) -> Self {
+        let mut countables = Countables::default();
+        for (k, v) in resources.countables() {
+            if k != "cpus" && k != "mems" {
+                countables.set(k, *v);
+            }
+        }
+        Self {
+            cpus: (0..resources.get_countable("cpus")).collect(),
+            mems: (0..resources.get_countable("mems")).collect(),
+            countables,
+            properties: Properties(resources.properties().clone()),
+        }
+    }
+}
+
 impl ResourcesProvider {
     pub fn acceptable(&self, requirement: &ResourcesRequirement) -> bool {
-        self.cpus_acceptable(&requirement.cpus)
-            && self.countables_acceptable(&requirement.countables)
-            && self.properties_acceptable(&requirement.properties)
+        self.check(requirement).is_ok()
     }
 
     pub fn execlusive_mem_acceptable(&self, requirement: &ResourcesRequirement) -> bool {
-        self.mems_acceptable(&requirement.mems) && self.acceptable(requirement)
+        self.check_execlusive_mem(requirement).is_ok()
+    }
+
+    /// Every dimension of `requirement` this provider can't satisfy.
+    pub fn check(&self, requirement: &ResourcesRequirement) -> Result<(), Vec<AcceptFailure>> {
+        let mut failures = vec![];
+        if !self.cpus_acceptable(&requirement.cpus) {
+            failures.push(AcceptFailure::Cpus);
+        }
+        if let Err(shortfall) = self.countables.try_subtract(&requirement.countables) {
+            failures.extend(
+                shortfall
+                    .into_iter()
+                    .map(|(k, missing)| AcceptFailure::Countable(k, missing)),
+            );
+        }
+        failures.extend(
+            requirement
+                .properties
+                .get_all()
+                .iter()
+                .filter(|(k, v)| !self.properties.matches(k, v))
+                .map(|(k, _)| AcceptFailure::Property(k.clone())),
+        );
+        failures.sort();
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(failures)
+        }
+    }
+
+    pub fn check_execlusive_mem(
+        &self,
+        requirement: &ResourcesRequirement,
+    ) -> Result<(), Vec<AcceptFailure>> {
+        let mut failures = self.check(requirement).err().unwrap_or_default();
+        if !self.mems_acceptable(&requirement.mems) {
+            failures.push(AcceptFailure::Mems);
+            failures.sort();
+        }
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(failures)
+        }
+    }
+
+    /// Take the nodes and countables used by `requirement` out of this
+    /// provider. `Use` takes any nodes of the size, `Auto` takes them all.
+    pub fn subtract(&mut self, requirement: &ResourcesRequirement) {
+        fn take_nodes(nodes: &mut NodeSet, requirement: &NodesRequirement) {
+            match requirement {
+                NodesRequirement::Select(set) => nodes.retain(|node| !set.contains(node)),
+                NodesRequirement::Use(size) => {
+                    let taken = nodes.iter().take(*size).cloned().collect::<Vec<_>>();
+                    for node in taken {
+                        nodes.remove(&node);
+                    }
+                }
+                NodesRequirement::Auto => nodes.clear(),
+            }
+        }
+        take_nodes(&mut self.cpus, &requirement.cpus);
+        take_nodes(&mut self.mems, &requirement.mems);
+        self.countables.saturating_subtract(&requirement.countables);
+    }
+
+    /// Share of the free cpus and of each countable asked for by
+    /// `requirement` still free once it is placed here, summed. Resources
+    /// the job doesn't ask for are left out.
+    pub fn leftover(&self, requirement: &ResourcesRequirement) -> f64 {
+        let mut after = self.clone();
+        after.subtract(requirement);
+        let share = |after: usize, before: usize| {
+            if before == 0 {
+                0.
+            } else {
+                after as f64 / before as f64
+            }
+        };
+        let countables = requirement
+            .countables
+            .get_all()
+            .keys()
+            .map(|k| share(after.countables.get(k), self.countables.get(k)))
+            .sum::<f64>();
+        share(after.cpus.len(), self.cpus.len()) + countables
+    }
+
+    /// Number of `prefer` properties this provider matches minus the number
+    /// of `avoid` properties it matches.
+    pub fn affinity(&self, prefer: &Properties, avoid: &Properties) -> i64 {
+        let matched = |properties: &Properties| {
+            properties
+                .get_all()
+                .iter()
+                .filter(|(k, v)| self.properties.matches(k, v))
+                .count() as i64
+        };
+        matched(prefer) - matched(avoid)
     }
 
     fn cpus_acceptable(&self, requirement: &NodesRequirement) -> bool {
@@ -203,11 +550,197 @@ impl ResourcesProvider {
         requirement <= &NodesRequirement::Select(self.mems.clone())
     }
 
-    fn countables_acceptable(&self, requirement: &Countables) -> bool {
-        requirement <= &self.countables
+}
+
+/// A dimension of a requirement a provider can't satisfy, ordered the way
+/// they are reported.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Serialize, Deserialize)]
+pub enum AcceptFailure {
+    Cpus,
+    Mems,
+    /// A countable and how much of it is missing.
+    Countable(String, usize),
+    Property(String),
+}
+
+impl std::fmt::Display for AcceptFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Cpus => write!(f, "cpus"),
+            Self::Mems => write!(f, "mems"),
+            Self::Countable(k, missing) => write!(f, "countable {} (short by {})", k, missing),
+            Self::Property(k) => write!(f, "property {}", k),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn countables(yaml: &str) -> Countables {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn zero_countables_equal_missing_ones() {
+        assert_eq!(countables("{cpu: 4}"), countables("{cpu: 4, gpu: 0}"));
+        assert_eq!(countables("{gpu: 0}"), countables("{}"));
+        assert_ne!(countables("{cpu: 4}"), countables("{cpu: 4, gpu: 1}"));
+    }
+
+    #[test]
+    fn countables_order_by_domination() {
+        use std::cmp::Ordering;
+        let small = countables("{cpu: 2}");
+        let large = countables("{cpu: 4, gpu: 1}");
+        assert_eq!(small.partial_cmp(&large), Some(Ordering::Less));
+        assert_eq!(large.partial_cmp(&small), Some(Ordering::Greater));
+        assert_eq!(small.partial_cmp(&countables("{cpu: 2, gpu: 0}")), Some(Ordering::Equal));
+        assert_eq!(countables("{cpu: 4}").partial_cmp(&countables("{gpu: 1}")), None);
+    }
+
+    #[test]
+    fn add_treats_missing_countables_as_zero() {
+        let mut sum = countables("{cpu: 2, memory: 10}");
+        sum.add(&countables("{cpu: 1, gpu: 3}"));
+        assert_eq!(sum, countables("{cpu: 3, memory: 10, gpu: 3}"));
+        let mut empty = Countables::default();
+        empty.add(&Countables::default());
+        assert_eq!(empty, Countables::default());
     }
 
-    fn properties_acceptable(&self, requirement: &Properties) -> bool {
-        requirement <= &self.properties
+    #[test]
+    fn add_is_commutative() {
+        let a = countables("{cpu: 2, memory: 10}");
+        let b = countables("{cpu: 1, gpu: 3}");
+        let mut a_b = a.clone();
+        a_b.add(&b);
+        let mut b_a = b.clone();
+        b_a.add(&a);
+        assert_eq!(a_b, b_a);
+    }
+
+    #[test]
+    fn scale_keeps_every_countable() {
+        let scaled = countables("{cpu: 2, gpu: 0}").scale(1.5);
+        assert_eq!(scaled.get("cpu"), Some(&3.));
+        assert_eq!(scaled.get("gpu"), Some(&0.));
+        assert_eq!(scaled.get("memory"), None);
+    }
+
+    #[test]
+    fn check_reports_exactly_what_is_short() {
+        let provider: ResourcesProvider = serde_yaml::from_str(
+            "{cpus: '0-3', mems: [0], countables: {memory: 8, gpu: 2}, properties: {p: fast}}",
+        )
+        .unwrap();
+        let requirement = |yaml: &str| -> ResourcesRequirement { serde_yaml::from_str(yaml).unwrap() };
+        let gpus = requirement("{cpus: !Use 2, mems: Auto, countables: {memory: 4, gpu: 3}, properties: {p: fast}}");
+        assert_eq!(provider.check(&gpus), Err(vec![AcceptFailure::Countable("gpu".to_string(), 1)]));
+        assert!(!provider.acceptable(&gpus));
+
+        let everything = requirement("{cpus: !Use 8, mems: Auto, countables: {}, properties: {p: slow}}");
+        assert_eq!(
+            provider.check(&everything),
+            Err(vec![AcceptFailure::Cpus, AcceptFailure::Property("p".to_string())])
+        );
+        let fits = requirement("{cpus: !Use 4, mems: Auto, countables: {memory: 8}, properties: {}}");
+        assert_eq!(provider.check(&fits), Ok(()));
+        assert!(provider.acceptable(&fits));
+    }
+
+    #[test]
+    fn sizes_parse_with_each_suffix() {
+        assert_eq!(parse_size("1024"), Ok(1024));
+        assert_eq!(parse_size("3k"), Ok(3_000));
+        assert_eq!(parse_size("3K"), Ok(3_000));
+        assert_eq!(parse_size("512M"), Ok(512_000_000));
+        assert_eq!(parse_size("4G"), Ok(4_000_000_000));
+        assert_eq!(parse_size("2T"), Ok(2_000_000_000_000));
+        assert_eq!(parse_size("3Ki"), Ok(3 << 10));
+        assert_eq!(parse_size("512Mi"), Ok(512 << 20));
+        assert_eq!(parse_size(" 2Gi "), Ok(2 << 30));
+        assert_eq!(parse_size("1Ti"), Ok(1 << 40));
+        assert!(parse_size("4GB").is_err());
+        assert!(parse_size("G").is_err());
+        assert!(parse_size("99999999999999999999T").is_err());
+        assert_eq!(countables("{memory: 2Gi, gpu: 1}").get("memory"), 2 << 30);
+        assert_eq!(countables("{memory: 2048}").get("memory"), 2048);
+    }
+
+    #[test]
+    fn node_sets_read_as_ranges_or_lists() {
+        let provider: ResourcesProvider = serde_yaml::from_str(
+            "{cpus: '0-3, 8,10-11', mems: '0-1', countables: {}, properties: {}}",
+        )
+        .unwrap();
+        assert_eq!(provider.cpus, NodeSet::from([0, 1, 2, 3, 8, 10, 11]));
+        assert_eq!(provider.mems, NodeSet::from([0, 1]));
+        let listed: ResourcesProvider =
+            serde_yaml::from_str("{cpus: [0, 1, 2, 3, 8, 10, 11], mems: [0, 1], countables: {}, properties: {}}")
+                .unwrap();
+        assert_eq!(listed.cpus, provider.cpus);
+        let round_trip: ResourcesProvider =
+            serde_json::from_str(&serde_json::to_string(&provider).unwrap()).unwrap();
+        assert_eq!(round_trip.cpus, provider.cpus);
+        assert_eq!(round_trip.mems, provider.mems);
+        assert!(serde_yaml::from_str::<ResourcesProvider>(
+            "{cpus: '3-1', mems: [0], countables: {}, properties: {}}"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn try_subtract_gives_remainder_or_shortfall() {
+        let free = countables("{gpu: 2, memory: 8}");
+        assert_eq!(free.try_subtract(&countables("{gpu: 2, memory: 8}")), Ok(countables("{}")));
+        assert_eq!(free.try_subtract(&countables("{gpu: 1}")), Ok(countables("{gpu: 1, memory: 8}")));
+        assert_eq!(
+            free.try_subtract(&countables("{gpu: 3, memory: 8, fpga: 1}")),
+            Err(HashMap::from([("gpu".to_string(), 1), ("fpga".to_string(), 1)]))
+        );
+        let mut drained = free.clone();
+        drained.saturating_subtract(&countables("{gpu: 5}"));
+        assert_eq!(drained, countables("{memory: 8}"));
+    }
+
+    #[test]
+    fn best_and_worst_fit_pick_different_vertexes() {
+        let provider = |yaml: &str| -> ResourcesProvider { serde_yaml::from_str(yaml).unwrap() };
+        let nearly_full = provider("{cpus: '0-1', mems: [0], countables: {gpu: 1}, properties: {}}");
+        let empty = provider("{cpus: '0-7', mems: [0], countables: {gpu: 4}, properties: {}}");
+        let requirement: ResourcesRequirement =
+            serde_yaml::from_str("{cpus: !Use 2, mems: Auto, countables: {gpu: 1}, properties: {}}").unwrap();
+        let pick = |packing: PackingStrategy| {
+            let mut vertexes = [("nearly_full", &nearly_full), ("empty", &empty)];
+            vertexes.sort_by(|(_, a), (_, b)| {
+                packing.score(a, &requirement).total_cmp(&packing.score(b, &requirement))
+            });
+            vertexes[0].0
+        };
+        assert_eq!(pick(PackingStrategy::BestFit), "nearly_full");
+        assert_eq!(pick(PackingStrategy::WorstFit), "empty");
+        assert_eq!(pick(PackingStrategy::FirstFit), "nearly_full");
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn old_resource_model_converts() {
+        use crate::resources::{Nodes, Resources};
+        for yaml in ["!Select [1, 2]", "!Use 3", "Auto"] {
+            let old: Nodes = serde_yaml::from_str(yaml).unwrap();
+            // both models write nodes the same way
+            let written = serde_yaml::to_string(&old).unwrap();
+            let new: NodesRequirement = serde_yaml::from_str(&written).unwrap();
+            assert_eq!(NodesRequirement::from(old), new);
+        }
+        let old: Resources =
+            serde_yaml::from_str("{countables: {cpus: 4, mems: 2, gpu: 2}, properties: {p: fast}}").unwrap();
+        let provider = ResourcesProvider::from(old);
+        assert_eq!(provider.cpus, NodeSet::from([0, 1, 2, 3]));
+        assert_eq!(provider.mems, NodeSet::from([0, 1]));
+        assert_eq!(provider.countables, countables("{gpu: 2}"));
+        assert!(provider.properties.matches("p", "fast"));
     }
 }
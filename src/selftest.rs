@@ -0,0 +1,72 @@
+use std::{fs, os::unix::fs::chown, path::Path, process};
+
+use cgroups_rs::{cgroup_builder::CgroupBuilder, cpu::CpuController, hierarchies, memory::MemController};
+
+/// Owner given to the test log file, the usual `nobody`.
+const NOBODY: u32 = 65534;
+
+/// Check what a vertex needs to run jobs: root, cgroups with the cpu and
+/// memory controllers, and log files it can create and hand over. Exits 1
+/// if any check fails.
+pub fn selftest(log_dir: &str) {
+    let checks = [
+        ("running as root", running_as_root()),
+        ("cgroup create and delete", cgroup()),
+        ("log file write and chown", log_file(Path::new(log_dir))),
+    ];
+    let mut failed = false;
+    for (name, result) in checks {
+        match result {
+            Ok(()) => println!("PASS {}", name),
+            Err(err) => {
+                println!("FAIL {}: {}", name, err);
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        process::exit(1);
+    }
+}
+
+fn running_as_root() -> Result<(), String> {
+    let euid = unsafe { libc::geteuid() };
+    if euid == 0 {
+        Ok(())
+    } else {
+        Err(format!("effective uid is {}, jobs can't switch users", euid))
+    }
+}
+
+fn cgroup() -> Result<(), String> {
+    let name = format!("job_dispatcher_selftest_{}", process::id());
+    let cgroup = CgroupBuilder::new(&name)
+        .cpu()
+        .done()
+        .memory()
+        .done()
+        .build(hierarchies::auto())
+        .map_err(|err| err.to_string())?;
+    let missing = [
+        ("cpu", cgroup.controller_of::<CpuController>().is_none()),
+        ("memory", cgroup.controller_of::<MemController>().is_none()),
+    ]
+    .into_iter()
+    .filter(|(_, missing)| *missing)
+    .map(|(controller, _)| controller)
+    .collect::<Vec<_>>();
+    cgroup.delete().map_err(|err| err.to_string())?;
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("no {} controller", missing.join(", ")))
+    }
+}
+
+fn log_file(log_dir: &Path) -> Result<(), String> {
+    let path = log_dir.join(format!("job_dispatcher_selftest_{}.log", process::id()));
+    fs::write(&path, "selftest\n").map_err(|err| format!("{}: {}", path.display(), err))?;
+    let chowned = chown(&path, Some(NOBODY), Some(NOBODY));
+    fs::remove_file(&path).map_err(|err| format!("{}: {}", path.display(), err))?;
+    chowned.map_err(|err| format!("{}: {}", path.display(), err))
+}
@@ -1,85 +1,661 @@
 use std::{
     env,
-    ffi::CString,
+    io,
+    path::Path,
     process::{self, Stdio},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
 };
 
 use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
     process::Command,
-    time::{Duration, timeout},
+    signal::unix::{signal, SignalKind},
+    sync::Notify,
+    time::{Duration, Instant, timeout, timeout_at},
 };
 
-use cgroups_rs::{cgroup_builder::CgroupBuilder, hierarchies, CgroupPid};
+use cgroups_rs::{
+    cgroup_builder::CgroupBuilder, cpu::CpuController, freezer::FreezerController, hierarchies,
+    memory::MemController, pid::PidController, Cgroup, CgroupPid, Controller, MaxValue,
+};
 
-use crate::jobs_management::JobConfiguration;
+use crate::{
+    jobs_management::{exit_code, IsolationConfig, JobConfiguration, ResourceUsage},
+    resources_management::parse_node_list,
+    utils::home_dir,
+};
 
-use libc::chown;
+use nix::{
+    mount::MsFlags,
+    sched::{unshare, CloneFlags},
+    unistd::{getgroups, geteuid, setegid, seteuid, setgroups, Gid, Uid},
+};
 
 pub async fn supervisor(task_id: &str, data: &str) {
     println!("Parsing job configuration");
-    let job_configuration: JobConfiguration = serde_json::from_str(&data).unwrap();
+    let job_configuration: JobConfiguration = serde_json::from_str(data).unwrap();
     println!("Create cgroup");
-    let hier = hierarchies::auto();
-    let cgroup = CgroupBuilder::new(&task_id)
-        .cpu()
-        .cpus(job_configuration.requirement.cpus.to_string().unwrap())
-        .mems(job_configuration.requirement.mems.to_string().unwrap())
-        .done()
-        .memory()
-        .memory_hard_limit(job_configuration.requirement.countables.get("memory") as i64)
-        .done()
-        .build(hier)
-        .unwrap();
+    let cgroup = create_cgroup(task_id, &job_configuration).unwrap();
+    for mismatch in limit_mismatches(&cgroup, &job_configuration) {
+        println!("Warning: {}", mismatch);
+    }
     println!("Get into cgroup");
     cgroup
         .add_task_by_tgid(CgroupPid::from(process::id() as u64))
         .unwrap();
     println!("Create log files");
-    let stdout = std::fs::File::open(&job_configuration.stdout_file).unwrap();
-    let stderr = std::fs::File::open(&job_configuration.stderr_file).unwrap();
-    unsafe {
-        let stdout = CString::new(job_configuration.stdout_file.as_str()).unwrap();
-        let stderr = CString::new(job_configuration.stderr_file.as_str()).unwrap();
-        if chown(
-            stdout.as_ptr(),
-            job_configuration.uid,
-            job_configuration.gid,
-        ) != 0
-            || chown(
-                stderr.as_ptr(),
-                job_configuration.uid,
-                job_configuration.gid,
-            ) != 0
-        {
-            panic!("Failed to set privilleges on log files")
+    create_log_dir(&job_configuration.stdout_file, &job_configuration).unwrap();
+    create_log_dir(&job_configuration.stderr_file, &job_configuration).unwrap();
+    let (stdout, stderr) = as_job_owner(&job_configuration, || {
+        Ok((
+            std::fs::File::create(&job_configuration.stdout_file)?,
+            std::fs::File::create(&job_configuration.stderr_file)?,
+        ))
+    })
+    .unwrap();
+    if let Some(isolate) = &job_configuration.isolate {
+        println!("Isolate the job");
+        if let Err(err) = isolate_mounts(isolate) {
+            panic!("Failed to isolate the job: {}", err)
         }
     }
     println!("Start executor");
     let program = env::current_exe().unwrap();
-    let mut child = Command::new(program)
-        .arg("executor")
-        .arg(data)
-        .uid(job_configuration.uid)
-        .gid(job_configuration.gid)
-        .stdout(Stdio::from(stdout))
-        .stderr(Stdio::from(stderr))
+    let (stdout_stdio, stderr_stdio, log_files) = if job_configuration.max_log_bytes.is_some() {
+        (Stdio::piped(), Stdio::piped(), Some((stdout, stderr)))
+    } else {
+        (Stdio::from(stdout), Stdio::from(stderr), None)
+    };
+    let mut child = executor_command(&program, &job_configuration, data)
+        .stdout(stdout_stdio)
+        .stderr(stderr_stdio)
         .spawn()
         .unwrap();
-
-    let exit_status = child.wait();
-    let time_limit = timeout(Duration::from_secs(job_configuration.requirement.countables.get("time_limit") as u64), exit_status).await;
-    if let Ok(exit_status) = time_limit {
-        println!("Executor exited. \n{:#?}", exit_status.unwrap());
-    } else {
-        child.kill().await.unwrap();
-        println!("Time limit reached!");
+    let affinity_mismatch = child
+        .id()
+        .filter(|_| job_configuration.strict_pinning)
+        .and_then(|pid| verify_affinity(pid, &job_configuration));
+    if let Some(mismatch) = &affinity_mismatch {
+        println!("Warning: {}", mismatch);
     }
-    
-    println!("Clean cgroup");
+    // out of the job cgroup, so freezing the job doesn't freeze its supervisor
     cgroup
         .remove_task_by_tgid(CgroupPid::from(process::id() as u64))
         .unwrap();
-    cgroup.kill().unwrap();
+    let affinity_failed = affinity_mismatch.is_some() && job_configuration.strict_affinity;
+    if affinity_failed {
+        child.kill().await.unwrap();
+        println!("Cpu affinity not applied, job killed!");
+    }
+
+    let limit_exceeded = Arc::new(Notify::new());
+    let mut pumps = Vec::new();
+    if let (Some(limit), Some((stdout, stderr))) = (job_configuration.max_log_bytes, log_files) {
+        let written = Arc::new(AtomicU64::new(0));
+        let stdout = LimitedWriter::new(File::from_std(stdout), written.clone(), limit);
+        let stderr = LimitedWriter::new(File::from_std(stderr), written, limit);
+        pumps.push(tokio::spawn(pump_log(child.stdout.take().unwrap(), stdout, limit_exceeded.clone())));
+        pumps.push(tokio::spawn(pump_log(child.stderr.take().unwrap(), stderr, limit_exceeded.clone())));
+    }
+
+    let time_limit = Duration::from_secs(job_configuration.requirement.countables.get("time_limit") as u64);
+    let mut log_limit_reached = false;
+    let mut killed = false;
+    let mut time_limit_reached = false;
+    let mut job_exit_code = 0;
+    // sent by the vertex when the job is killed on request
+    let mut terminate = signal(SignalKind::terminate()).unwrap();
+    tokio::select! {
+        time_limit = timeout(time_limit, child.wait()) => {
+            if let Ok(exit_status) = time_limit {
+                let exit_status = exit_status.unwrap();
+                println!("Executor exited. \n{:#?}", exit_status);
+                job_exit_code = exit_code(exit_status);
+            } else {
+                thaw(&cgroup);
+                child.kill().await.unwrap();
+                println!("Time limit reached!");
+                time_limit_reached = true;
+            }
+        }
+        _ = limit_exceeded.notified() => {
+            thaw(&cgroup);
+            child.kill().await.unwrap();
+            println!("Log limit exceeded!");
+            log_limit_reached = true;
+        }
+        _ = terminate.recv() => {
+            thaw(&cgroup);
+            child.kill().await.unwrap();
+            println!("Killed on request!");
+            killed = true;
+        }
+    }
+    
+    // processes left behind by the job may still hold the pipes open
+    let deadline = Instant::now() + LOG_DRAIN_TIMEOUT;
+    for pump in pumps {
+        if timeout_at(deadline, pump).await.is_err() {
+            println!("Log not fully written");
+        }
+    }
+    println!("{}{}", USAGE_PREFIX, serde_json::to_string(&usage(&cgroup)).unwrap());
+    if let Some(cleanup) = job_configuration.cleanup_job() {
+        println!("Run cleanup");
+        if let Err(err) = run_cleanup(&program, &cleanup, &job_configuration, &cgroup).await {
+            println!("Cleanup failed: {}", err);
+        }
+    }
+    println!("Clean cgroup");
+    kill_cgroup(&cgroup);
     cgroup.delete().unwrap();
-    println!("Cgroup cleaned, exit.")
+    println!("Cgroup cleaned, exit.");
+    if log_limit_reached {
+        eprintln!("log limit exceeded");
+        process::exit(1);
+    }
+    if affinity_failed {
+        eprintln!("cpu affinity mismatch");
+        process::exit(1);
+    }
+    if killed {
+        eprintln!("killed on request");
+        process::exit(1);
+    }
+    if time_limit_reached {
+        eprintln!("time limit reached");
+        process::exit(TIME_LIMIT_EXIT_CODE);
+    }
+    if job_exit_code != 0 {
+        eprintln!("job exited with {}", job_exit_code);
+        process::exit(job_exit_code);
+    }
+}
+
+/// Kill what is left in the cgroup, one process at a time where `cgroup.kill`
+/// is missing (cgroup v1, kernels before 5.14).
+fn kill_cgroup(cgroup: &Cgroup) {
+    if cgroup.kill().is_err() {
+        for pid in cgroup.procs() {
+            unsafe { libc::kill(pid.pid as libc::pid_t, libc::SIGKILL) };
+        }
+    }
+}
+
+/// Exit code of a supervisor whose job ran out of time, the one of `timeout(1)`.
+pub const TIME_LIMIT_EXIT_CODE: i32 = 124;
+
+/// Command running the executor for `data` as the owner of `job_configuration`.
+fn executor_command(program: &Path, job_configuration: &JobConfiguration, data: &str) -> Command {
+    let mut executor = Command::new(program);
+    if job_configuration.clean_env {
+        executor.env_clear().env("PATH", CLEAN_PATH);
+        if let Some(home) = home_dir(job_configuration.uid) {
+            executor.env("HOME", home);
+        }
+    }
+    executor
+        .arg("executor")
+        .arg(data)
+        .uid(job_configuration.uid)
+        .gid(job_configuration.gid);
+    executor
+}
+
+/// Run the cleanup phases of a job, appending to its logs and killed after
+/// `cleanup_timeout` seconds.
+async fn run_cleanup(
+    program: &Path,
+    cleanup: &JobConfiguration,
+    job_configuration: &JobConfiguration,
+    cgroup: &Cgroup,
+) -> io::Result<()> {
+    let append = |path: &str| std::fs::OpenOptions::new().append(true).open(path);
+    let data = serde_json::to_string(cleanup).unwrap();
+    let mut child = executor_command(program, job_configuration, &data)
+        .stdout(append(&job_configuration.stdout_file)?)
+        .stderr(append(&job_configuration.stderr_file)?)
+        .spawn()?;
+    if let Some(pid) = child.id() {
+        cgroup
+            .add_task_by_tgid(CgroupPid::from(pid as u64))
+            .map_err(io::Error::other)?;
+    }
+    let time_limit = Duration::from_secs(job_configuration.cleanup_timeout);
+    match timeout(time_limit, child.wait()).await {
+        Ok(exit_status) => {
+            let exit_status = exit_status?;
+            if exit_status.success() {
+                Ok(())
+            } else {
+                Err(io::Error::other(exit_status.to_string()))
+            }
+        }
+        Err(_) => {
+            child.kill().await?;
+            Err(io::Error::new(io::ErrorKind::TimedOut, "time limit reached"))
+        }
+    }
+}
+
+/// Freeze or thaw the processes of the job `task_id`, through the freezer
+/// of the cgroup its supervisor created.
+pub fn set_frozen(task_id: &str, frozen: bool) -> Result<(), String> {
+    let cgroup = Cgroup::load(hierarchies::auto(), task_id);
+    if !cgroup.exists() {
+        return Err(format!("no cgroup for {}", task_id));
+    }
+    let freezer = cgroup
+        .controller_of::<FreezerController>()
+        .ok_or("no freezer controller".to_string())?;
+    if frozen {
+        freezer.freeze()
+    } else {
+        freezer.thaw()
+    }
+    .map_err(|err| err.to_string())
+}
+
+/// Thaw the job so it can be killed, a frozen process never handles the
+/// signal.
+fn thaw(cgroup: &Cgroup) {
+    if let Some(freezer) = cgroup.controller_of::<FreezerController>() {
+        if let Err(err) = freezer.thaw() {
+            println!("Failed to thaw the job: {}", err);
+        }
+    }
+}
+
+/// Longest wait for the logs to be written once the job is over.
+const LOG_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Run `action` with the effective ids of the job owner, so what it creates
+/// belongs to them and what they can't reach is refused. Only a root
+/// supervisor can switch, others run `action` as themselves.
+fn as_job_owner<T>(job_configuration: &JobConfiguration, action: impl FnOnce() -> io::Result<T>) -> io::Result<T> {
+    if !geteuid().is_root() {
+        return action();
+    }
+    let groups = getgroups()?;
+    let gid = Gid::from_raw(job_configuration.gid);
+    setgroups(&[gid])?;
+    setegid(gid)?;
+    seteuid(Uid::from_raw(job_configuration.uid))?;
+    let result = action();
+    seteuid(Uid::from_raw(0))?;
+    setegid(Gid::from_raw(0))?;
+    setgroups(&groups)?;
+    result
+}
+
+/// Create the missing directory of a log file as the job owner, so it can
+/// only go where they may write.
+fn create_log_dir(path: &str, job_configuration: &JobConfiguration) -> io::Result<()> {
+    match Path::new(path).parent() {
+        Some(parent) if !parent.as_os_str().is_empty() && !parent.exists() => {
+            as_job_owner(job_configuration, || std::fs::create_dir_all(parent))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// `PATH` of jobs started with a clean environment.
+const CLEAN_PATH: &str = "/usr/local/bin:/usr/bin:/bin";
+
+/// Move the supervisor, and so the executor it starts, into a new mount
+/// namespace with the mounts of `isolate`. Log files are already open, so
+/// they may live under a hidden `/tmp`.
+fn isolate_mounts(isolate: &IsolationConfig) -> io::Result<()> {
+    if !geteuid().is_root() {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "isolation needs the supervisor to run as root",
+        ));
+    }
+    unshare(CloneFlags::CLONE_NEWNS)?;
+    // keep the mounts below from propagating back to the vertex
+    mount(None, "/", None, MsFlags::MS_REC | MsFlags::MS_PRIVATE, None)?;
+    // nothing mounted for the job may carry setuid programs or devices
+    let restricted = MsFlags::MS_NOSUID | MsFlags::MS_NODEV;
+    if isolate.private_tmp {
+        mount(Some("tmpfs"), "/tmp", Some("tmpfs"), restricted, Some("mode=1777"))?;
+    }
+    for bind in &isolate.binds {
+        mount(Some(&bind.source), &bind.target, None, MsFlags::MS_BIND | MsFlags::MS_REC, None)?;
+        // a bind takes its flags from a remount only
+        let mut flags = MsFlags::MS_BIND | MsFlags::MS_REMOUNT | restricted;
+        if bind.read_only {
+            flags |= MsFlags::MS_RDONLY;
+        }
+        mount(None, &bind.target, None, flags, None)?;
+    }
+    Ok(())
+}
+
+fn mount(
+    source: Option<&str>,
+    target: &str,
+    fstype: Option<&str>,
+    flags: MsFlags,
+    data: Option<&str>,
+) -> io::Result<()> {
+    nix::mount::mount(source, target, fstype, flags, data).map_err(|err| {
+        io::Error::new(io::Error::from(err).kind(), format!("mount {}: {}", target, err))
+    })
+}
+
+/// Prefix of the stdout line carrying the `ResourceUsage` of the job.
+pub const USAGE_PREFIX: &str = "usage: ";
+
+fn usage(cgroup: &Cgroup) -> ResourceUsage {
+    let read = |path: &std::path::Path, file: &str| {
+        std::fs::read_to_string(path.join(file)).ok()
+    };
+    let peak_memory_bytes = cgroup.controller_of::<MemController>().and_then(|memory| {
+        read(memory.path(), "memory.peak")
+            .or(read(memory.path(), "memory.max_usage_in_bytes"))
+            .and_then(|peak| peak.trim().parse().ok())
+    });
+    let cpu_time_usecs = cgroup.controller_of::<CpuController>().and_then(|cpu| {
+        let v2 = read(cpu.path(), "cpu.stat").and_then(|stat| {
+            stat.lines()
+                .find_map(|line| line.strip_prefix("usage_usec "))
+                .and_then(|usec| usec.trim().parse().ok())
+        });
+        v2.or(read(cpu.path(), "cpuacct.usage")
+            .and_then(|nsec| nsec.trim().parse::<u64>().ok())
+            .map(|nsec| nsec / 1000))
+    });
+    ResourceUsage {
+        peak_memory_bytes,
+        cpu_time_usecs,
+    }
+}
+
+/// Cfs period in microseconds the cpu quota is given for.
+const CPU_PERIOD: u64 = 100000;
+
+/// Cpu shares of a cgroup left to the system default, given per cpu to jobs
+/// without strict pinning.
+const DEFAULT_CPU_SHARES: u64 = 1024;
+
+/// Quota for `percent` of one cpu, 200 allows two full cpus.
+fn cpu_quota(percent: u32) -> i64 {
+    CPU_PERIOD as i64 * percent as i64 / 100
+}
+
+/// The cgroup of the job, with its cpus, cpu shares and quota, memory and
+/// pids limits.
+fn create_cgroup(
+    task_id: &str,
+    job_configuration: &JobConfiguration,
+) -> Result<Cgroup, cgroups_rs::error::Error> {
+    let cpu = CgroupBuilder::new(task_id).cpu();
+    let cpu = if job_configuration.strict_pinning {
+        let cpu = cpu
+            .cpus(job_configuration.requirement.cpus.to_string().unwrap())
+            .mems(job_configuration.requirement.mems.to_string().unwrap());
+        if let Some(cpu_weight) = job_configuration.cpu_weight {
+            cpu.shares(cpu_weight)
+        } else {
+            cpu
+        }
+    } else {
+        let cpus = job_configuration.requirement.cpus.take_set().len().max(1) as u64;
+        cpu.shares(job_configuration.cpu_weight.unwrap_or(DEFAULT_CPU_SHARES) * cpus)
+    };
+    let cpu = if let Some(percent) = job_configuration.cpu_quota_percent {
+        cpu.period(CPU_PERIOD).quota(cpu_quota(percent))
+    } else {
+        cpu
+    };
+    // already in bytes, size units are resolved when the job is parsed. No
+    // memory means no limit, a zero limit would kill the job at once.
+    let memory = job_configuration.requirement.countables.get("memory");
+    let builder = if memory > 0 {
+        cpu.done()
+            .memory()
+            .memory_hard_limit(i64::try_from(memory).unwrap_or(i64::MAX))
+            .done()
+    } else {
+        cpu.done()
+    };
+    let builder = if let Some(pids_max) = job_configuration.pids_max {
+        builder
+            .pid()
+            .maximum_number_of_processes(MaxValue::Value(pids_max as i64))
+            .done()
+    } else {
+        builder
+    };
+    builder.build(hierarchies::auto())
+}
+
+/// Read the cpu quota and pids limit back from the cgroup, describing each
+/// one the kernel didn't keep as asked.
+fn limit_mismatches(cgroup: &Cgroup, job_configuration: &JobConfiguration) -> Vec<String> {
+    let mut mismatches = Vec::new();
+    if let Some(percent) = job_configuration.cpu_quota_percent {
+        let quota = cgroup
+            .controller_of::<CpuController>()
+            .and_then(|cpu| cpu.cfs_quota().ok());
+        if quota != Some(cpu_quota(percent)) {
+            mismatches.push(format!("cpu quota is {:?}, expected {}", quota, cpu_quota(percent)));
+        }
+    }
+    if let Some(pids_max) = job_configuration.pids_max {
+        let max = cgroup
+            .controller_of::<PidController>()
+            .and_then(|pid| pid.get_pid_max().ok());
+        if max != Some(MaxValue::Value(pids_max as i64)) {
+            mismatches.push(format!("pids.max is {:?}, expected {}", max, pids_max));
+        }
+    }
+    mismatches
+}
+
+/// Compare the `Cpus_allowed_list` of the process `pid` with the cpus the
+/// job was pinned to, describing the difference if any.
+fn verify_affinity(pid: u32, job_configuration: &JobConfiguration) -> Option<String> {
+    let status = std::fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    let allowed = status
+        .lines()
+        .find_map(|line| line.strip_prefix("Cpus_allowed_list:"))
+        .map(|list| parse_node_list(list.trim()))?;
+    let expected = job_configuration.requirement.cpus.take_set();
+    match allowed {
+        Ok(allowed) if &allowed == expected => None,
+        Ok(allowed) => {
+            let mut allowed = allowed.into_iter().collect::<Vec<_>>();
+            let mut expected = expected.iter().cloned().collect::<Vec<_>>();
+            allowed.sort();
+            expected.sort();
+            Some(format!("cpus allowed are {:?}, expected {:?}", allowed, expected))
+        }
+        Err(err) => Some(format!("unreadable Cpus_allowed_list: {}", err)),
+    }
+}
+
+/// Log file writer sharing a byte budget with the other log of the same job.
+struct LimitedWriter {
+    file: File,
+    written: Arc<AtomicU64>,
+    limit: u64,
+}
+
+impl LimitedWriter {
+    fn new(file: File, written: Arc<AtomicU64>, limit: u64) -> Self {
+        Self {
+            file,
+            written,
+            limit,
+        }
+    }
+
+    /// Write the part of `buf` fitting in the budget, returns `false` once
+    /// the budget is exceeded.
+    async fn write(&mut self, buf: &[u8]) -> io::Result<bool> {
+        let size = buf.len() as u64;
+        let before = self.written.fetch_add(size, Ordering::SeqCst);
+        let allowed = self.limit.saturating_sub(before).min(size) as usize;
+        self.file.write_all(&buf[..allowed]).await?;
+        Ok(before + size <= self.limit)
+    }
+}
+
+async fn pump_log<R: AsyncRead + Unpin>(mut reader: R, mut writer: LimitedWriter, exceeded: Arc<Notify>) {
+    let mut buf = [0u8; 8192];
+    while let Ok(size) = reader.read(&mut buf).await {
+        if size == 0 {
+            break;
+        }
+        match writer.write(&buf[..size]).await {
+            Ok(true) => {}
+            Ok(false) => {
+                exceeded.notify_one();
+                break;
+            }
+            Err(err) => {
+                println!("Failed to write log: {}", err);
+                break;
+            }
+        }
+    }
+    let _ = writer.file.flush().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+
+    async fn limited_writer(path: &std::path::Path, written: &Arc<AtomicU64>, limit: u64) -> LimitedWriter {
+        LimitedWriter::new(File::create(path).await.unwrap(), written.clone(), limit)
+    }
+
+    #[tokio::test]
+    async fn log_over_the_limit_is_cut_and_reported() {
+        let dir = env::temp_dir().join(format!("jd_supervisor_{}", process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let written = Arc::new(AtomicU64::new(0));
+        let exceeded = Arc::new(Notify::new());
+        let stdout = limited_writer(&dir.join("stdout"), &written, 100).await;
+        pump_log(&[b'a'; 60][..], stdout, exceeded.clone()).await;
+        assert!(timeout(Duration::from_millis(50), exceeded.notified()).await.is_err());
+
+        let stderr = limited_writer(&dir.join("stderr"), &written, 100).await;
+        pump_log(&[b'b'; 60][..], stderr, exceeded.clone()).await;
+        assert!(timeout(Duration::from_millis(50), exceeded.notified()).await.is_ok());
+        assert_eq!(std::fs::read(dir.join("stdout")).unwrap().len(), 60);
+        assert_eq!(std::fs::read(dir.join("stderr")).unwrap().len(), 40);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn cgroup_keeps_cpu_quota_and_pids_max() {
+        assert_eq!(cpu_quota(50), 50_000);
+        assert_eq!(cpu_quota(200), 200_000);
+        // creating cgroups needs root
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+        let job: JobConfiguration = serde_yaml::from_str(
+            "{name: test, uid: 0, gid: 0, phases: [!Sh 'true'], cpu_quota_percent: 150, pids_max: 32, \
+             requirement: {cpus: !Select [0], mems: !Select [0], countables: {}, properties: {}}}",
+        )
+        .unwrap();
+        let cgroup = create_cgroup(&format!("jd_test_limits_{}", process::id()), &job).unwrap();
+        let mismatches = limit_mismatches(&cgroup, &job);
+        cgroup.delete().unwrap();
+        assert_eq!(mismatches, Vec::<String>::new());
+    }
+
+    #[tokio::test]
+    async fn clean_env_hides_the_vertex_environment() {
+        let dir = env::temp_dir().join(format!("jd_clean_env_{}", process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        // stands in for the executor, called as `<program> executor <data>`
+        let program = dir.join("print_env");
+        std::fs::write(&program, "#!/bin/sh\nenv > \"$2\"\n").unwrap();
+        std::fs::set_permissions(&program, std::fs::Permissions::from_mode(0o755)).unwrap();
+        env::set_var("JD_CLEAN_ENV_SECRET", "leaked");
+        let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        let mut job: JobConfiguration = serde_yaml::from_str(&format!(
+            "{{name: test, uid: {}, gid: {}, phases: [!Sh 'true'], \
+             requirement: {{cpus: Auto, mems: Auto, countables: {{}}, properties: {{}}}}}}",
+            uid, gid
+        ))
+        .unwrap();
+        let mut environments = Vec::new();
+        for clean_env in [false, true] {
+            job.clean_env = clean_env;
+            let output = dir.join(format!("env_{}", clean_env));
+            let status = executor_command(&program, &job, output.to_str().unwrap())
+                .status()
+                .await
+                .unwrap();
+            assert!(status.success());
+            environments.push(std::fs::read_to_string(&output).unwrap());
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(environments[0].contains("JD_CLEAN_ENV_SECRET=leaked"));
+        assert!(!environments[1].contains("JD_CLEAN_ENV_SECRET"));
+        assert!(environments[1].contains(&format!("PATH={}", CLEAN_PATH)));
+    }
+
+    #[test]
+    fn strict_and_soft_pinning_build_cgroups() {
+        use cgroups_rs::cpuset::CpuSetController;
+        // creating cgroups needs root
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+        let job = |strict_pinning: bool| -> JobConfiguration {
+            serde_yaml::from_str(&format!(
+                "{{name: test, uid: 0, gid: 0, phases: [!Sh 'true'], strict_pinning: {}, \
+                 requirement: {{cpus: !Select [0], mems: !Select [0], countables: {{}}, properties: {{}}}}}}",
+                strict_pinning
+            ))
+            .unwrap()
+        };
+        let strict = create_cgroup(&format!("jd_test_strict_{}", process::id()), &job(true)).unwrap();
+        let cpus = strict.controller_of::<CpuSetController>().map(|cpuset| cpuset.cpuset().cpus);
+        strict.delete().unwrap();
+        assert_eq!(cpus, Some(vec![(0, 0)]));
+
+        let soft = create_cgroup(&format!("jd_test_soft_{}", process::id()), &job(false)).unwrap();
+        let shares = soft.controller_of::<CpuController>().and_then(|cpu| cpu.shares().ok());
+        soft.delete().unwrap();
+        assert_eq!(shares, Some(DEFAULT_CPU_SHARES));
+    }
+
+    #[test]
+    fn memory_limit_is_set_only_when_asked() {
+        // creating cgroups needs root
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+        let limit_of = |memory: usize| {
+            let job: JobConfiguration = serde_yaml::from_str(&format!(
+                "{{name: test, uid: 0, gid: 0, phases: [!Sh 'true'], \
+                 requirement: {{cpus: !Select [0], mems: !Select [0], countables: {{memory: {}}}, properties: {{}}}}}}",
+                memory
+            ))
+            .unwrap();
+            let cgroup = create_cgroup(&format!("jd_test_memory_{}_{}", memory, process::id()), &job).unwrap();
+            let limit = cgroup
+                .controller_of::<MemController>()
+                .map(|memory| memory.memory_stat().limit_in_bytes);
+            cgroup.delete().unwrap();
+            limit
+        };
+        assert_eq!(limit_of(64 << 20), Some(64 << 20));
+        // no limit, the cgroup keeps the unlimited default instead of zero
+        assert!(limit_of(0).map(|limit| limit > 1 << 40).unwrap_or(true));
+    }
 }
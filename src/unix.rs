@@ -1,25 +1,175 @@
-use serde::{Serialize, Deserialize};
-use crate::jobs_management::JobConfiguration;
+use std::{
+    collections::HashMap,
+    io::{self, Read, Write},
+};
 
-#[derive(Serialize, Deserialize, Debug)]
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use crate::{
+    jobs_management::JobConfiguration,
+    queue_management::{JobSummary, QueueError, QueueLimits, QueueOverview},
+    resources_management::ResourcesRequirement,
+    vertex::VertexJobStatus,
+};
+
+/// Leading byte of a gzip stream, plain requests start with JSON instead.
+const GZIP_FLAG: u8 = 0x1f;
+
+/// Requests serialized larger than this are sent compressed.
+const COMPRESS_ABOVE: usize = 64 * 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum ClientRequest {
-    SubmitJob(String, JobConfiguration),
+    /// Submit to the named queue, or the default queue if `None`. A
+    /// submission repeating a recent idempotency key isn't queued again.
+    SubmitJob(Option<String>, Box<JobConfiguration>, Option<String>),
+    SubmitAuto(Box<JobConfiguration>, Option<String>),
     DeleteJob(String),
+    Modify(String, ResourcesRequirement),
+    /// Move a queued job to another queue.
+    RequeueJob(String, String),
     Status,
+    Permissions,
+    /// Explain why a queued job isn't dispatched.
+    Why(String),
+    /// The configuration loaded by the dispatcher, root only.
+    DumpConfig,
+    /// Stop or restart dispatching jobs of a queue, root only.
+    PauseQueue(String),
+    ResumeQueue(String),
+    /// Make vertexes forget jobs which ended before the timestamp, root only.
+    PruneHistory(u64),
+    Queues,
+    /// Delete the caller's queued jobs carrying the label `key: value`.
+    DeleteByLabel(String, String),
+    /// `Status` of the jobs carrying the label `key: value`.
+    StatusByLabel(String, String),
+    /// Stdout of a job sent to a vertex, from the given byte on.
+    Log(String, u64),
+    /// Freeze or thaw a running job on its vertex, root only.
+    Suspend(String),
+    Resume(String),
+}
+
+/// How a client proves who it is where the peer can't be asked for its uid.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Credentials {
+    Basic { username: String, password: String },
+    Token(String),
+}
+
+/// A request with credentials, required over TCP. Over the unix socket the
+/// peer uid wins, except for root acting for the owner of the credentials.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AuthRequest {
+    pub credentials: Credentials,
+    pub request: ClientRequest,
+}
+
+/// What the unix socket accepts.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+pub enum UnixRequest {
+    Plain(ClientRequest),
+    Authenticated(AuthRequest),
+}
+
+/// Bytes to send to the dispatcher, gzipped if the message is large.
+pub fn encode<T: Serialize>(message: &T) -> Vec<u8> {
+    let data = serde_json::to_vec(message).unwrap();
+    if data.len() <= COMPRESS_ABOVE {
+        return data;
+    }
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&data).unwrap();
+    encoder.finish().unwrap()
+}
+
+/// Parse what `encode` produced, plain or gzipped. Gzipped messages
+/// inflating beyond `limit` bytes are refused.
+pub fn decode<T: DeserializeOwned>(data: &[u8], limit: u64) -> io::Result<T> {
+    if data.first() == Some(&GZIP_FLAG) {
+        let mut content = Vec::new();
+        GzDecoder::new(data).take(limit + 1).read_to_end(&mut content)?;
+        if content.len() as u64 > limit {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "request too large"));
+        }
+        Ok(serde_json::from_slice(&content)?)
+    } else {
+        Ok(serde_json::from_slice(data)?)
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum DispatcherResponse {
     InvalidRequest,
+    /// Unknown or missing credentials.
+    Unauthorized,
+    InternalError,
     SubmitSuccess(String),
-    SubmitFailed,
+    /// `NotFound` if the queue doesn't exist, `Rejected` if it doesn't
+    /// accept the job.
+    SubmitFailed(DispatcherFailReasons),
+    /// Too many submissions, retry after this many seconds.
+    RateLimited(u64),
+    SubmitAutoSuccess { queue: String, task_id: String },
+    SubmitAutoFailed(Vec<(String, String)>),
     DeleteSuccess,
     DeleteFailed(DispatcherFailReasons),
-    Status(),
+    /// Task ids of the jobs deleted by label.
+    DeleteByLabelSuccess(Vec<String>),
+    ModifySuccess,
+    ModifyFailed(DispatcherFailReasons),
+    RequeueSuccess,
+    RequeueFailed(DispatcherFailReasons),
+    Status {
+        jobs: Vec<JobSummary>,
+        vertex_jobs: HashMap<String, HashMap<String, VertexJobStatus>>,
+        paused_queues: Vec<String>,
+    },
+    Permissions {
+        uid: u32,
+        gid: u32,
+        queues: Vec<QueueLimits>,
+    },
+    /// `queue` is set when the queue itself holds the job back, `vertexes`
+    /// lists what each alive vertex lacks, or `fits`.
+    Why {
+        queue: Option<String>,
+        vertexes: Vec<(String, String)>,
+    },
+    WhyFailed(DispatcherFailReasons),
+    Config(serde_json::Value),
+    DumpConfigFailed(DispatcherFailReasons),
+    PauseSuccess,
+    PauseFailed(DispatcherFailReasons),
+    /// Jobs forgotten by each vertex, or why it failed.
+    PruneSuccess(HashMap<String, Result<usize, String>>),
+    PruneFailed(DispatcherFailReasons),
+    Queues(Vec<QueueOverview>),
+    /// The next bytes of the stdout, empty at its current end.
+    Log(Vec<u8>),
+    LogFailed(DispatcherFailReasons),
+    SuspendSuccess,
+    SuspendFailed(DispatcherFailReasons),
+    ResumeSuccess,
+    ResumeFailed(DispatcherFailReasons),
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum DispatcherFailReasons {
     PermissionDenied,
     NotFound,
-}
\ No newline at end of file
+    Running,
+    Rejected(String),
+}
+impl From<QueueError> for DispatcherFailReasons {
+    fn from(err: QueueError) -> Self {
+        match err {
+            QueueError::NoSuchQueue | QueueError::NotFound => Self::NotFound,
+            QueueError::PermissionDenied => Self::PermissionDenied,
+            QueueError::Running => Self::Running,
+            QueueError::Rejected(reason) => Self::Rejected(reason),
+        }
+    }
+}
@@ -1,9 +1,54 @@
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::{
+    sync::{RwLock, RwLockReadGuard, RwLockWriteGuard},
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 pub fn now_to_secs() -> u64 {
     SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
 }
 
+/// Home directory of the user `uid`, as the password database tells.
+pub fn home_dir(uid: u32) -> Option<String> {
+    let passwd = unsafe { libc::getpwuid(uid) };
+    if passwd.is_null() {
+        return None;
+    }
+    let home = unsafe { std::ffi::CStr::from_ptr((*passwd).pw_dir) };
+    Some(home.to_string_lossy().to_string())
+}
+
+/// Primary group of the user `uid`.
+pub fn primary_gid(uid: u32) -> Option<u32> {
+    let passwd = unsafe { libc::getpwuid(uid) };
+    if passwd.is_null() {
+        return None;
+    }
+    Some(unsafe { (*passwd).pw_gid })
+}
+
 pub fn now_to_micros() -> u128 {
     SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_micros()
-}
\ No newline at end of file
+}
+
+/// Lock acquisition recovering from poisoning, so a panic while a lock was
+/// held doesn't wedge every later user of it.
+pub trait RwLockExt<T> {
+    fn lock_read(&self) -> RwLockReadGuard<'_, T>;
+    fn lock_write(&self) -> RwLockWriteGuard<'_, T>;
+}
+
+impl<T> RwLockExt<T> for RwLock<T> {
+    fn lock_read(&self) -> RwLockReadGuard<'_, T> {
+        self.read().unwrap_or_else(|poisoned| {
+            println!("Warning: recovering from a poisoned lock");
+            poisoned.into_inner()
+        })
+    }
+
+    fn lock_write(&self) -> RwLockWriteGuard<'_, T> {
+        self.write().unwrap_or_else(|poisoned| {
+            println!("Warning: recovering from a poisoned lock");
+            poisoned.into_inner()
+        })
+    }
+}
@@ -2,75 +2,268 @@ use std::{
     collections::{HashMap, HashSet},
     fs,
     net::SocketAddr,
-    sync::{Arc, RwLock}, thread::spawn, process::Command, env, str::FromStr,
+    sync::{Arc, Mutex, PoisonError, RwLock}, thread::spawn, process::{Command, Stdio}, env,
+    time::Duration,
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write},
+    path::{Component, PathBuf},
 };
 
 use crate::{
-    jobs_management::JobConfiguration,
-    resources_management::{ResourcesProvider, ResourcesRequirement, NodesRequirement},
-    http::{basic_check, HttpServerConfig}, utils::now_to_secs,
+    jobs_management::{JobConfiguration, ResourceUsage},
+    supervisor::{set_frozen, USAGE_PREFIX},
+    resources_management::{
+        deserialize_optional_size, parse_node_list, NodesRequirement, ResourcesProvider,
+    },
+    http::{basic_check, HttpServerConfig, TlsConfig}, utils::{now_to_secs, RwLockExt},
+    vertex_client::{Registration, VertexConnect},
 };
 use axum::{
     http::StatusCode,
-    extract::{State, Path},
+    extract::{DefaultBodyLimit, State, Path, Query},
     headers::{authorization::Basic, Authorization},
     middleware,
     response::{Response, IntoResponse},
     routing::{get, post},
     Json, Router, TypedHeader,
 };
+use base64::Engine;
 use serde::{Deserialize, Serialize};
+use tokio::time::sleep;
 use uuid::Uuid;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct VertexConfig {
     #[serde(default)]
     http: HttpServerConfig,
+    #[serde(default)]
+    tls: Option<TlsConfig>,
     basic: HashMap<String, String>,
     resources: ResourcesProvider,
     history: String,
+    /// Upper bound of running jobs, regardless of the free resources.
+    #[serde(default)]
+    max_concurrent_jobs: Option<usize>,
+    /// Reject jobs whose uid or gid doesn't exist on this node.
+    #[serde(default)]
+    check_ids: bool,
+    /// Seconds finished jobs are kept in the history, forever if `None`.
+    #[serde(default)]
+    history_retention: Option<u64>,
+    /// Largest job accepted from the dispatcher.
+    #[serde(default = "default_max_request_bytes")]
+    max_request_bytes: usize,
+    /// Announce this vertex to a dispatcher instead of being listed in its
+    /// configuration.
+    #[serde(default)]
+    register: Option<RegisterConfig>,
+    /// Memory limit in bytes of jobs requesting no `memory`, which run
+    /// unlimited if unset. Size units like `4Gi` are accepted.
+    #[serde(default, deserialize_with = "deserialize_optional_size")]
+    default_memory_limit: Option<usize>,
+    /// Directories jobs may bind mount from and to, with everything below
+    /// them. Jobs asking for other binds are rejected, as are all binds if
+    /// empty.
+    #[serde(default)]
+    allowed_binds: Vec<String>,
 }
 
+/// Where and how often to announce this vertex, see `Registration`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct RegisterConfig {
+    /// Base URL of the `registration` listener of the dispatcher.
+    dispatcher: String,
+    name: String,
+    secret: String,
+    /// How the dispatcher reaches this vertex, with one of the `basic`
+    /// users.
+    connect: VertexConnect,
+    /// Seconds between announcements, below the `ttl` of the dispatcher.
+    #[serde(default = "default_register_interval")]
+    interval: u64,
+    /// PEM file of the CA the certificate of an HTTPS dispatcher is checked
+    /// against, besides the system ones.
+    #[serde(default)]
+    ca_cert: Option<String>,
+}
+
+impl RegisterConfig {
+    fn client(&self) -> reqwest::Client {
+        let mut builder = reqwest::Client::builder();
+        if let Some(ca_cert) = &self.ca_cert {
+            let ca_cert = fs::read(ca_cert).unwrap();
+            builder = builder.add_root_certificate(reqwest::Certificate::from_pem(&ca_cert).unwrap());
+        }
+        builder.build().unwrap()
+    }
+}
+
+fn default_register_interval() -> u64 {
+    30
+}
+
+fn default_max_request_bytes() -> usize {
+    4 * 1024 * 1024
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PruneQuery {
+    pub before: u64,
+}
+
+/// Offset in bytes of the stdout of a job to read from.
+#[derive(Deserialize, Debug)]
+pub struct LogQuery {
+    #[serde(default)]
+    pub from: u64,
+}
+
+/// Most bytes of stdout returned by one log request.
+pub const LOG_CHUNK_BYTES: u64 = 64 * 1024;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum VertexJobStatus {
-    Running(JobConfiguration, u64),
+    Running {
+        configuration: JobConfiguration,
+        started_at: u64,
+        /// Cpus and memory nodes the job was pinned to.
+        cpuset: Vec<usize>,
+        memset: Vec<usize>,
+        /// Pid of the supervisor, 0 until it is spawned.
+        #[serde(default)]
+        pid: u32,
+        /// Suspended through the cgroup freezer.
+        #[serde(default)]
+        frozen: bool,
+    },
     Error {
         configuration: JobConfiguration,
         status_code: i32,
         error_message: String,
-        exit_at: u64
+        exit_at: u64,
+        #[serde(default)]
+        runtime_secs: u64,
+        /// End of the supervisor stderr, base64 encoded for jobs with
+        /// `binary_output`.
+        #[serde(default)]
+        stderr: String,
+    },
+    Finished {
+        configuration: JobConfiguration,
+        exit_code: i32,
+        exit_at: u64,
+        runtime_secs: u64,
+        #[serde(default)]
+        usage: ResourceUsage,
     },
-    Finished(JobConfiguration, u64),
+}
+
+impl VertexJobStatus {
+    pub fn configuration(&self) -> &JobConfiguration {
+        match self {
+            Self::Running { configuration, .. }
+            | Self::Error { configuration, .. }
+            | Self::Finished { configuration, .. } => configuration,
+        }
+    }
+
+    /// Whether the job ended before `before`, running jobs never did.
+    pub fn ended_before(&self, before: u64) -> bool {
+        match self {
+            Self::Running { .. } => false,
+            Self::Error { exit_at, .. } | Self::Finished { exit_at, .. } => *exit_at < before,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 struct VertexState {
     configuration: VertexConfig,
     jobs: Arc<RwLock<HashMap<(String, String), VertexJobStatus>>>,
+    /// Cpu to NUMA node map, `None` if the topology couldn't be read.
+    topology: Arc<Option<HashMap<usize, usize>>>,
+    /// Held by a submit from reading the free resources until its job is
+    /// recorded as running, so concurrent submits can't pick the same cpus.
+    submitting: Arc<Mutex<()>>,
 }
 
 pub async fn vertex(config_path: &str) {
     let configuration: VertexConfig = serde_yaml::from_str(&fs::read_to_string(config_path).unwrap()).unwrap();
+    if configuration.resources.cpus.is_empty() {
+        panic!("No cpus configured in {}, this vertex could never run a job", config_path)
+    }
+    if configuration.resources.mems.is_empty() {
+        println!("Warning: no memory nodes configured, jobs will use node 0");
+    }
     let history: HashMap<(String, String), VertexJobStatus> =
         serde_json::from_str(&fs::read_to_string(&configuration.history).unwrap()).unwrap();
     let state = VertexState {
         configuration,
         jobs: Arc::new(RwLock::new(history)),
+        topology: Arc::new(numa_topology()),
+        submitting: Arc::new(Mutex::new(())),
     };
+    if let Some(retention) = state.configuration.history_retention {
+        let jobs = state.jobs.clone();
+        tokio::spawn(async move {
+            loop {
+                let before = now_to_secs().saturating_sub(retention);
+                jobs.lock_write().retain(|_, status| !status.ended_before(before));
+                sleep(Duration::from_secs(60)).await;
+            }
+        });
+    }
+    if let Some(register) = state.configuration.register.clone() {
+        let resources = state.configuration.resources.clone();
+        let client = register.client();
+        tokio::spawn(async move {
+            loop {
+                announce(&client, &register, &resources).await;
+                sleep(Duration::from_secs(register.interval)).await;
+            }
+        });
+    }
     let app = Router::new()
         .route("/", get(get_free))
-        .route("/jobs", get(get_jobs))
-        .route("/job/:task_id", post(submit_job))
+        .route("/jobs", get(get_jobs).delete(prune_jobs))
+        .route("/job/:task_id", post(submit_job).delete(kill_job))
+        .route("/job/:task_id/log", get(get_log))
+        .route("/job/:task_id/suspend", post(suspend_job))
+        .route("/job/:task_id/resume", post(resume_job))
+        .layer(DefaultBodyLimit::max(state.configuration.max_request_bytes))
         .layer(middleware::from_fn_with_state(
             state.configuration.basic.clone(),
             basic_check,
         ))
         .with_state(state.clone());
     let addr = SocketAddr::from((state.configuration.http.ip, state.configuration.http.port));
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service())
-        .await
-        .unwrap();
+    if let Some(tls) = &state.configuration.tls {
+        axum_server::bind_rustls(addr, tls.rustls_config().unwrap())
+            .serve(app.into_make_service())
+            .await
+            .unwrap();
+    } else {
+        axum::Server::bind(&addr)
+            .serve(app.into_make_service())
+            .await
+            .unwrap();
+    }
+}
+
+/// Tell the dispatcher of `register` this vertex is up, failures are only
+/// logged and retried at the next announcement.
+async fn announce(client: &reqwest::Client, register: &RegisterConfig, resources: &ResourcesProvider) {
+    let registration = Registration {
+        name: register.name.clone(),
+        secret: register.secret.clone(),
+        connect: register.connect.clone(),
+        resources: resources.clone(),
+    };
+    let url = format!("{}/register", register.dispatcher.trim_end_matches('/'));
+    match client.post(&url).json(&registration).send().await {
+        Ok(resp) if resp.status().is_success() => {}
+        Ok(resp) => println!("Failed to register at {}: {}", url, resp.status()),
+        Err(err) => println!("Failed to register at {}: {}", url, err),
+    }
 }
 
 async fn get_free(State(state): State<VertexState>) -> Json<ResourcesProvider> {
@@ -83,7 +276,7 @@ async fn get_jobs(
     TypedHeader(Authorization(basic)): TypedHeader<Authorization<Basic>>,
 ) -> Json<HashMap<String, VertexJobStatus>> {
     let username = basic.username();
-    let jobs = state.jobs.read().unwrap();
+    let jobs = state.jobs.lock_read();
     let filtered = jobs
         .iter()
         .filter(|((user, _), _)| user == username)
@@ -92,91 +285,543 @@ async fn get_jobs(
     Json(filtered)
 }
 
+/// Forget the jobs of the user which ended before `before`.
+async fn prune_jobs(
+    State(state): State<VertexState>,
+    Query(PruneQuery { before }): Query<PruneQuery>,
+    TypedHeader(Authorization(basic)): TypedHeader<Authorization<Basic>>,
+) -> Json<usize> {
+    let username = basic.username();
+    let mut jobs = state.jobs.lock_write();
+    let count = jobs.len();
+    jobs.retain(|(user, _), status| user != username || !status.ended_before(before));
+    Json(count - jobs.len())
+}
+
+/// Up to `LOG_CHUNK_BYTES` of the stdout of a job from the offset `from`,
+/// empty once the end is reached.
+async fn get_log(
+    Path(task_id): Path<String>,
+    Query(LogQuery { from }): Query<LogQuery>,
+    State(state): State<VertexState>,
+    TypedHeader(Authorization(basic)): TypedHeader<Authorization<Basic>>,
+) -> Response {
+    let key = (basic.username().to_string(), task_id);
+    let path = match state.jobs.lock_read().get(&key) {
+        Some(status) => status.configuration().stdout_file.clone(),
+        None => return (StatusCode::NOT_FOUND, "No such job").into_response(),
+    };
+    let read = || -> io::Result<Vec<u8>> {
+        let mut file = fs::File::open(&path)?;
+        file.seek(SeekFrom::Start(from))?;
+        let mut data = Vec::new();
+        file.take(LOG_CHUNK_BYTES).read_to_end(&mut data)?;
+        Ok(data)
+    };
+    match read() {
+        Ok(data) => (StatusCode::OK, data).into_response(),
+        // the supervisor didn't create it yet
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            (StatusCode::OK, Vec::new()).into_response()
+        }
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+    }
+}
+
+/// Ask the supervisor of a running job to kill it.
+async fn kill_job(
+    Path(task_id): Path<String>,
+    State(state): State<VertexState>,
+    TypedHeader(Authorization(basic)): TypedHeader<Authorization<Basic>>,
+) -> Response {
+    let key = (basic.username().to_string(), task_id);
+    let pid = match state.jobs.lock_read().get(&key) {
+        Some(VertexJobStatus::Running { pid, .. }) if *pid != 0 => *pid,
+        Some(VertexJobStatus::Running { .. }) => {
+            return (StatusCode::CONFLICT, "Job is starting").into_response()
+        }
+        _ => return (StatusCode::NOT_FOUND, "No such running job").into_response(),
+    };
+    if unsafe { libc::kill(pid as i32, libc::SIGTERM) } == 0 {
+        (StatusCode::OK, "Killed").into_response()
+    } else {
+        let err = io::Error::last_os_error().to_string();
+        (StatusCode::INTERNAL_SERVER_ERROR, err).into_response()
+    }
+}
+
+async fn suspend_job(
+    Path(task_id): Path<String>,
+    State(state): State<VertexState>,
+    TypedHeader(Authorization(basic)): TypedHeader<Authorization<Basic>>,
+) -> Response {
+    set_job_frozen(&state, (basic.username().to_string(), task_id), true)
+}
+
+async fn resume_job(
+    Path(task_id): Path<String>,
+    State(state): State<VertexState>,
+    TypedHeader(Authorization(basic)): TypedHeader<Authorization<Basic>>,
+) -> Response {
+    set_job_frozen(&state, (basic.username().to_string(), task_id), false)
+}
+
+/// Freeze or thaw a running job. Jobs which ended, or whose supervisor
+/// isn't up yet, are left alone.
+fn set_job_frozen(state: &VertexState, key: (String, String), frozen: bool) -> Response {
+    let mut jobs = state.jobs.lock_write();
+    let current = match jobs.get_mut(&key) {
+        Some(VertexJobStatus::Running { pid, .. }) if *pid == 0 => {
+            return (StatusCode::CONFLICT, "Job is starting").into_response()
+        }
+        Some(VertexJobStatus::Running { frozen, .. }) => frozen,
+        Some(_) => return (StatusCode::CONFLICT, "Job already ended").into_response(),
+        None => return (StatusCode::NOT_FOUND, "No such job").into_response(),
+    };
+    if *current == frozen {
+        return (StatusCode::OK, "Unchanged").into_response();
+    }
+    match set_frozen(&key.1, frozen) {
+        Ok(()) => {
+            *current = frozen;
+            (StatusCode::OK, if frozen { "Suspended" } else { "Resumed" }).into_response()
+        }
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err).into_response(),
+    }
+}
+
 async fn submit_job(
     Path(task_id): Path<String>,
     State(state): State<VertexState>,
     TypedHeader(Authorization(basic)): TypedHeader<Authorization<Basic>>,
     Json(job_configuration): Json<JobConfiguration>,
 ) -> Response {
-    let task_id = Uuid::from_str(&task_id).unwrap_or(Uuid::new_v4()).to_string();
-    let mut available_resources = current_free(&state);
-    if available_resources.mems.len() == 0 {
-        available_resources.mems = HashSet::from([0]);
+    // keep ids safe to use in cgroup names, like UUIDs or short `q1-42` ids
+    let task_id = if !task_id.is_empty()
+        && task_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        task_id
+    } else {
+        Uuid::new_v4().to_string()
+    };
+    if state.configuration.check_ids {
+        if let Some(reason) = unknown_ids(job_configuration.uid, job_configuration.gid) {
+            return (StatusCode::BAD_REQUEST, reason).into_response();
+        }
     }
-    if available_resources.acceptable(&job_configuration.requirement) {
-        let mut job_configuration = job_configuration;
-        if let NodesRequirement::Use(size) = job_configuration.requirement.cpus {
-            job_configuration.requirement.cpus = NodesRequirement::Select(
-                available_resources.cpus.into_iter().take(size).collect::<HashSet<_>>()
-            );
-        } else if let NodesRequirement::Auto = job_configuration.requirement.cpus {
-            job_configuration.requirement.cpus = NodesRequirement::Select(
-                available_resources.cpus
-            )
-        };
-        if let NodesRequirement::Use(size) = job_configuration.requirement.mems {
-            job_configuration.requirement.cpus = NodesRequirement::Select(
-                available_resources.mems.into_iter().take(size).collect::<HashSet<_>>()
-            );
-        } else if let NodesRequirement::Auto = job_configuration.requirement.cpus {
-            job_configuration.requirement.cpus = NodesRequirement::Select(
-                available_resources.mems
-            )
-        };
-        let username = basic.username().to_string();
-        state.jobs.write().unwrap().insert(
-            (username.to_string(), task_id.clone()), VertexJobStatus::Running(job_configuration.clone(), now_to_secs())
-        );
-        let jobs = state.jobs.clone();
-        let task_id_supervisor = task_id.clone();
-        spawn(move || {
-            let program = env::current_exe().unwrap();
-            let mut command = Command::new(program)
-                .arg("supervisor")
-                .arg(serde_json::to_string(&job_configuration).unwrap())
-                .spawn()
-                .unwrap();
-            let exit_status = command.wait().unwrap();
-            let mut jobs = jobs.write().unwrap();
-            if exit_status.success() {
-                jobs.insert((username, task_id_supervisor), VertexJobStatus::Finished(job_configuration, now_to_secs()));
+    if let Some(reason) = forbidden_bind(&state.configuration.allowed_binds, &job_configuration) {
+        return (StatusCode::FORBIDDEN, reason).into_response();
+    }
+    let username = basic.username().to_string();
+    let started_at = now_to_secs();
+    let job_configuration = match reserve(&state, &username, &task_id, job_configuration, started_at) {
+        Ok(Some(job_configuration)) => job_configuration,
+        // a retry of a submission whose answer was lost
+        Ok(None) => return (StatusCode::OK, task_id).into_response(),
+        Err(reason) => return (StatusCode::SERVICE_UNAVAILABLE, reason).into_response(),
+    };
+    let jobs = state.jobs.clone();
+    let task_id_supervisor = task_id.clone();
+    let supervised = supervised(&state.configuration, &job_configuration);
+    spawn(move || {
+        let program = env::current_exe().unwrap();
+        let mut command = Command::new(program)
+            .arg("supervisor")
+            .arg(&task_id_supervisor)
+            .arg(serde_json::to_string(&supervised).unwrap())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let supervisor_stderr = command.stderr.take().unwrap();
+        let stderr_tail = spawn(move || stderr_tail(supervisor_stderr));
+        let key = (username.clone(), task_id_supervisor.clone());
+        if let Some(VertexJobStatus::Running { pid, .. }) = jobs.lock_write().get_mut(&key) {
+            *pid = command.id();
+        }
+        // pass the supervisor output through, picking the usage report
+        let mut usage = ResourceUsage::default();
+        for line in BufReader::new(command.stdout.take().unwrap()).lines().map_while(Result::ok) {
+            if let Some(report) = line.strip_prefix(USAGE_PREFIX) {
+                usage = serde_json::from_str(report).unwrap_or_default();
             } else {
-                jobs.insert((username, task_id_supervisor), VertexJobStatus::Error { configuration: job_configuration, status_code: exit_status.code().unwrap_or(1), error_message: exit_status.to_string(), exit_at: now_to_secs() });
+                println!("{}", line);
             }
-        });
-        (StatusCode::OK, task_id).into_response()
+        }
+        let exit_status = command.wait().unwrap();
+        let stderr = encode_output(&job_configuration, &stderr_tail.join().unwrap_or_default());
+        let mut jobs = jobs.lock_write();
+        let exit_at = now_to_secs();
+        let runtime_secs = exit_at.saturating_sub(started_at);
+        jobs.insert((username, task_id_supervisor), ended(job_configuration, exit_status, exit_at, runtime_secs, usage, stderr));
+    });
+    (StatusCode::OK, task_id).into_response()
+}
+
+/// Status of a job whose supervisor exited, which passes the job's exit code on.
+fn ended(configuration: JobConfiguration, exit_status: std::process::ExitStatus, exit_at: u64, runtime_secs: u64, usage: ResourceUsage, stderr: String) -> VertexJobStatus {
+    if exit_status.success() {
+        VertexJobStatus::Finished { configuration, exit_code: 0, exit_at, runtime_secs, usage }
     } else {
-        (StatusCode::SERVICE_UNAVAILABLE, "Resources not enough").into_response()
+        VertexJobStatus::Error { configuration, status_code: exit_status.code().unwrap_or(1), error_message: exit_status.to_string(), exit_at, runtime_secs, stderr }
+    }
+}
+
+/// The job as given to its supervisor, with the `default_memory_limit` if it
+/// requests no memory. Only the supervisor sees the default, it isn't a
+/// requested resource.
+fn supervised(configuration: &VertexConfig, job_configuration: &JobConfiguration) -> JobConfiguration {
+    let mut supervised = job_configuration.clone();
+    if let Some(limit) = configuration.default_memory_limit {
+        if supervised.requirement.countables.get("memory") == 0 {
+            supervised.requirement.countables.set("memory", limit);
+        }
+    }
+    supervised
+}
+
+/// Pick the cpus and memory nodes of a job and record it as running, or
+/// tell why it can't run now. `None` if the job already runs.
+fn reserve(
+    state: &VertexState,
+    username: &str,
+    task_id: &str,
+    mut job_configuration: JobConfiguration,
+    started_at: u64,
+) -> Result<Option<JobConfiguration>, &'static str> {
+    let _submitting = state.submitting.lock().unwrap_or_else(PoisonError::into_inner);
+    let key = (username.to_string(), task_id.to_string());
+    if matches!(state.jobs.lock_read().get(&key), Some(VertexJobStatus::Running { .. })) {
+        return Ok(None);
+    }
+    let mut available_resources = current_free(state);
+    if available_resources.mems.is_empty() {
+        available_resources.mems = HashSet::from([0]);
+    }
+    if !available_resources.acceptable(&job_configuration.requirement) {
+        return Err("Resources not enough");
+    }
+    if job_configuration.requirement.whole_node {
+        job_configuration.requirement.cpus = NodesRequirement::Select(available_resources.cpus.clone());
+        job_configuration.requirement.mems = NodesRequirement::Select(available_resources.mems.clone());
+    }
+    if let NodesRequirement::Use(size) = job_configuration.requirement.cpus {
+        job_configuration.requirement.cpus = NodesRequirement::Select(
+            available_resources.cpus.into_iter().take(size).collect::<HashSet<_>>()
+        );
+    } else if let NodesRequirement::Auto = job_configuration.requirement.cpus {
+        job_configuration.requirement.cpus = NodesRequirement::Select(
+            available_resources.cpus
+        )
+    };
+    if let NodesRequirement::Use(size) = job_configuration.requirement.mems {
+        job_configuration.requirement.mems = NodesRequirement::Select(
+            available_resources.mems.into_iter().take(size).collect::<HashSet<_>>()
+        );
+    } else if let NodesRequirement::Auto = job_configuration.requirement.mems {
+        job_configuration.requirement.mems = NodesRequirement::Select(
+            local_mems(state, job_configuration.requirement.cpus.take_set())
+        )
+    };
+    let mut jobs = state.jobs.lock_write();
+    let running = jobs
+        .values()
+        .filter(|status| matches!(status, VertexJobStatus::Running { .. }))
+        .count();
+    if let Some(max_concurrent_jobs) = state.configuration.max_concurrent_jobs {
+        if running >= max_concurrent_jobs {
+            return Err("Too many running jobs");
+        }
+    }
+    if job_configuration.requirement.whole_node && running > 0 {
+        return Err("Node not idle");
+    }
+    jobs.insert(
+        key,
+        VertexJobStatus::Running {
+            configuration: job_configuration.clone(),
+            started_at,
+            cpuset: sorted_nodes(&job_configuration.requirement.cpus),
+            memset: sorted_nodes(&job_configuration.requirement.mems),
+            pid: 0,
+            frozen: false,
+        },
+    );
+    Ok(Some(job_configuration))
+}
+
+/// Bytes of the supervisor stderr kept for the `Error` status.
+const STDERR_TAIL_BYTES: usize = 4096;
+
+/// Pass `stderr` through to the vertex stderr, returning its last bytes.
+fn stderr_tail(mut stderr: impl Read) -> Vec<u8> {
+    let mut tail = Vec::new();
+    let mut buffer = [0; 4096];
+    while let Ok(read) = stderr.read(&mut buffer) {
+        if read == 0 {
+            break;
+        }
+        let _ = io::stderr().write_all(&buffer[..read]);
+        tail.extend_from_slice(&buffer[..read]);
+        if tail.len() > STDERR_TAIL_BYTES {
+            tail.drain(..tail.len() - STDERR_TAIL_BYTES);
+        }
+    }
+    tail
+}
+
+/// `output` as text, base64 encoded if the job asked for `binary_output`.
+fn encode_output(job_configuration: &JobConfiguration, output: &[u8]) -> String {
+    if job_configuration.binary_output {
+        base64::engine::general_purpose::STANDARD.encode(output)
+    } else {
+        String::from_utf8_lossy(output).to_string()
     }
 }
 
 fn current_free(state: &VertexState) -> ResourcesProvider {
     let mut available_resources = state.configuration.resources.clone();
-    for (_, job_status) in state.jobs.read().unwrap().iter() {
-        if let VertexJobStatus::Running(JobConfiguration { requirement, .. }, _) = job_status {
-            let ResourcesRequirement {
-                cpus,
-                mems,
-                countables,
-                ..
-            } = requirement;
-            available_resources.cpus = available_resources
-                .cpus
-                .difference(cpus.take_set())
-                .cloned()
-                .collect::<HashSet<_>>();
-            available_resources.mems = available_resources
-                .mems
-                .difference(mems.take_set())
-                .cloned()
-                .collect::<HashSet<_>>();
-            for (k, v) in countables.get_all() {
-                let current = available_resources.countables.get(k);
-                available_resources
-                    .countables
-                    .set(k, current.checked_sub(*v).unwrap_or(0))
-            }
+    for (_, job_status) in state.jobs.lock_read().iter() {
+        if let VertexJobStatus::Running {
+            configuration: JobConfiguration { requirement, .. },
+            ..
+        } = job_status
+        {
+            available_resources.subtract(requirement);
         }
     }
     available_resources
 }
+
+fn unknown_ids(uid: u32, gid: u32) -> Option<String> {
+    // the returned records are only checked for null, never read
+    if unsafe { libc::getpwuid(uid) }.is_null() {
+        Some(format!("Unknown uid {}", uid))
+    } else if unsafe { libc::getgrgid(gid) }.is_null() {
+        Some(format!("Unknown gid {}", gid))
+    } else {
+        None
+    }
+}
+
+/// The first bind of the job with a source or target outside `allowed`,
+/// following symlinks of the paths that exist.
+fn forbidden_bind(allowed: &[String], job_configuration: &JobConfiguration) -> Option<String> {
+    let is_allowed = |path: &str| {
+        let path = fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path));
+        path.is_absolute()
+            && !path.components().any(|component| component == Component::ParentDir)
+            && allowed.iter().any(|allowed| path.starts_with(allowed))
+    };
+    job_configuration
+        .isolate
+        .iter()
+        .flat_map(|isolate| isolate.binds.iter())
+        .find(|bind| !is_allowed(&bind.source) || !is_allowed(&bind.target))
+        .map(|bind| format!("bind mount of {} on {} is not allowed", bind.source, bind.target))
+}
+
+/// Memory nodes hosting `cpus`, or node 0 if the topology is unknown.
+fn local_mems(state: &VertexState, cpus: &HashSet<usize>) -> HashSet<usize> {
+    let mems = state
+        .topology
+        .as_ref()
+        .as_ref()
+        .map(|topology| {
+            cpus.iter()
+                .filter_map(|cpu| topology.get(cpu).cloned())
+                .collect::<HashSet<_>>()
+        })
+        .unwrap_or_default();
+    if mems.is_empty() {
+        HashSet::from([0])
+    } else {
+        mems
+    }
+}
+
+fn numa_topology() -> Option<HashMap<usize, usize>> {
+    let mut topology = HashMap::new();
+    for entry in fs::read_dir("/sys/devices/system/node").ok()? {
+        let entry = entry.ok()?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        let Some(node) = name.strip_prefix("node").and_then(|node| node.parse::<usize>().ok()) else {
+            continue;
+        };
+        let cpulist = fs::read_to_string(entry.path().join("cpulist")).ok()?;
+        for cpu in parse_node_list(&cpulist).ok()? {
+            topology.insert(cpu, node);
+        }
+    }
+    if topology.is_empty() {
+        None
+    } else {
+        Some(topology)
+    }
+}
+
+fn sorted_nodes(nodes: &NodesRequirement) -> Vec<usize> {
+    let mut nodes = nodes.take_set().iter().cloned().collect::<Vec<_>>();
+    nodes.sort();
+    nodes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(extra: &str) -> VertexState {
+        let configuration = serde_yaml::from_str(&format!(
+            "{{basic: {{}}, history: '', resources: {{cpus: '0-3', mems: [0], countables: {{}}, properties: {{}}}}, {}}}",
+            extra
+        ))
+        .unwrap();
+        VertexState {
+            configuration,
+            jobs: Arc::new(RwLock::new(HashMap::new())),
+            topology: Arc::new(None),
+            submitting: Arc::new(Mutex::new(())),
+        }
+    }
+
+    fn job(cpus: usize) -> JobConfiguration {
+        serde_yaml::from_str(&format!(
+            "{{name: test, uid: 1000, gid: 1000, phases: [!Sh 'true'], \
+             requirement: {{cpus: !Use {}, mems: Auto, countables: {{}}, properties: {{}}}}}}",
+            cpus
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn submits_over_max_concurrent_jobs_are_rejected() {
+        let state = state("max_concurrent_jobs: 2");
+        assert!(reserve(&state, "d", "a", job(1), 0).unwrap().is_some());
+        assert!(reserve(&state, "d", "b", job(1), 0).unwrap().is_some());
+        assert_eq!(reserve(&state, "d", "c", job(1), 0), Err("Too many running jobs"));
+        // a retry of a running job is still answered
+        assert_eq!(reserve(&state, "d", "a", job(1), 0), Ok(None));
+    }
+
+    #[tokio::test]
+    async fn prune_never_drops_running_jobs() {
+        let state = state("");
+        let finished = |exit_at| VertexJobStatus::Finished {
+            configuration: job(1),
+            exit_code: 0,
+            exit_at,
+            runtime_secs: 1,
+            usage: ResourceUsage::default(),
+        };
+        assert!(reserve(&state, "d", "running", job(1), 0).unwrap().is_some());
+        state.jobs.lock_write().extend([
+            (("d".to_string(), "old".to_string()), finished(10)),
+            (("d".to_string(), "new".to_string()), finished(200)),
+            (("e".to_string(), "other".to_string()), finished(10)),
+        ]);
+        let Json(pruned) = prune_jobs(
+            State(state.clone()),
+            Query(PruneQuery { before: u64::MAX }),
+            TypedHeader(Authorization::basic("d", "")),
+        )
+        .await;
+        assert_eq!(pruned, 2);
+        let kept = state.jobs.lock_read().keys().cloned().collect::<HashSet<_>>();
+        assert!(kept.contains(&("d".to_string(), "running".to_string())));
+        assert!(kept.contains(&("e".to_string(), "other".to_string())));
+        let Json(pruned) = prune_jobs(
+            State(state.clone()),
+            Query(PruneQuery { before: 100 }),
+            TypedHeader(Authorization::basic("e", "")),
+        )
+        .await;
+        assert_eq!(pruned, 1);
+        assert_eq!(state.jobs.lock_read().len(), 1);
+    }
+
+    #[test]
+    fn whole_node_job_waits_for_an_idle_node() {
+        let state = state("");
+        let mut whole = job(1);
+        whole.requirement.whole_node = true;
+        assert!(reserve(&state, "d", "a", job(1), 0).unwrap().is_some());
+        assert!(reserve(&state, "d", "whole", whole.clone(), 0).is_err());
+        state.jobs.lock_write().clear();
+        let reserved = reserve(&state, "d", "whole", whole, 0).unwrap().unwrap();
+        assert_eq!(reserved.requirement.cpus, NodesRequirement::Select(HashSet::from([0, 1, 2, 3])));
+    }
+
+    #[test]
+    fn concurrent_submits_never_share_a_cpu() {
+        let state = state("");
+        let barrier = Arc::new(std::sync::Barrier::new(8));
+        let submits = (0..8)
+            .map(|index| {
+                let state = state.clone();
+                let barrier = barrier.clone();
+                spawn(move || {
+                    barrier.wait();
+                    reserve(&state, "d", &index.to_string(), job(1), 0)
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut cpus = Vec::new();
+        for submit in submits {
+            if let Ok(Some(job)) = submit.join().unwrap() {
+                cpus.extend(job.requirement.cpus.take_set().iter().cloned());
+            }
+        }
+        cpus.sort();
+        assert_eq!(cpus, vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn default_memory_limit_only_fills_missing_memory() {
+        let memory = |extra: &str, job: JobConfiguration| {
+            supervised(&state(extra).configuration, &job).requirement.countables.get("memory")
+        };
+        let mut asks = job(1);
+        asks.requirement.countables.set("memory", 1 << 20);
+        assert_eq!(memory("default_memory_limit: 4Gi", asks), 1 << 20);
+        assert_eq!(memory("default_memory_limit: 4Gi", job(1)), 4 << 30);
+        assert_eq!(memory("", job(1)), 0);
+    }
+
+    #[test]
+    fn failed_job_is_reported_with_its_exit_code() {
+        let exit_status = std::process::Command::new("sh").args(["-c", "exit 3"]).status().unwrap();
+        match ended(job(1), exit_status, 0, 0, ResourceUsage::default(), String::new()) {
+            VertexJobStatus::Error { status_code, .. } => assert_eq!(status_code, 3),
+            other => panic!("reported as {:?}", other),
+        }
+    }
+
+    #[test]
+    fn binds_outside_the_allowed_directories_are_rejected() {
+        let scratch = std::env::temp_dir().join(format!("jd_binds_{}", std::process::id()));
+        std::fs::create_dir_all(scratch.join("data")).unwrap();
+        std::os::unix::fs::symlink("/etc", scratch.join("etc")).unwrap();
+        let bind = |source: &str, target: &str| {
+            let mut job = job(1);
+            job.isolate = Some(
+                serde_yaml::from_str(&format!("{{binds: [{{source: {}, target: {}}}]}}", source, target))
+                    .unwrap(),
+            );
+            job
+        };
+        let allowed = vec![scratch.display().to_string()];
+        let inside = scratch.join("data").display().to_string();
+        assert_eq!(forbidden_bind(&allowed, &bind(&inside, &inside)), None);
+        assert_eq!(forbidden_bind(&allowed, &job(1)), None);
+        assert!(forbidden_bind(&[], &bind(&inside, &inside)).is_some());
+        assert!(forbidden_bind(&allowed, &bind("/etc", &inside)).is_some());
+        assert!(forbidden_bind(&allowed, &bind(&inside, "/usr/bin")).is_some());
+        let escaping = format!("{}/../../etc", inside);
+        assert!(forbidden_bind(&allowed, &bind(&escaping, &inside)).is_some());
+        let linked = scratch.join("etc").display().to_string();
+        assert!(forbidden_bind(&allowed, &bind(&linked, &inside)).is_some());
+        std::fs::remove_dir_all(&scratch).unwrap();
+    }
+}
@@ -1,32 +1,125 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, fs, time::Duration};
 
 use crate::{
     jobs_management::JobConfiguration,
     resources_management::ResourcesProvider, vertex::VertexJobStatus
 };
 
-use reqwest::{Body, Client, RequestBuilder};
+use reqwest::{Body, Certificate, Client, Identity, RequestBuilder};
 use serde::{Serialize, Deserialize};
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct VertexConnect {
     url: String,
     username: String,
     password: String,
+    #[serde(default)]
+    tls: Option<VertexClientTls>,
+    /// Milliseconds allowed for establishing a connection.
+    #[serde(default = "default_connect_timeout")]
+    connect_timeout: u64,
+    /// Milliseconds allowed for a whole request, including the response body.
+    #[serde(default = "default_request_timeout")]
+    request_timeout: u64,
+    /// Milliseconds an idle keep-alive connection stays in the pool.
+    #[serde(default = "default_pool_idle_timeout")]
+    pool_idle_timeout: u64,
+    #[serde(default = "default_pool_max_idle")]
+    pool_max_idle: usize,
+}
+
+/// Serializes a `VertexConnect` with its password hidden.
+pub struct Redacted<'a>(pub &'a VertexConnect);
+
+impl Serialize for Redacted<'_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        VertexConnect {
+            password: "<redacted>".to_string(),
+            ..self.0.clone()
+        }
+        .serialize(serializer)
+    }
+}
+
+fn default_connect_timeout() -> u64 {
+    1000
+}
+
+fn default_request_timeout() -> u64 {
+    5000
+}
+
+fn default_pool_idle_timeout() -> u64 {
+    90000
+}
+
+fn default_pool_max_idle() -> usize {
+    4
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct VertexClientTls {
+    /// Extra CA trusted when verifying the vertex certificate.
+    #[serde(default)]
+    ca: Option<String>,
+    /// Client certificate and key presented to vertexes requiring mutual TLS.
+    #[serde(default)]
+    cert: Option<String>,
+    #[serde(default)]
+    key: Option<String>,
 }
 
 impl VertexConnect {
     pub fn new(url: &str, username: &str, password: &str) -> Self {
         Self {
-            url: url.to_string(), username: username.to_string(), password: password.to_string()
+            url: url.to_string(),
+            username: username.to_string(),
+            password: password.to_string(),
+            tls: None,
+            connect_timeout: default_connect_timeout(),
+            request_timeout: default_request_timeout(),
+            pool_idle_timeout: default_pool_idle_timeout(),
+            pool_max_idle: default_pool_max_idle(),
         }
     }
 
     pub fn create(&self) -> VertexClient {
-        VertexClient { url: self.url.clone(), username: self.username.clone(), password: self.password.clone(), client: Client::new() }
+        let mut builder = Client::builder()
+            .connect_timeout(Duration::from_millis(self.connect_timeout))
+            .timeout(Duration::from_millis(self.request_timeout))
+            .pool_idle_timeout(Duration::from_millis(self.pool_idle_timeout))
+            .pool_max_idle_per_host(self.pool_max_idle)
+            .tcp_keepalive(Duration::from_millis(self.pool_idle_timeout));
+        if let Some(tls) = &self.tls {
+            builder = builder.use_rustls_tls();
+            if let Some(ca) = &tls.ca {
+                let ca = fs::read(ca).unwrap();
+                builder = builder.add_root_certificate(Certificate::from_pem(&ca).unwrap());
+            }
+            if let (Some(cert), Some(key)) = (&tls.cert, &tls.key) {
+                let mut pem = fs::read(cert).unwrap();
+                pem.push(b'\n');
+                pem.extend(fs::read(key).unwrap());
+                builder = builder.identity(Identity::from_pem(&pem).unwrap());
+            }
+        }
+        VertexClient { url: self.url.clone(), username: self.username.clone(), password: self.password.clone(), client: builder.build().unwrap() }
     }
 }
 
+/// Announcement a vertex sends to the dispatcher to be added to, and kept
+/// in, its vertexes.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Registration {
+    pub name: String,
+    /// Shared with the `registration` configuration of the dispatcher.
+    pub secret: String,
+    /// How the dispatcher reaches this vertex.
+    pub connect: VertexConnect,
+    pub resources: ResourcesProvider,
+}
+
+#[derive(Clone)]
 pub struct VertexClient {
     url: String,
     username: String, 
@@ -80,14 +173,81 @@ impl VertexClient {
             .map_err(|e| e.to_string())
     }
 
+    /// Forget the finished jobs which ended before `before`, returns how many.
+    pub async fn prune(&self, before: u64) -> Result<usize, String> {
+        let url = format!("{}/jobs?before={}", self.url, before);
+        self.client
+            .delete(url)
+            .basic_auth(self.username(), Some(self.password()))
+            .send()
+            .await
+            .map_err(|e| e.to_string())?
+            .json()
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    /// Stdout of the job `task_id` from the byte `from` on, at most
+    /// `LOG_CHUNK_BYTES` of it.
+    pub async fn log(&self, task_id: &str, from: u64) -> Result<Vec<u8>, String> {
+        let resp = self
+            .get(&format!("/job/{}/log?from={}", task_id, from))
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        if resp.status().is_success() {
+            Ok(resp.bytes().await.map_err(|e| e.to_string())?.to_vec())
+        } else {
+            Err(resp.text().await.map_err(|e| e.to_string())?)
+        }
+    }
+
+    /// Kill the running job `task_id`.
+    pub async fn kill(&self, task_id: &str) -> Result<(), String> {
+        let url = format!("{}/job/{}", self.url, task_id);
+        let resp = self
+            .client
+            .delete(url)
+            .basic_auth(self.username(), Some(self.password()))
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        if resp.status().is_success() {
+            Ok(())
+        } else {
+            Err(resp.text().await.map_err(|e| e.to_string())?)
+        }
+    }
+
+    /// Freeze the running job `task_id`, or thaw it if `frozen` is unset.
+    pub async fn set_frozen(&self, task_id: &str, frozen: bool) -> Result<(), String> {
+        let action = if frozen { "suspend" } else { "resume" };
+        let url = format!("{}/job/{}/{}", self.url, task_id, action);
+        let resp = self
+            .client
+            .post(url)
+            .basic_auth(self.username(), Some(self.password()))
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        if resp.status().is_success() {
+            Ok(())
+        } else {
+            Err(resp.text().await.map_err(|e| e.to_string())?)
+        }
+    }
+
     pub async fn submit_job(&self, task_id: &str, job: &JobConfiguration) -> Result<String, String> {
         let resp = self.post(&format!("/job/{}", task_id), job.clone())
             .send()
             .await
             .map_err(|e| e.to_string())?;
         println!("{}", resp.status());
-        resp.text()
-            .await
-            .map_err(|e| e.to_string())
+        // a refusal is a failed submission, not the id of the job
+        if resp.status().is_success() {
+            resp.text().await.map_err(|e| e.to_string())
+        } else {
+            Err(resp.text().await.map_err(|e| e.to_string())?)
+        }
     }
 }
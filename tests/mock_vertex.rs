@@ -0,0 +1,95 @@
+//! Submit a job to a dispatcher over its unix socket and check it reaches a
+//! mock vertex.
+#![cfg(feature = "mock-vertex")]
+
+use std::time::Duration;
+
+use job_dispatcher::jobs_management::{ExecutePhase, JobConfigurationBuilder};
+use job_dispatcher::mock_vertex::MockVertex;
+use job_dispatcher::resources_management::ResourcesRequirement;
+use job_dispatcher::unix::{encode, ClientRequest, DispatcherResponse};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+
+async fn send(socket: &str, request: &ClientRequest) -> DispatcherResponse {
+    let mut server = UnixStream::connect(socket).await.unwrap();
+    server.write_all(&encode(request)).await.unwrap();
+    server.shutdown().await.unwrap();
+    let mut response = String::new();
+    server.read_to_string(&mut response).await.unwrap();
+    serde_json::from_str(&response).unwrap()
+}
+
+#[tokio::test]
+async fn submitted_job_is_dispatched_to_the_mock_vertex() {
+    let resources = serde_yaml::from_str(
+        "{cpus: '0-3', mems: [0], countables: {memory: 8Gi, time_limit: 3600}, properties: {}}",
+    )
+    .unwrap();
+    let (connect, _server) = MockVertex::new(resources, None)
+        .serve("127.0.0.1:0".parse().unwrap())
+        .await;
+
+    let dir = std::env::temp_dir().join(format!("jd_mock_vertex_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let socket = dir.join("dispatcher.socket");
+    let config = format!(
+        "listen: {socket}
+vertexes:
+  m: {connect}
+max_timeout: 1000000
+loop_interval: 100000
+queues:
+  q1:
+    priority_rule: []
+    users: !Deny []
+    groups: !Deny []
+    properties: {{}}
+    global_limit: null
+    user_limit: null
+    group_limit: null
+persistent: {persistent}
+",
+        socket = socket.display(),
+        connect = serde_json::to_string(&connect).unwrap(),
+        persistent = dir.join("queues.json").display(),
+    );
+    let config_path = dir.join("dispatcher.yaml");
+    std::fs::write(&config_path, config).unwrap();
+    let config_path = config_path.to_str().unwrap().to_string();
+    tokio::spawn(async move { job_dispatcher::dispatcher::dispatcher(&config_path).await });
+
+    let requirement: ResourcesRequirement = serde_yaml::from_str(
+        "{cpus: !Use 1, mems: Auto, countables: {memory: 1Gi, time_limit: 60}, properties: {}}",
+    )
+    .unwrap();
+    let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+    let job = JobConfigurationBuilder::new("mock", uid, gid, requirement)
+        .phase(ExecutePhase::Sh("true".to_string()))
+        .build()
+        .unwrap();
+    let request = ClientRequest::SubmitJob(Some("q1".to_string()), Box::new(job), None);
+    let socket = socket.to_str().unwrap();
+    for _ in 0..50 {
+        if UnixStream::connect(socket).await.is_ok() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    let task_id = match send(socket, &request).await {
+        DispatcherResponse::SubmitSuccess(task_id) => task_id,
+        other => panic!("submit failed: {:?}", other),
+    };
+
+    let vertex = connect.create();
+    let mut dispatched = false;
+    for _ in 0..50 {
+        if vertex.jobs().await.unwrap().contains_key(&task_id) {
+            dispatched = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    std::fs::remove_dir_all(&dir).ok();
+    assert!(dispatched, "{} never reached the mock vertex", task_id);
+}
@@ -0,0 +1,98 @@
+//! Run jobs through the supervisor binary and check how it exits.
+
+use std::{
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use job_dispatcher::jobs_management::JobConfiguration;
+use job_dispatcher::supervisor::TIME_LIMIT_EXIT_CODE;
+
+/// Exit code of the supervisor running `script` as `uid`, logging into `dir`.
+fn supervise(task_id: &str, dir: &Path, uid: u32, script: &str, time_limit: u64, extra: &str) -> Option<i32> {
+    let job = serde_json::to_string(&serde_yaml::from_str::<JobConfiguration>(&format!(
+        "{{name: test, uid: {uid}, gid: {uid}, phases: [!Sh '{script}'], \
+         stdout_file: {dir}/out, stderr_file: {dir}/err, {extra} \
+         requirement: {{cpus: !Select [0], mems: !Select [0], countables: {{time_limit: {time_limit}}}, properties: {{}}}}}}",
+        dir = dir.display(),
+    ))
+    .unwrap())
+    .unwrap();
+    Command::new(env!("CARGO_BIN_EXE_job_dispatcher"))
+        .args(["supervisor", task_id, &job])
+        .output()
+        .unwrap()
+        .status
+        .code()
+}
+
+fn scratch(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("jd_supervisor_{}_{}", name, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn supervisor_exits_with_the_job_exit_code() {
+    if unsafe { libc::geteuid() } != 0 {
+        return;
+    }
+    let dir = scratch("exit");
+    assert_eq!(supervise("jd_exit_0", &dir, 0, "true", 60, ""), Some(0));
+    assert_eq!(supervise("jd_exit_3", &dir, 0, "exit 3", 60, ""), Some(3));
+    assert_eq!(supervise("jd_time_limit", &dir, 0, "sleep 10", 1, ""), Some(TIME_LIMIT_EXIT_CODE));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn limited_logs_are_written_before_the_supervisor_exits() {
+    if unsafe { libc::geteuid() } != 0 {
+        return;
+    }
+    let dir = scratch("drain");
+    assert_eq!(supervise("jd_drain", &dir, 0, "seq 100000", 60, "max_log_bytes: 10000000,"), Some(0));
+    let out = std::fs::read_to_string(dir.join("out")).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(out.lines().last(), Some("100000"));
+}
+
+#[test]
+fn log_files_are_opened_as_the_job_owner() {
+    if unsafe { libc::geteuid() } != 0 {
+        return;
+    }
+    let dir = scratch("owner");
+    std::os::unix::fs::chown(&dir, Some(1234), Some(1234)).unwrap();
+    let protected = dir.join("protected");
+    std::fs::write(&protected, "keep").unwrap();
+    std::os::unix::fs::symlink(&protected, dir.join("out")).unwrap();
+    assert_ne!(supervise("jd_owner", &dir, 1234, "true", 60, ""), Some(0));
+    let kept = std::fs::read_to_string(&protected).unwrap();
+    std::fs::remove_file(dir.join("out")).unwrap();
+    // whether the executor starts doesn't matter, the log files are opened first
+    supervise("jd_owner", &dir, 1234, "true", 60, "");
+    let owner = std::fs::metadata(dir.join("out")).unwrap().uid();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(kept, "keep");
+    assert_eq!(owner, 1234);
+}
+
+#[test]
+fn log_dirs_are_created_as_the_job_owner() {
+    if unsafe { libc::geteuid() } != 0 {
+        return;
+    }
+    let dir = scratch("log_dir");
+    let owned = dir.join("owned");
+    std::fs::create_dir(&owned).unwrap();
+    std::os::unix::fs::chown(&owned, Some(1234), Some(1234)).unwrap();
+    supervise("jd_log_dir", &owned.join("logs/today"), 1234, "true", 60, "");
+    let created = std::fs::metadata(owned.join("logs/today")).unwrap();
+    // root's directory is left alone
+    supervise("jd_log_dir", &dir.join("logs"), 1234, "true", 60, "");
+    let refused = dir.join("logs").exists();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!((created.uid(), created.gid()), (1234, 1234));
+    assert!(!refused);
+}